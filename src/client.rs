@@ -1,5 +1,21 @@
 use reqwest::header;
 
+mod autosave;
+pub mod builder;
+pub mod captcha;
+pub mod cookies;
+pub mod credentials;
+pub mod har;
+pub mod keep_alive;
+pub mod lockout;
+mod message;
+pub mod pool;
+pub mod retry;
+#[cfg(test)]
+mod test_util;
+pub mod session;
+mod state;
+
 #[cfg(feature = "async")]
 pub mod async_impl;
 
@@ -12,9 +28,11 @@ pub use async_impl::UestcClient;
 #[cfg(feature = "blocking")]
 pub use blocking_impl::UestcBlockingClient;
 
-pub(crate) const AUTH_SERVER_URL: &str = "https://idas.uestc.edu.cn/authserver";
-
-pub(crate) fn default_headers() -> header::HeaderMap {
+pub use crate::core::cas::{ServiceLogin, TicketValidation};
+/// The browser-like header profile sent by default.
+///
+/// Use it as a starting point for [`UestcClientBuilder::default_headers`](builder::UestcClientBuilder::default_headers).
+pub fn default_headers() -> header::HeaderMap {
     let mut headers = header::HeaderMap::new();
     // common headers
     headers.insert(header::ACCEPT, header::HeaderValue::from_static("text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.7"));
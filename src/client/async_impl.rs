@@ -1,425 +1,618 @@
-use super::AUTH_SERVER_URL;
+use super::builder::{CookieKey, DEFAULT_COOKIE_FILE, UestcClientBuilder};
+use super::captcha::AsyncCaptchaSolver;
+use super::credentials::{CredentialProvider, Credentials};
+use super::captcha;
+use super::har::{HarRecorder, Hops, ResponseHead};
+use super::keep_alive::{KeepAliveConfig, KeepAliveEvent, KeepAliveHandle, KeepAliveState};
+use super::lockout::LoginGuard;
+use super::message::ClientRequest;
+use super::retry::{Attempts, Deadline, RetryPolicy};
+use super::state::{ClientState, build_client};
+use super::ServiceLogin;
+use crate::core::cas::ServiceRedirects;
+use crate::core::dynamic_code_login::{CodeLogin, CodeRequest, VerificationCodeRequest};
+use crate::core::flow::{Flow, HttpRequest, HttpResponse, Input, SessionCheck, SessionStatus, Step};
+use crate::core::log;
+use crate::core::logout::{Logout, LogoutOptions, LogoutReport};
+use crate::core::password_login::PasswordLogin;
+use crate::core::secret::Secret;
+use crate::core::trace::Span;
+use crate::core::user_info::SessionInfo;
+use crate::core::verification::{DynamicCodeLogin, PendingVerification};
+use crate::core::wechat::{ScanStatus, WechatQrLogin};
+use crate::core::wechat_login::{CancellationToken, WechatLoginEvent, WechatLoginOptions, WechatStart, WechatWait};
 use crate::{Result, UestcClientError, core};
-use reqwest::{Client, IntoUrl, Method, RequestBuilder};
-use std::fs;
-use std::path::{Path, PathBuf};
+use reqwest::{Client, IntoUrl, Method, Request, RequestBuilder, Response};
+use std::path::Path;
 use std::sync::Arc;
-use cookie_store::CookieStore;
-use reqwest_cookie_store::CookieStoreMutex;
-use serde::{Deserialize, Serialize};
-
-const DEFAULT_COOKIE_FILE: &str = "uestc_cookies.json";
-
-#[derive(Serialize, Deserialize, Debug)]
-struct SerializableCookie {
-    name: String,
-    value: String,
-    domain: String,
-    path: String,
-    expires: Option<i64>,
-    secure: bool,
-    http_only: bool,
-}
 
 pub struct UestcClient {
     client: Client,
-    cookie_store: Arc<CookieStoreMutex>,
-    cookie_file: PathBuf,
+    /// Same session as `client` but without automatic redirects, used to
+    /// observe CAS ticket hops. `None` when a custom client was supplied.
+    redirectless_client: Option<Client>,
+    captcha_solver: Option<Arc<dyn AsyncCaptchaSolver>>,
+    /// Everything else, shared with the other client
+    state: ClientState,
 }
 
 impl UestcClient {
+    /// Persist cookies to [`DEFAULT_COOKIE_FILE`].
+    ///
+    /// # Panics
+    ///
+    /// In the same cases as [`with_cookie_file`](Self::with_cookie_file).
     pub fn new() -> Self {
         Self::with_cookie_file(DEFAULT_COOKIE_FILE)
     }
 
+    /// Persist cookies to `path`.
+    ///
+    /// A file that can't be read, locked or parsed is logged and the client
+    /// starts without a session.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `path` holds an encrypted cookie file, use
+    /// [`with_encrypted_cookie_file`](Self::with_encrypted_cookie_file) for
+    /// those, or if the underlying `reqwest` client can't be built, e.g.
+    /// because the TLS backend fails to initialize.
     pub fn with_cookie_file<P: AsRef<Path>>(path: P) -> Self {
-        let cookie_file = path.as_ref().to_path_buf();
-
-        // Try to load existing cookies
-        let cookie_store = if cookie_file.exists() {
-            log::debug!("发现 cookie 文件: {:?}", cookie_file);
-            match Self::load_cookie_store(&cookie_file) {
-                Ok(store) => {
-                    let count = store.lock().unwrap().iter_any().count();
-                    log::debug!("成功加载 {} 个 cookies", count);
-                    store
-                }
-                Err(e) => {
-                    log::warn!("加载 cookie 失败: {}", e);
-                    Arc::new(CookieStoreMutex::new(CookieStore::default()))
-                }
-            }
-        } else {
-            log::debug!("cookie 文件不存在: {:?}", cookie_file);
-            Arc::new(CookieStoreMutex::new(CookieStore::default()))
-        };
-
-        let client = Client::builder()
-            .default_headers(super::default_headers())
-            .cookie_provider(cookie_store.clone())
-            .build()
-            .expect("Failed to build client");
+        Self::builder()
+            .cookie_file(path.as_ref())
+            .build_async()
+            .expect("Failed to build client")
+    }
 
-        Self {
-            client,
-            cookie_store,
-            cookie_file,
-        }
+    /// Like [`with_cookie_file`](Self::with_cookie_file), but the file is
+    /// encrypted with `key`.
+    ///
+    /// Fails with [`UestcClientError::CookieError`] if an existing file
+    /// cannot be decrypted.
+    pub fn with_encrypted_cookie_file<P: AsRef<Path>>(path: P, key: CookieKey) -> Result<Self> {
+        Self::builder()
+            .encrypted_cookie_file(path.as_ref(), key)
+            .build_async()
     }
 
+    /// Wrap an existing `reqwest` client.
+    ///
+    /// Cookies are managed by the supplied client's own cookie store (if
+    /// any), which this client can't see. So they are not persisted, logout
+    /// leaves them in place and a [`HarRecorder`] can't list the cookies
+    /// sent. Use [`builder`](Self::builder) to customise the underlying
+    /// client while keeping these.
     pub fn with_client(client: Client) -> Self {
-        let cookie_store = Arc::new(CookieStoreMutex::new(CookieStore::default()));
         Self {
             client,
-            cookie_store,
-            cookie_file: PathBuf::from(DEFAULT_COOKIE_FILE),
+            redirectless_client: None,
+            captcha_solver: None,
+            state: ClientState::custom(),
         }
     }
 
-    fn load_cookie_store(path: &Path) -> Result<Arc<CookieStoreMutex>> {
-        let json = fs::read_to_string(path).map_err(|e| UestcClientError::CookieError {
-            operation: "read".to_string(),
-            file_path: Some(path.display().to_string()),
-            message: format!("Failed to read cookie file: {}", e),
-            source: Some(Box::new(e)),
+    pub fn builder() -> UestcClientBuilder {
+        UestcClientBuilder::new()
+    }
+
+    pub(crate) fn from_builder(config: UestcClientBuilder) -> Result<Self> {
+        let (state, client, redirectless_client) = ClientState::from_builder(&config, |cookies, policy| {
+            build_client!(Client::builder(), &config, cookies, policy, read_timeout)
         })?;
+        Ok(Self {
+            client,
+            redirectless_client: Some(redirectless_client),
+            captcha_solver: None,
+            state,
+        })
+    }
 
-        let cookies: Vec<SerializableCookie> =
-            serde_json::from_str(&json).map_err(|e| UestcClientError::CookieError {
-                operation: "deserialize".to_string(),
-                file_path: Some(path.display().to_string()),
-                message: format!("Failed to deserialize cookies: {}", e),
-                source: Some(Box::new(e)),
-            })?;
-
-        let mut store = CookieStore::default();
-
-        // Convert SerializableCookie back to cookie_store format
-        for sc in cookies {
-            // Skip cookies with empty domain
-            if sc.domain.is_empty() {
-                log::debug!("跳过空 domain 的 cookie: {}", sc.name);
-                continue;
-            }
+    /// Set the solver used when IDAS requires a captcha during password login.
+    ///
+    /// Without a solver, such logins fail with [`UestcClientError::CaptchaRequired`].
+    pub fn with_captcha_solver<S: AsyncCaptchaSolver + 'static>(mut self, solver: S) -> Self {
+        self.captcha_solver = Some(Arc::new(solver));
+        self
+    }
 
-            let mut cookie_str = format!("{}={}", sc.name, sc.value);
-            cookie_str.push_str(&format!("; Domain={}", sc.domain));
-            cookie_str.push_str(&format!("; Path={}", sc.path));
+    /// Retries and deadline for login flows, see [`RetryPolicy`]
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.state.retry_policy = policy;
+        self
+    }
 
-            if sc.secure {
-                cookie_str.push_str("; Secure");
-            }
-            if sc.http_only {
-                cookie_str.push_str("; HttpOnly");
-            }
-            if let Some(expires) = sc.expires {
-                cookie_str.push_str(&format!("; Max-Age={}", expires));
-            }
+    /// Count failed password logins and refuse attempts that could lock the
+    /// account, see [`LoginGuard`]
+    pub fn with_login_guard(mut self, guard: LoginGuard) -> Self {
+        self.state.login_guard = Some(guard);
+        self
+    }
 
-            // Parse and insert the cookie
-            if let Ok(cookie) = cookie_str.parse::<cookie_store::RawCookie>() {
-                if let Ok(url) = url::Url::parse(&format!("https://{}", sc.domain)) {
-                    if let Err(e) = store.insert_raw(&cookie, &url) {
-                        log::debug!("插入 cookie 失败: {:?}", e);
-                    }
-                } else {
-                    log::debug!("无法解析 domain: {}", sc.domain);
-                }
-            }
-        }
+    /// Record the client's traffic to a HAR file, see [`HarRecorder`]
+    pub fn with_har_recorder(mut self, recorder: HarRecorder) -> Self {
+        self.state.har_recorder = Some(recorder);
+        self
+    }
 
-        Ok(Arc::new(CookieStoreMutex::new(store)))
-    }
-
-    fn save_cookie_store(&self) -> Result<()> {
-        let store = self.cookie_store.lock().unwrap();
-
-        // Convert cookies to SerializableCookie format
-        let cookies: Vec<SerializableCookie> = store
-            .iter_any()
-            .map(|c| {
-                // Use a default domain if the cookie doesn't have one
-                let domain = c.domain()
-                    .filter(|d| !d.is_empty())
-                    .unwrap_or("idas.uestc.edu.cn");
-
-                SerializableCookie {
-                    name: c.name().to_string(),
-                    value: c.value().to_string(),
-                    domain: domain.to_string(),
-                    path: c.path().unwrap_or("/").to_string(),
-                    expires: None, // Treat all as session cookies for simplicity
-                    secure: c.secure().unwrap_or(false),
-                    http_only: c.http_only().unwrap_or(false),
-                }
-            })
-            .collect();
+    /// Log in again with these credentials when an authenticated request
+    /// hits the login page, see [`send_authenticated`](Self::send_authenticated)
+    pub fn with_credentials(self, username: impl Into<String>, password: impl Into<Secret>) -> Self {
+        self.with_credential_provider(Credentials::new(username, password))
+    }
 
-        let count = cookies.len();
-        log::debug!("保存 {} 个 cookies 到: {:?}", count, self.cookie_file);
+    /// Obtain credentials from `callback` whenever a re-login is needed,
+    /// see [`send_authenticated`](Self::send_authenticated)
+    pub fn with_relogin_callback<F>(self, callback: F) -> Self
+    where
+        F: Fn() -> Result<Credentials> + Send + Sync + 'static,
+    {
+        self.with_credential_provider(callback)
+    }
 
-        let json = serde_json::to_string_pretty(&cookies).map_err(|e| {
-            UestcClientError::CookieError {
-                operation: "serialize".to_string(),
-                file_path: Some(self.cookie_file.display().to_string()),
-                message: format!("Failed to serialize cookies: {}", e),
-                source: Some(Box::new(e)),
-            }
-        })?;
+    /// Obtain credentials from `provider` whenever the client logs in on
+    /// its own, see [`login_with_provider`](Self::login_with_provider) and
+    /// [`send_authenticated`](Self::send_authenticated)
+    pub fn with_credential_provider<P: CredentialProvider + 'static>(mut self, provider: P) -> Self {
+        self.state.credential_provider = Some(Arc::new(provider));
+        self
+    }
 
-        fs::write(&self.cookie_file, json).map_err(|e| UestcClientError::CookieError {
-            operation: "write".to_string(),
-            file_path: Some(self.cookie_file.display().to_string()),
-            message: format!("Failed to write cookie file: {}", e),
-            source: Some(Box::new(e)),
-        })?;
+    /// Call `hook` whenever a request finds the session expired
+    pub(crate) fn on_session_expired(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.state.session_expired_hook = Some(Arc::new(hook));
+        self
+    }
 
-        log::debug!("cookies 已成功保存");
-        Ok(())
+    /// Save the cookies to the session store, if cookies are persisted.
+    ///
+    /// Logins save on their own; call this to keep cookies picked up later,
+    /// e.g. after a successful [`session_status`](Self::session_status).
+    pub fn save_cookie_store(&self) -> Result<()> {
+        self.state.save_cookie_store()
     }
 
-    pub async fn login(&self, username: &str, password: &str) -> Result<()> {
-        log::info!("Starting login for user: {}", username);
+    pub async fn login(&self, username: &str, password: impl Into<Secret>) -> Result<()> {
+        let span = Span::flow("login", Some(username));
+        span.run(async {
+            log::info!("Starting login for user: {}", username);
 
-        // Check if session is already active
-        if self.is_session_active().await {
-            log::info!("Session already active, skipping login");
-            return Ok(());
-        }
+            let flow = PasswordLogin::new(&self.state.endpoints, username, password.into());
+            let result = self.drive(flow, self.state.retry_policy.start_deadline(), None, |_| {}).await;
+            self.state.record_outcome(username, &result);
+            result
+        })
+        .await
+    }
 
-        // Perform password login
-        let login_url = format!("{}/login", AUTH_SERVER_URL);
-
-        log::debug!("Fetching login page");
-        // Get login page without service parameter
-        let resp = self.client.get(&login_url).send().await?;
-        let html = resp.text().await?;
-
-        log::debug!("Parsing login page");
-        // Parse login page
-        let info = core::parser::parse_login_page(&html)?;
-
-        log::debug!("Encrypting password");
-        // Encrypt password
-        let encrypted_password = core::crypto::encrypt_password(password, &info.pwd_encrypt_salt)?;
-
-        // Prepare form data
-        let mut form_data = info.form_data;
-        form_data
-            .entry("username".to_string())
-            .and_modify(|v| *v = username.to_string())
-            .or_insert(username.to_string());
-        form_data
-            .entry("password".to_string())
-            .and_modify(|v| *v = encrypted_password.to_string())
-            .or_insert(encrypted_password.to_string());
-
-        log::debug!("Submitting login form");
-        // Submit login form
-        let resp = self.client.post(&login_url).form(&form_data).send().await?;
-
-        // Check for redirect (302) or success status
-        let status = resp.status();
-        let final_url = resp.url().to_string();
+    /// Request an SMS dynamic code for a phone number or student ID.
+    ///
+    /// Pass the returned handle and the code the user received to
+    /// [`dynamic_code_login`](Self::dynamic_code_login). If a captcha solver is
+    /// configured it is used for the graphic captcha guarding the SMS request.
+    pub async fn request_dynamic_code(&self, account: &str) -> Result<DynamicCodeLogin> {
+        let flow = CodeRequest::new(&self.state.endpoints, account, self.captcha_solver.is_some());
+        self.drive(flow, self.state.retry_policy.start_deadline(), None, |_| {}).await
+    }
 
-        log::debug!("Login response status: {}, URL: {}", status, final_url);
+    /// Log in with the SMS dynamic code requested by
+    /// [`request_dynamic_code`](Self::request_dynamic_code)
+    pub async fn dynamic_code_login(
+        &self,
+        pending: DynamicCodeLogin,
+        code: impl Into<Secret>,
+    ) -> Result<()> {
+        let span = Span::flow("dynamic_code_login", Some(&pending.account));
+        span.run(async {
+            let flow = CodeLogin::dynamic(&self.state.endpoints, pending, &code.into());
+            self.drive(flow, self.state.retry_policy.start_deadline(), None, |_| {}).await
+        })
+        .await
+    }
+
+    /// Ask IDAS to send the secondary verification code to the bound phone
+    pub async fn send_verification_code(&self, pending: &PendingVerification) -> Result<()> {
+        log::info!("Requesting verification code for user: {}", pending.username);
+        let flow = VerificationCodeRequest::new(&self.state.endpoints, pending);
+        self.drive(flow, self.state.retry_policy.start_deadline(), None, |_| {}).await
+    }
+
+    /// Resume a login paused by [`UestcClientError::VerificationRequired`]
+    pub async fn complete_verification(
+        &self,
+        pending: PendingVerification,
+        code: impl Into<Secret>,
+    ) -> Result<()> {
+        let span = Span::flow("complete_verification", Some(&pending.username));
+        span.run(async {
+            let flow = CodeLogin::verification(&self.state.endpoints, pending, &code.into());
+            self.drive(flow, self.state.retry_policy.start_deadline(), None, |_| {}).await
+        })
+        .await
+    }
 
-        // Login is successful if we're not on the login page
-        if status.is_redirection() || status.is_success() {
-            if !final_url.contains("/authserver/login") {
-                log::info!("Login successful for user: {}", username);
-                // Save cookies after successful login
-                if let Err(e) = self.save_cookie_store() {
-                    log::warn!("Failed to save cookies after login: {}", e);
+    /// Send a request on behalf of a login flow, retrying it as the retry
+    /// policy allows
+    async fn execute(&self, mut request: HttpRequest, deadline: Option<&Deadline>) -> Result<HttpResponse> {
+        let span = Span::step(&request);
+        span.run(async {
+            let mut attempts = Attempts::new(&self.state.retry_policy, deadline);
+            loop {
+                attempts.start(&mut request)?;
+                match self.send_once(&request).await {
+                    Ok(resp) => {
+                        span.record_response(resp.status, &resp.url);
+                        return Ok(resp);
+                    }
+                    Err(e) => {
+                        let delay = attempts.failed(&request, e)?;
+                        span.record_retries(attempts.retries());
+                        tokio::time::sleep(delay).await;
+                    }
                 }
-                return Ok(());
             }
+        })
+        .await
+    }
+
+    async fn send_once(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let resp = self.dispatch(&self.client, Request::from_flow(request)?).await?;
+        if request.error_for_status {
+            resp.error_for_status_ref()?;
         }
+        let url = resp.url().clone();
+        let status = resp.status();
+        // Read the whole body so that cookies are properly captured
+        let body = resp.text().await?;
+        Ok(HttpResponse { url, status, body })
+    }
 
-        // If we're still on login page, extract error message
-        let html = resp.text().await?;
-        let error_msg = core::parser::extract_error_message(&html)
-            .unwrap_or_else(|| format!("Login failed with status: {}", status));
+    /// Send a request, recording it when a HAR recorder is set
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let (client, request) = request.build_split();
+        self.dispatch(&client, request?).await
+    }
 
-        log::error!("Login failed for user {}: {}", username, error_msg);
+    /// Send `request` with `client`, recording it when a HAR recorder is set.
+    ///
+    /// While recording, redirects are followed here one hop at a time so
+    /// that each hop is recorded.
+    async fn dispatch(&self, client: &Client, request: Request) -> Result<Response> {
+        let Some(har) = &self.state.har_recorder else {
+            return Ok(client.execute(request).await?);
+        };
+        let Some(client) = &self.redirectless_client else {
+            return self.send_recorded(har, client, request).await;
+        };
 
-        Err(UestcClientError::LoginFailed {
-            message: error_msg,
-            username: Some(username.to_string()),
-        })
+        let mut hops = Hops::new(&request, self.state.redirect_limit);
+        let mut request = request;
+        loop {
+            let resp = self.send_recorded(har, client, request).await?;
+            match hops.follow(&resp)? {
+                Some(next) => request = next,
+                None => return Ok(resp),
+            }
+        }
     }
 
-    pub async fn logout(&self) -> Result<()> {
-        log::info!("Attempting to logout");
+    /// Send a single hop of a redirect chain, recording it when a HAR
+    /// recorder is set
+    async fn send_hop(&self, client: &Client, request: Request) -> Result<Response> {
+        match &self.state.har_recorder {
+            Some(har) => self.send_recorded(har, client, request).await,
+            None => Ok(client.execute(request).await?),
+        }
+    }
 
-        let logout_url = format!("{}/logout", AUTH_SERVER_URL);
-        let resp = self.client.get(&logout_url).send().await?;
+    /// Send `request` with `client` and record the exchange. The response
+    /// body is read for the recording and handed back in a new response.
+    async fn send_recorded(&self, har: &HarRecorder, client: &Client, request: Request) -> Result<Response> {
+        let exchange = self.state.start_exchange(&request);
+        let resp = client.execute(request).await?;
+        let head = ResponseHead::of(&resp);
+        let body = resp.bytes().await?;
+        Ok(Response::from(exchange.finish(har, head, body)))
+    }
 
-        if resp.status().is_success() {
-            log::info!("Logout successful");
-            // Clear cookies after logout
-            if let Err(e) = fs::remove_file(&self.cookie_file) {
-                log::warn!(
-                    "Failed to delete cookie file after logout: {}",
-                    e
-                );
-            }
-            return Ok(());
+    /// Run a login flow to completion, performing the I/O it asks for.
+    /// Requests in flight are abandoned once `cancel` is cancelled.
+    async fn drive<T, F>(
+        &self,
+        mut flow: impl Flow<Output = T>,
+        deadline: Option<Deadline>,
+        cancel: Option<&CancellationToken>,
+        mut on_event: F,
+    ) -> Result<T>
+    where
+        F: FnMut(WechatLoginEvent),
+    {
+        let mut input = Input::Start;
+        loop {
+            input = match flow.resume(input)? {
+                Step::Send(request) => Input::Response(match cancel {
+                    Some(token) => tokio::select! {
+                        resp = self.execute(request, deadline.as_ref()) => resp,
+                        _ = token.cancelled() => Err(UestcClientError::Cancelled {
+                            message: "WeChat login cancelled".to_string(),
+                        }),
+                    },
+                    None => self.execute(request, deadline.as_ref()).await,
+                }),
+                Step::SolveCaptcha(username) => Input::Captcha(self.solve_captcha(&username).await?),
+                Step::Sleep(duration) => {
+                    tokio::time::sleep(duration).await;
+                    Input::Continue
+                }
+                Step::Event(event) => {
+                    on_event(event);
+                    Input::Continue
+                }
+                Step::Done(output) => return Ok(output),
+                step => self.state.perform(step)?,
+            };
         }
+    }
+
+    /// Log in to a downstream system through the CAS `service=` flow.
+    ///
+    /// Requires an active CAS session, e.g. from [`login`](Self::login) or
+    /// [`wechat_login`](Self::wechat_login). CAS issues a service ticket for
+    /// `service_url`, which is followed through to the target application so
+    /// that it sets up its own session. Returns
+    /// [`UestcClientError::SessionExpired`] if CAS asks for credentials instead.
+    pub async fn login_to_service(&self, service_url: &str) -> Result<ServiceLogin<Response>> {
+        log::info!("Logging in to service: {}", service_url);
+
+        let mut walk = ServiceRedirects::new(&self.state.endpoints.auth_server_url, service_url)?;
+        let client = self.redirectless_client.as_ref().unwrap_or(&self.client);
+        let resp = loop {
+            let resp = self.send_hop(client, Request::new(Method::GET, walk.url().clone())).await?;
+            if !walk.follow(resp.status(), resp.headers())? {
+                break resp;
+            }
+        };
+        self.state.service_login(walk, service_url, resp)
+    }
+
+    async fn solve_captcha(&self, username: &str) -> Result<String> {
+        let solver = self.captcha_solver.as_ref().ok_or_else(|| captcha::solver_missing(username))?;
+        log::debug!("Fetching captcha image");
+        let image_url = core::captcha::build_image_url(&self.state.endpoints.auth_server_url);
+        let image = self.send(self.client.get(&image_url)).await?.bytes().await?;
+        captcha::answer(solver.solve(&image).await)
+    }
 
-        let error_msg = format!("Logout failed with status: {}", resp.status());
-        log::error!("{}", error_msg);
+    /// Who the current session belongs to, from the IDAS personal center.
+    ///
+    /// Returns [`UestcClientError::SessionExpired`] if there is no active
+    /// session, e.g. to check which account a saved cookie file is for.
+    pub async fn whoami(&self) -> Result<SessionInfo> {
+        log::debug!("Fetching user info");
+        let resp = self.send(self.client.post(&self.state.endpoints.user_info_url)).await?;
+        self.state.require_session(&resp)?;
+        core::user_info::parse_user_info(&resp.text().await?)
+    }
 
-        Err(UestcClientError::LogoutFailed {
-            message: error_msg,
+    /// Log out of CAS and forget the session, see [`logout_with`](Self::logout_with)
+    pub async fn logout(&self) -> Result<LogoutReport> {
+        self.logout_with(&LogoutOptions::default()).await
+    }
+
+    /// Log out of CAS, first visiting the downstream logout endpoints in
+    /// `options` of systems entered during this session.
+    ///
+    /// The in-memory cookies and the saved session are cleared even if IDAS
+    /// rejects the logout, in which case [`UestcClientError::LogoutFailed`] is
+    /// returned.
+    pub async fn logout_with(&self, options: &LogoutOptions) -> Result<LogoutReport> {
+        let span = Span::flow("logout", None);
+        span.run(async {
+            let flow = Logout::new(&self.state.endpoints, options, &self.state.take_entered_services());
+            self.drive(flow, self.state.retry_policy.start_deadline(), None, |_| {}).await
         })
+        .await
     }
 
     /// Login using WeChat QR code
     /// This will display a QR code in the terminal for scanning
     pub async fn wechat_login(&self) -> Result<()> {
-        use crate::core::wechat;
-
-        // Check if session is already active
-        log::debug!("检查已存储的会话");
-        if self.is_session_active().await {
-            log::info!("已经登录，无需重新登录");
-            return Ok(());
-        }
-        log::debug!("未检测到有效会话，开始微信登录流程");
-
-        log::debug!("正在连接 CAS 初始化参数");
+        self.wechat_login_with(&WechatLoginOptions::default()).await
+    }
 
-        // Step 1: Get WeChat OAuth parameters
-        let cas_login_url = format!("{}/combinedLogin.do?type=weixin", AUTH_SERVER_URL);
-        let resp = self.client.get(&cas_login_url).send().await?;
+    /// Login using WeChat QR code in the terminal, with a deadline,
+    /// cancellation or automatic QR refresh as configured in `options`
+    pub async fn wechat_login_with(&self, options: &WechatLoginOptions) -> Result<()> {
+        let span = Span::flow("wechat_login", None);
+        span.run(async {
+            use crate::core::wechat;
+
+            // Check if session is already active
+            log::debug!("检查已存储的会话");
+            match self.session_status().await {
+                SessionStatus::Active => {
+                    log::info!("已经登录，无需重新登录");
+                    return Ok(());
+                }
+                SessionStatus::LoggedOut => {}
+                SessionStatus::Undetermined(e) => return Err(e),
+            }
+            log::debug!("未检测到有效会话，开始微信登录流程");
 
-        // Extract WeChat OAuth parameters from the final URL
-        let wechat_auth_url = resp.url().to_string();
+            let qr = self.start_wechat_login().await?;
 
-        // Verify we got redirected to WeChat OAuth page
-        if !wechat_auth_url.contains("open.weixin.qq.com") {
-            return Err(UestcClientError::WeChatError {
-                message: format!(
-                    "Failed to redirect to WeChat login page, current URL: {}",
-                    wechat_auth_url
-                ),
-            });
-        }
+            // Display QR code in terminal
+            wechat::display_qr_in_terminal(qr.uuid())?;
 
-        let params = wechat::WechatAuthParams::from_url(&wechat_auth_url)?;
-        log::debug!("Target AppID: {}", params.appid);
-
-        // Step 2: Get QR code UUID
-        log::debug!("正在获取二维码 UUID");
-        let xml_url = params.build_qr_xml_url();
-        let resp = self.client.get(&xml_url).send().await?;
-        let xml_text = resp.text().await?;
-        let uuid = wechat::parse_qr_uuid_from_xml(&xml_text)?;
-
-        // Step 3: Display QR code in terminal
-        wechat::display_qr_in_terminal(&uuid)?;
-
-        // Step 4: Poll for scan status
-        log::debug!("等待扫码");
-        let mut last_code: Option<String> = None;
-        let wx_code = loop {
-            let poll_url = wechat::build_poll_url(&uuid, last_code.as_deref());
-            let resp = self.client
-                .get(&poll_url)
-                .timeout(std::time::Duration::from_secs(30))
-                .send()
-                .await?;
-            let text = resp.text().await?;
-            let result = wechat::parse_scan_status(&text)?;
-
-            match result.status {
-                wechat::ScanStatus::Confirmed => {
-                    log::debug!("登录成功 (405)");
-                    if let Some(code) = result.wx_code {
-                        log::debug!("获取到 wx_code");
-                        break code;
-                    } else {
-                        return Err(UestcClientError::WeChatError {
-                            message: "Received 405 status but wx_code not found".to_string(),
-                        });
-                    }
-                }
-                wechat::ScanStatus::Scanned => {
+            log::debug!("等待扫码");
+            self.wait_wechat_login_with(&qr, options, |event| match event {
+                WechatLoginEvent::Status(ScanStatus::Scanned) => {
                     log::info!("已扫码，请在手机上点击确认");
-                    last_code = Some("404".to_string());
                 }
-                wechat::ScanStatus::Expired => {
-                    return Err(UestcClientError::WeChatError {
-                        message: "QR code expired, please run again".to_string(),
-                    });
-                }
-                wechat::ScanStatus::Waiting => {
-                    // Keep waiting silently
-                }
-                wechat::ScanStatus::Unknown(code) => {
-                    log::warn!("未知状态码: {}", code);
+                WechatLoginEvent::QrRefreshed(qr) => {
+                    if let Err(e) = wechat::display_qr_in_terminal(qr.uuid()) {
+                        log::warn!("Failed to display refreshed QR code: {}", e);
+                    }
                 }
-            }
+                WechatLoginEvent::Status(_) => {}
+            })
+            .await
+        })
+        .await
+    }
 
-            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-        };
+    /// Start a WeChat QR login without displaying anything.
+    ///
+    /// Render the returned QR code (PNG, SVG, Unicode or just its confirm
+    /// URL) wherever the user is, then call
+    /// [`wait_wechat_login`](Self::wait_wechat_login) to complete the login.
+    pub async fn start_wechat_login(&self) -> Result<WechatQrLogin> {
+        let span = Span::flow("wechat_start", None);
+        span.run(async {
+            let deadline = self.state.retry_policy.start_deadline();
+            self.drive(WechatStart::new(&self.state.endpoints), deadline, None, |_| {}).await
+        })
+        .await
+    }
 
-        // Step 5: Complete login
-        log::debug!("正在验证登录");
-        let callback_url = params.build_callback_url(&wx_code);
-        let resp = self.client.get(&callback_url).send().await?;
-        let final_url = resp.url().to_string();
+    /// Wait for the QR code from [`start_wechat_login`](Self::start_wechat_login)
+    /// to be scanned and confirmed, then complete the login.
+    ///
+    /// `on_status` is called whenever the scan status changes. This waits
+    /// without a deadline; see [`wait_wechat_login_with`](Self::wait_wechat_login_with).
+    pub async fn wait_wechat_login<F>(&self, qr: &WechatQrLogin, mut on_status: F) -> Result<()>
+    where
+        F: FnMut(&ScanStatus),
+    {
+        self.wait_wechat_login_with(qr, &WechatLoginOptions::default(), |event| {
+            if let WechatLoginEvent::Status(status) = event {
+                on_status(&status);
+            }
+        })
+        .await
+    }
 
-        // Consume the response body to ensure cookies are properly captured
-        let _ = resp.bytes().await?;
+    /// Like [`wait_wechat_login`](Self::wait_wechat_login), with a deadline,
+    /// cancellation or automatic QR refresh as configured in `options`.
+    ///
+    /// When the QR code is refreshed, `on_event` receives the new code, which
+    /// replaces the one shown to the user.
+    pub async fn wait_wechat_login_with<F>(
+        &self,
+        qr: &WechatQrLogin,
+        options: &WechatLoginOptions,
+        on_event: F,
+    ) -> Result<()>
+    where
+        F: FnMut(WechatLoginEvent),
+    {
+        let span = Span::flow("wechat_wait", None);
+        span.run(async {
+            let flow = WechatWait::new(&self.state.endpoints, qr, options);
+            let deadline = options.deadline.map(Deadline::after);
+            self.drive(flow, deadline, options.cancel.as_ref(), on_event).await
+        })
+        .await
+    }
 
-        // Check if login succeeded by examining the final URL
-        if !final_url.contains("/authserver/login") {
-            // Save cookies after successful login
-            if let Err(e) = self.save_cookie_store() {
-                log::warn!("Failed to save cookies after WeChat login: {}", e);
-            }
-            log::info!("微信登录成功");
-            Ok(())
-        } else {
-            Err(UestcClientError::WeChatError {
-                message: "WeChat login failed, still on login page".to_string(),
-            })
-        }
+    /// Check whether the CAS session is still active.
+    ///
+    /// A failed request or an unexpected answer gives
+    /// [`SessionStatus::Undetermined`] instead of counting as logged out.
+    /// Nothing is saved; call [`save_cookie_store`](Self::save_cookie_store)
+    /// to persist cookies refreshed by the check.
+    pub async fn session_status(&self) -> SessionStatus {
+        let deadline = self.state.retry_policy.start_deadline();
+        Span::flow("session_check", None)
+            .run(self.drive(SessionCheck::new(&self.state.endpoints), deadline, None, |_| {}))
+            .await
+            .unwrap_or_else(SessionStatus::Undetermined)
     }
 
-    /// Check if the current session is still active
-    /// Returns true if logged in, false otherwise
+    /// Whether the session is active. A failed check counts as inactive, use
+    /// [`session_status`](Self::session_status) to tell the two apart.
     pub async fn is_session_active(&self) -> bool {
-        let login_url = format!("{}/login", AUTH_SERVER_URL);
-        let expected_redirect = "https://idas.uestc.edu.cn/personalInfo/personCenter/index.html";
-
-        log::debug!("Checking session status");
-
-        match self.client.get(&login_url).send().await {
-            Ok(resp) => {
-                let final_url = resp.url().to_string();
-                // If we're redirected to personal center, session is active
-                if final_url == expected_redirect {
-                    log::debug!("Session is active");
-                    // Save cookies when session is confirmed active
-                    if let Err(e) = self.save_cookie_store() {
-                        log::warn!("Failed to save cookies during session check: {}", e);
-                    }
-                    true
-                } else {
-                    log::debug!("Session is not active (URL: {})", final_url);
-                    false
+        self.session_status().await.is_active()
+    }
+
+    /// Check the session every `config.interval` on a background task,
+    /// starting right away, and log in again through the credential provider
+    /// when it has lapsed. Cookies are saved after every successful check.
+    ///
+    /// State changes are reported to `on_event`. The task runs until the
+    /// returned handle is stopped or dropped.
+    pub fn spawn_keep_alive<F>(
+        self: &Arc<Self>,
+        config: KeepAliveConfig,
+        mut on_event: F,
+    ) -> KeepAliveHandle
+    where
+        F: FnMut(KeepAliveEvent) + Send + 'static,
+    {
+        let client = Arc::clone(self);
+        let token = CancellationToken::new();
+        let stop = token.clone();
+
+        let task = tokio::spawn(async move {
+            let mut keep_alive = KeepAliveState::default();
+            loop {
+                client.keep_alive_check(&config, &mut keep_alive, &mut on_event).await;
+                tokio::select! {
+                    _ = stop.cancelled() => break,
+                    _ = tokio::time::sleep(config.interval) => {}
                 }
             }
-            Err(e) => {
-                log::debug!("Session check failed: {}", e);
-                false
-            }
+            log::debug!("Keep-alive stopped");
+        });
+
+        KeepAliveHandle { token, task }
+    }
+
+    async fn keep_alive_check<F: FnMut(KeepAliveEvent)>(
+        &self,
+        config: &KeepAliveConfig,
+        keep_alive: &mut KeepAliveState,
+        on_event: &mut F,
+    ) {
+        let status = self.session_status().await;
+        if keep_alive.observe(&self.state, config, status, on_event) {
+            keep_alive.relogged(self.login_with_provider().await, on_event);
         }
     }
 
+    /// Send a request, logging in again and replaying it once if it ends
+    /// up on the IDAS login page because the session has expired.
+    ///
+    /// Returns [`UestcClientError::SessionExpired`] if no credentials are
+    /// configured, the request body cannot be replayed, or the replayed
+    /// request still lands on the login page. A failed re-login returns the
+    /// login error instead.
+    pub async fn send_authenticated(&self, request: RequestBuilder) -> Result<Response> {
+        let replay = request.try_clone();
+        let resp = self.send(request).await?;
+        if !self.state.is_login_redirect(&resp) {
+            return Ok(resp);
+        }
+
+        let replay = self.state.replay_after_expiry(replay)?;
+        self.relogin().await?;
+
+        log::debug!("Replaying request after re-login");
+        self.state.replayed(self.send(replay).await?)
+    }
+
+    /// Log in with credentials from the configured [`CredentialProvider`].
+    ///
+    /// Returns [`UestcClientError::CredentialError`] if no provider is configured.
+    pub async fn login_with_provider(&self) -> Result<()> {
+        let credentials = self.state.credentials()?;
+        self.login(&credentials.username, credentials.password).await
+    }
+
+    async fn relogin(&self) -> Result<()> {
+        let credentials = self.state.relogin_credentials()?;
+        self.login(&credentials.username, credentials.password).await
+    }
+
     pub fn request<U: IntoUrl>(&self, method: Method, url: U) -> RequestBuilder {
         self.client.request(method, url)
     }
@@ -449,22 +642,30 @@ impl UestcClient {
     }
 }
 
+impl Default for UestcClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_new() {
         let _client = UestcClient::new();
-        assert!(true);
     }
 
     #[tokio::test]
     async fn test_with_client() {
         use reqwest::Client;
         let req_client = Client::new();
-        let _client = UestcClient::with_client(req_client);
-        assert!(true);
+        let client = UestcClient::with_client(req_client);
+        // The supplied client's cookies are out of reach
+        assert!(client.state.cookie_store.is_none());
+        assert!(client.save_cookie_store().is_ok());
     }
 
     #[tokio::test]
@@ -473,4 +674,292 @@ mod tests {
         let result = client.login("1234567890", "password123").await;
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_send_authenticated_without_credentials() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        // Every business request bounces to the login page
+        let base_url = spawn_server(2, |request_line| {
+            if request_line.starts_with("GET /site/bedroom") {
+                response("302 Found", &[("Location", "/authserver/login?service=x")], "")
+            } else {
+                response("200 OK", &[], "<html>login</html>")
+            }
+        });
+
+        let client = test_client(&base_url).build_async().unwrap();
+        let result = client
+            .send_authenticated(client.get(format!("{}/site/bedroom", base_url)))
+            .await;
+        assert!(matches!(result, Err(UestcClientError::SessionExpired)));
+    }
+
+    #[tokio::test]
+    async fn test_login_with_provider() {
+        let client = UestcClient::with_client(Client::new());
+        let result = client.login_with_provider().await;
+        assert!(matches!(result, Err(UestcClientError::CredentialError { .. })));
+
+        // Provider errors are returned before any request is made
+        let client = client.with_relogin_callback(|| {
+            Err(UestcClientError::CredentialError {
+                message: "vault unavailable".to_string(),
+                source: None,
+            })
+        });
+        let err = client.login_with_provider().await.unwrap_err();
+        assert!(err.to_string().contains("vault unavailable"));
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_reports_active_session() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        let base_url = spawn_server(2, |request_line| {
+            if request_line.starts_with("GET /authserver/login") {
+                response(
+                    "302 Found",
+                    &[("Location", "/personalInfo/personCenter/index.html")],
+                    "",
+                )
+            } else {
+                response("200 OK", &[], "ok")
+            }
+        });
+
+        let client = Arc::new(test_client(&base_url).build_async().unwrap());
+        let (events, received) = std::sync::mpsc::channel();
+        let config = KeepAliveConfig::new().interval(Duration::from_secs(3600));
+        let handle = client.spawn_keep_alive(config, move |event| {
+            let _ = events.send(event);
+        });
+
+        let event = tokio::task::spawn_blocking(move || received.recv_timeout(Duration::from_secs(10)))
+            .await
+            .unwrap();
+        assert!(matches!(event, Ok(KeepAliveEvent::SessionActive)));
+        handle.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_logout_clears_session() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+        use crate::{MemorySessionStore, SessionStore};
+
+        let base_url = spawn_server(2, |request_line| {
+            if request_line.starts_with("GET /common/logout")
+                || request_line.starts_with("GET /authserver/logout")
+            {
+                response("200 OK", &[], "ok")
+            } else {
+                response("404 Not Found", &[], "")
+            }
+        });
+
+        let sessions = Arc::new(MemorySessionStore::new());
+        let client = test_client(&base_url)
+            .session_store(sessions.clone())
+            .build_async()
+            .unwrap();
+        let url = url::Url::parse(&base_url).unwrap();
+        client
+            .state
+            .cookie_store
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .insert_raw(&"JSESSIONID=abc; Path=/".parse().unwrap(), &url)
+            .unwrap();
+        client.save_cookie_store().unwrap();
+        client.state.enter_service(&base_url);
+
+        let options = LogoutOptions::new()
+            .service_logout(format!("{}/common/logout", base_url))
+            .service_logout("https://eams.uestc.edu.cn/eams/logout.action");
+        let report = client.logout_with(&options).await.unwrap();
+
+        assert_eq!(report.services.len(), 1);
+        assert!(report.services[0].succeeded());
+        assert_eq!(report.cookies_cleared, 1);
+        assert!(report.saved_session_cleared);
+        assert_eq!(client.state.cookie_store.as_ref().unwrap().lock().unwrap().iter_any().count(), 0);
+        assert!(sessions.load().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_whoami() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        let base_url = spawn_server(3, |request_line| {
+            if request_line.starts_with("POST /personalInfo/common/getUserConf") {
+                response(
+                    "200 OK",
+                    &[("Content-Type", "application/json")],
+                    r#"{"code":"0","datas":{"uid":"2022010901001","cn":"Zhang San"}}"#,
+                )
+            } else if request_line.starts_with("GET /authserver/login") {
+                response("200 OK", &[], "<html></html>")
+            } else {
+                response("302 Found", &[("Location", "/authserver/login")], "")
+            }
+        });
+
+        let mut client = test_client(&base_url).build_async().unwrap();
+        let info = client.whoami().await.unwrap();
+        assert_eq!(info.id, "2022010901001");
+        assert_eq!(info.name, "Zhang San");
+
+        // Without a session the personal center bounces to the login page
+        client.state.endpoints.user_info_url = format!("{}/personalInfo/expired", base_url);
+        assert!(matches!(
+            client.whoami().await,
+            Err(UestcClientError::SessionExpired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_undetermined_session_skips_login() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        // IDAS is down: the check must not count as logged out
+        let base_url = spawn_server(2, |_| response("502 Bad Gateway", &[], ""));
+        let client = test_client(&base_url)
+            .retry_policy(RetryPolicy::none())
+            .build_async()
+            .unwrap();
+
+        assert!(matches!(
+            client.session_status().await,
+            SessionStatus::Undetermined(_)
+        ));
+        assert!(matches!(
+            client.login("2022010901001", "password").await,
+            Err(UestcClientError::NetworkError { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_check_retries_server_error() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // The first check hits a 503, the retry finds the session active
+        static CHECKS: AtomicUsize = AtomicUsize::new(0);
+        let base_url = spawn_server(3, |request_line| {
+            if !request_line.starts_with("GET /authserver/login") {
+                response("200 OK", &[], "ok")
+            } else if CHECKS.fetch_add(1, Ordering::SeqCst) == 0 {
+                response("503 Service Unavailable", &[], "")
+            } else {
+                response(
+                    "302 Found",
+                    &[("Location", "/personalInfo/personCenter/index.html")],
+                    "",
+                )
+            }
+        });
+        let client = test_client(&base_url)
+            .retry_policy(RetryPolicy::new().backoff(Duration::from_millis(10), Duration::from_millis(10)))
+            .build_async()
+            .unwrap();
+
+        assert!(client.session_status().await.is_active());
+        assert_eq!(CHECKS.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_har_recorder_records_each_redirect_hop() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        let base_url = spawn_server(3, |request_line| {
+            if request_line.starts_with("GET /authserver/login") {
+                response(
+                    "302 Found",
+                    &[
+                        ("Location", "/personalInfo/cas?ticket=ST-1-abc"),
+                        ("Set-Cookie", "CASTGC=TGT-1-secret; Path=/authserver; HttpOnly"),
+                    ],
+                    "",
+                )
+            } else if request_line.starts_with("GET /personalInfo/cas") {
+                response("302 Found", &[("Location", "/personalInfo/personCenter/index.html")], "")
+            } else {
+                response("200 OK", &[("Content-Type", "text/html")], "<html>个人中心</html>")
+            }
+        });
+        let path = std::env::temp_dir().join(format!("uestc_client_async_{}.har", std::process::id()));
+        let recorder = HarRecorder::new(&path);
+        let client = test_client(&base_url)
+            .har_recorder(recorder.clone())
+            .build_async()
+            .unwrap();
+
+        assert!(client.session_status().await.is_active());
+        assert_eq!(recorder.len(), 3);
+        recorder.save().unwrap();
+
+        let har: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries[0]["response"]["status"], 302);
+        assert_eq!(entries[0]["response"]["cookies"][0]["name"], "CASTGC");
+        assert!(entries[0]["response"]["redirectURL"].as_str().unwrap().ends_with("?ticket=***"));
+        assert_eq!(entries[1]["request"]["queryString"][0]["value"], "***");
+        assert_eq!(entries[2]["response"]["content"]["text"], "<html>个人中心</html>");
+        assert!(!har.to_string().contains("TGT-1") && !har.to_string().contains("ST-1"));
+    }
+
+    #[tokio::test]
+    async fn test_har_recorder_redirect_loop() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        let base_url = spawn_server(4, |_| response("302 Found", &[("Location", "/loop")], ""));
+        let path = std::env::temp_dir().join(format!("uestc_client_async_loop_{}.har", std::process::id()));
+        let recorder = HarRecorder::new(&path);
+        let client = test_client(&base_url)
+            .redirect_limit(3)
+            .har_recorder(recorder.clone())
+            .build_async()
+            .unwrap();
+
+        assert!(matches!(
+            client.session_status().await,
+            SessionStatus::Undetermined(UestcClientError::TooManyRedirects { limit: 3, .. })
+        ));
+        assert_eq!(recorder.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_login_to_service_redirect_loop() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        let base_url = spawn_server(11, |_| response("302 Found", &[("Location", "/loop")], ""));
+        let client = test_client(&base_url).build_async().unwrap();
+
+        assert!(matches!(
+            client.login_to_service("https://eportal.uestc.edu.cn/").await,
+            Err(UestcClientError::TooManyRedirects { limit: 10, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_deadline_passing_mid_request_is_a_timeout() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        let base_url = spawn_server(1, |_| {
+            std::thread::sleep(Duration::from_millis(500));
+            response("200 OK", &[], "")
+        });
+        let client = test_client(&base_url)
+            .retry_policy(RetryPolicy::new().deadline(Duration::from_millis(100)))
+            .build_async()
+            .unwrap();
+
+        assert!(matches!(
+            client.session_status().await,
+            SessionStatus::Undetermined(UestcClientError::Timeout { .. })
+        ));
+    }
 }
@@ -0,0 +1,193 @@
+//! Saving the cookie file whenever a response changes the cookie store.
+
+use super::session::{SessionSnapshot, SessionStore};
+use crate::core::cookie_jar;
+use crate::core::log;
+use reqwest::header::HeaderValue;
+use reqwest_cookie_store::CookieStoreMutex;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+enum Signal {
+    Changed,
+    Shutdown,
+}
+
+/// The cookie provider handed to reqwest. Wraps the shared store and, with
+/// autosave enabled, tells the autosave thread when a response changed it.
+#[derive(Debug)]
+pub(crate) struct TrackedCookieStore {
+    store: Arc<CookieStoreMutex>,
+    changes: Option<Sender<Signal>>,
+}
+
+impl TrackedCookieStore {
+    pub(crate) fn new(store: Arc<CookieStoreMutex>, autosave: Option<&Autosave>) -> Self {
+        Self {
+            store,
+            changes: autosave.map(|autosave| autosave.signals.clone()),
+        }
+    }
+}
+
+impl reqwest::cookie::CookieStore for TrackedCookieStore {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &url::Url) {
+        let Some(changes) = &self.changes else {
+            return self.store.set_cookies(cookie_headers, url);
+        };
+
+        let mut store = self.store.lock().unwrap();
+        let before = cookie_jar::snapshot(&store);
+        let cookies = cookie_headers.filter_map(|value| {
+            std::str::from_utf8(value.as_bytes())
+                .ok()
+                .and_then(|s| cookie_store::RawCookie::parse(s).ok())
+                .map(|c| c.into_owned())
+        });
+        store.store_response_cookies(cookies, url);
+        if cookie_jar::snapshot(&store) != before {
+            let _ = changes.send(Signal::Changed);
+        }
+    }
+
+    fn cookies(&self, url: &url::Url) -> Option<HeaderValue> {
+        self.store.cookies(url)
+    }
+}
+
+/// Background thread writing the cookie file after changes.
+///
+/// Changes are batched: the file is written `delay` after the first unsaved
+/// change. Dropping it writes any pending changes before returning.
+#[derive(Debug)]
+pub(crate) struct Autosave {
+    signals: Sender<Signal>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Autosave {
+    pub(crate) fn spawn(
+        sessions: Arc<dyn SessionStore>,
+        store: Arc<CookieStoreMutex>,
+        delay: Duration,
+    ) -> Self {
+        let (signals, received) = mpsc::channel();
+        let thread = thread::spawn(move || run(sessions.as_ref(), &store, delay, &received));
+        Self {
+            signals,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Autosave {
+    fn drop(&mut self) {
+        let _ = self.signals.send(Signal::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(
+    sessions: &dyn SessionStore,
+    store: &CookieStoreMutex,
+    delay: Duration,
+    received: &Receiver<Signal>,
+) {
+    let save = || {
+        if let Err(e) = sessions.save(&SessionSnapshot::capture(store)) {
+            log::warn!("自动保存 cookies 失败: {}", e);
+        }
+    };
+
+    while let Ok(Signal::Changed) = received.recv() {
+        let due = Instant::now() + delay;
+        loop {
+            match received.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                Ok(Signal::Changed) => continue,
+                Err(RecvTimeoutError::Timeout) => {
+                    log::debug!("自动保存 cookies");
+                    save();
+                    break;
+                }
+                Ok(Signal::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                    log::debug!("退出前保存 cookies");
+                    save();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::cookies::FileSessionStore;
+    use reqwest::cookie::CookieStore as _;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("uestc_client_{}_{}", name, std::process::id()))
+    }
+
+    fn set_cookie(store: &TrackedCookieStore, header: &'static str) {
+        let url = url::Url::parse("https://online.uestc.edu.cn/site/bedroom").unwrap();
+        let header = HeaderValue::from_static(header);
+        store.set_cookies(&mut std::iter::once(&header), &url);
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        let path = temp_path("autosave_drop.json");
+        let sessions: Arc<dyn SessionStore> = Arc::new(FileSessionStore::new(path.clone()));
+        let store = Arc::new(CookieStoreMutex::default());
+
+        let autosave = Autosave::spawn(sessions, store.clone(), Duration::from_secs(3600));
+        let tracked = TrackedCookieStore::new(store, Some(&autosave));
+        set_cookie(&tracked, "p_auth_token=abc; Path=/");
+        drop(autosave);
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(format!("{}.lock", path.display()));
+        assert!(saved.contains("p_auth_token"));
+    }
+
+    #[test]
+    fn test_saves_after_delay() {
+        let path = temp_path("autosave_delay.json");
+        let sessions: Arc<dyn SessionStore> = Arc::new(FileSessionStore::new(path.clone()));
+        let store = Arc::new(CookieStoreMutex::default());
+
+        let autosave = Autosave::spawn(sessions, store.clone(), Duration::from_millis(50));
+        let tracked = TrackedCookieStore::new(store, Some(&autosave));
+        set_cookie(&tracked, "p_auth_token=abc; Path=/");
+
+        let started = Instant::now();
+        while !path.exists() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let saved = std::fs::read_to_string(&path).unwrap();
+        drop(autosave);
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(format!("{}.lock", path.display()));
+        assert!(saved.contains("p_auth_token"));
+    }
+
+    #[test]
+    fn test_unchanged_cookies_not_signalled() {
+        let store = Arc::new(CookieStoreMutex::default());
+        let (signals, received) = mpsc::channel();
+        let tracked = TrackedCookieStore {
+            store,
+            changes: Some(signals),
+        };
+
+        set_cookie(&tracked, "route=r1; Path=/");
+        set_cookie(&tracked, "route=r1; Path=/");
+        assert_eq!(received.try_iter().count(), 1);
+    }
+}
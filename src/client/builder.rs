@@ -0,0 +1,373 @@
+use crate::core::endpoints::DEFAULT_AUTH_SERVER_URL;
+use super::cookies::FileSessionStore;
+use super::har::HarRecorder;
+use super::lockout::LoginGuard;
+use super::retry::RetryPolicy;
+use super::session::{self, SessionStore};
+use crate::core::cookie_cipher::KeySource;
+use crate::{Result, UestcClientError};
+use cookie_store::CookieStore;
+use reqwest::header::HeaderMap;
+use reqwest::{Certificate, Proxy, redirect};
+use reqwest_cookie_store::CookieStoreMutex;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use super::UestcClient;
+
+#[cfg(feature = "blocking")]
+use super::UestcBlockingClient;
+
+pub const DEFAULT_COOKIE_FILE: &str = "uestc_cookies.json";
+
+const DEFAULT_REDIRECT_LIMIT: usize = 10;
+
+/// The live cookie store and the session store it is saved to, if any
+pub(crate) type CookieParts = (Arc<CookieStoreMutex>, Option<Arc<dyn SessionStore>>);
+
+/// Where the client keeps its cookies
+#[derive(Clone)]
+pub enum CookiePersistence {
+    /// Load cookies from and save them to a JSON file
+    File(PathBuf),
+    /// Like `File`, but encrypted with AES-256-GCM.
+    ///
+    /// A plaintext file at `path` is still read and is encrypted on the next
+    /// save. Building the client fails with [`UestcClientError::CookieError`]
+    /// if the file cannot be decrypted with `key`.
+    EncryptedFile { path: PathBuf, key: CookieKey },
+    /// Keep cookies in memory for the lifetime of the client
+    Memory,
+    /// Use a caller-owned cookie store, which the client never persists
+    Custom(Arc<CookieStoreMutex>),
+    /// Load cookies from and save them to a [`SessionStore`]
+    Store(Arc<dyn SessionStore>),
+}
+
+impl fmt::Debug for CookiePersistence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CookiePersistence::File(path) => f.debug_tuple("File").field(path).finish(),
+            CookiePersistence::EncryptedFile { path, key } => f
+                .debug_struct("EncryptedFile")
+                .field("path", path)
+                .field("key", key)
+                .finish(),
+            CookiePersistence::Memory => f.write_str("Memory"),
+            CookiePersistence::Custom(_) => f.write_str("Custom(..)"),
+            CookiePersistence::Store(_) => f.write_str("Store(..)"),
+        }
+    }
+}
+
+/// Secret the cookie file encryption key is derived from
+#[derive(Clone)]
+pub enum CookieKey {
+    /// Stretched with PBKDF2-HMAC-SHA256
+    Passphrase(String),
+    /// A file whose contents are hashed into the key, e.g. 32 random bytes
+    KeyFile(PathBuf),
+}
+
+impl fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CookieKey::Passphrase(_) => f.write_str("Passphrase(\"***\")"),
+            CookieKey::KeyFile(path) => f.debug_tuple("KeyFile").field(path).finish(),
+        }
+    }
+}
+
+impl CookieKey {
+    pub(crate) fn with_source<T>(&self, f: impl FnOnce(KeySource<'_>) -> Result<T>) -> Result<T> {
+        match self {
+            CookieKey::Passphrase(passphrase) => f(KeySource::Passphrase(passphrase)),
+            CookieKey::KeyFile(path) => {
+                let material = std::fs::read(path).map_err(|e| UestcClientError::CookieError {
+                    operation: "read key".to_string(),
+                    file_path: Some(path.display().to_string()),
+                    message: format!("Failed to read cookie key file: {}", e),
+                    source: Some(Box::new(e)),
+                })?;
+                f(KeySource::KeyMaterial(&material))
+            }
+        }
+    }
+}
+
+/// Builder shared by [`UestcClient`] and [`UestcBlockingClient`].
+///
+/// ```no_run
+/// # #[cfg(feature = "async")]
+/// # fn main() -> uestc_client::Result<()> {
+/// use std::time::Duration;
+/// use uestc_client::{CookiePersistence, UestcClient};
+///
+/// let client = UestcClient::builder()
+///     .auth_server_url("http://127.0.0.1:8080/authserver")
+///     .proxy(reqwest::Proxy::all("http://proxy.lab:3128")?)
+///     .connect_timeout(Duration::from_secs(5))
+///     .cookie_persistence(CookiePersistence::Memory)
+///     .build_async()?;
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "async"))]
+/// # fn main() {}
+/// ```
+#[derive(Debug, Clone)]
+pub struct UestcClientBuilder {
+    pub(crate) auth_server_url: String,
+    pub(crate) proxies: Vec<Proxy>,
+    pub(crate) no_proxy: bool,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) redirect_limit: usize,
+    pub(crate) headers: HeaderMap,
+    pub(crate) cookie_persistence: CookiePersistence,
+    pub(crate) root_certificates: Vec<Certificate>,
+    pub(crate) autosave: Option<Duration>,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) login_guard: Option<LoginGuard>,
+    pub(crate) har_recorder: Option<HarRecorder>,
+}
+
+impl Default for UestcClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UestcClientBuilder {
+    pub fn new() -> Self {
+        Self {
+            auth_server_url: DEFAULT_AUTH_SERVER_URL.to_string(),
+            proxies: Vec::new(),
+            no_proxy: false,
+            connect_timeout: None,
+            read_timeout: None,
+            redirect_limit: DEFAULT_REDIRECT_LIMIT,
+            headers: super::default_headers(),
+            cookie_persistence: CookiePersistence::File(PathBuf::from(DEFAULT_COOKIE_FILE)),
+            root_certificates: Vec::new(),
+            autosave: None,
+            retry_policy: RetryPolicy::default(),
+            login_guard: None,
+            har_recorder: None,
+        }
+    }
+
+    /// Base URL of the IDAS auth server, without a trailing slash.
+    ///
+    /// Defaults to `https://idas.uestc.edu.cn/authserver`.
+    pub fn auth_server_url(mut self, url: impl Into<String>) -> Self {
+        self.auth_server_url = url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Route requests through a proxy. May be called multiple times.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxies.push(proxy);
+        self
+    }
+
+    /// Ignore system proxy settings and any configured proxies
+    pub fn no_proxy(mut self) -> Self {
+        self.no_proxy = true;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Timeout for reading a response.
+    ///
+    /// The blocking client applies it to the whole request instead, as
+    /// `reqwest::blocking` has no separate read timeout.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Maximum number of redirects followed per request. `0` disables redirects.
+    pub fn redirect_limit(mut self, limit: usize) -> Self {
+        self.redirect_limit = limit;
+        self
+    }
+
+    /// Replace the default header profile, see [`default_headers`](crate::default_headers)
+    pub fn default_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Shorthand for [`CookiePersistence::File`]
+    pub fn cookie_file(self, path: impl Into<PathBuf>) -> Self {
+        self.cookie_persistence(CookiePersistence::File(path.into()))
+    }
+
+    /// Shorthand for [`CookiePersistence::EncryptedFile`]
+    pub fn encrypted_cookie_file(self, path: impl Into<PathBuf>, key: CookieKey) -> Self {
+        self.cookie_persistence(CookiePersistence::EncryptedFile {
+            path: path.into(),
+            key,
+        })
+    }
+
+    /// Shorthand for [`CookiePersistence::Store`]
+    pub fn session_store<S: SessionStore + 'static>(self, store: S) -> Self {
+        self.cookie_persistence(CookiePersistence::Store(Arc::new(store)))
+    }
+
+    pub fn cookie_persistence(mut self, persistence: CookiePersistence) -> Self {
+        self.cookie_persistence = persistence;
+        self
+    }
+
+    /// Save the session whenever a response changes a cookie, at most
+    /// `delay` after the change, and once more when the client is dropped.
+    ///
+    /// Applies to every persistence that saves through a [`SessionStore`]:
+    /// files, encrypted files and [`session_store`](Self::session_store).
+    /// `Memory` and `Custom` stores are never saved. Without it, cookies are
+    /// saved after logins and session checks only.
+    pub fn autosave(mut self, delay: Duration) -> Self {
+        self.autosave = Some(delay);
+        self
+    }
+
+    /// Retries and deadline for login flows, see [`RetryPolicy`]
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Count failed password logins and refuse attempts that could lock the
+    /// account, see [`LoginGuard`]
+    pub fn login_guard(mut self, guard: LoginGuard) -> Self {
+        self.login_guard = Some(guard);
+        self
+    }
+
+    /// Record the client's traffic to a HAR file, see [`HarRecorder`]
+    pub fn har_recorder(mut self, recorder: HarRecorder) -> Self {
+        self.har_recorder = Some(recorder);
+        self
+    }
+
+    /// Trust an additional root certificate, e.g. for a local stand-in server
+    pub fn add_root_certificate(mut self, cert: Certificate) -> Self {
+        self.root_certificates.push(cert);
+        self
+    }
+
+    #[cfg(feature = "async")]
+    pub fn build_async(self) -> Result<UestcClient> {
+        UestcClient::from_builder(self)
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn build_blocking(self) -> Result<UestcBlockingClient> {
+        UestcBlockingClient::from_builder(self)
+    }
+
+    pub(crate) fn redirect_policy(&self) -> redirect::Policy {
+        if self.redirect_limit == 0 {
+            redirect::Policy::none()
+        } else {
+            redirect::Policy::limited(self.redirect_limit)
+        }
+    }
+
+    /// Resolve the cookie store and where it is persisted, if anywhere,
+    /// loading the saved session
+    pub(crate) fn cookie_parts(&self) -> Result<CookieParts> {
+        let sessions: Arc<dyn SessionStore> = match &self.cookie_persistence {
+            CookiePersistence::File(path) => Arc::new(FileSessionStore::new(path.clone())),
+            CookiePersistence::EncryptedFile { path, key } => {
+                Arc::new(FileSessionStore::encrypted(path.clone(), key.clone()))
+            }
+            CookiePersistence::Store(store) => store.clone(),
+            CookiePersistence::Memory => {
+                return Ok((Arc::new(CookieStoreMutex::new(CookieStore::default())), None));
+            }
+            CookiePersistence::Custom(store) => return Ok((store.clone(), None)),
+        };
+        Ok((session::restore_or_default(sessions.as_ref())?, Some(sessions)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_server_url_trailing_slash() {
+        let builder = UestcClientBuilder::new().auth_server_url("http://127.0.0.1:8080/authserver/");
+        assert_eq!(builder.auth_server_url, "http://127.0.0.1:8080/authserver");
+    }
+
+    #[test]
+    fn test_custom_cookie_store_is_shared() {
+        let store = Arc::new(CookieStoreMutex::new(CookieStore::default()));
+        let builder =
+            UestcClientBuilder::new().cookie_persistence(CookiePersistence::Custom(store.clone()));
+        let (resolved, file) = builder.cookie_parts().unwrap();
+        assert!(Arc::ptr_eq(&store, &resolved));
+        assert!(file.is_none());
+    }
+
+    #[test]
+    fn test_debug_hides_cookies() {
+        let url = url::Url::parse("https://idas.uestc.edu.cn/authserver/login").unwrap();
+        let mut store = CookieStore::default();
+        store.insert_raw(&"CASTGC=TGT-1; Path=/authserver".parse().unwrap(), &url).unwrap();
+        let builder = UestcClientBuilder::new()
+            .cookie_persistence(CookiePersistence::Custom(Arc::new(CookieStoreMutex::new(store))));
+
+        let debug = format!("{:?}", builder);
+        assert!(debug.contains("Custom(..)"));
+        assert!(!debug.contains("TGT-1"));
+    }
+
+    #[test]
+    fn test_session_store_is_loaded() {
+        let url = url::Url::parse("https://idas.uestc.edu.cn/authserver/login").unwrap();
+        let mut store = CookieStore::default();
+        store.insert_raw(&"CASTGC=TGT-1; Path=/authserver".parse().unwrap(), &url).unwrap();
+        let sessions = Arc::new(crate::MemorySessionStore::new());
+        sessions.save(&crate::SessionSnapshot::capture(&CookieStoreMutex::new(store))).unwrap();
+
+        let builder = UestcClientBuilder::new().session_store(sessions.clone());
+        let (resolved, saved_to) = builder.cookie_parts().unwrap();
+        assert!(resolved.lock().unwrap().get("idas.uestc.edu.cn", "/authserver", "CASTGC").is_some());
+        saved_to.unwrap().clear().unwrap();
+        assert!(sessions.load().unwrap().is_none());
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_stand_in_auth_server() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        // A stand-in IDAS that redirects `/authserver/login` to the personal center
+        let base_url = spawn_server(2, |request_line| {
+            if request_line.starts_with("GET /authserver/login") {
+                response(
+                    "302 Found",
+                    &[("Location", "/personalInfo/personCenter/index.html")],
+                    "",
+                )
+            } else {
+                response("200 OK", &[], "ok")
+            }
+        });
+
+        let client = test_client(&base_url).build_blocking().unwrap();
+        assert!(client.is_session_active());
+    }
+}
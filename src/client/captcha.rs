@@ -0,0 +1,74 @@
+//! Pluggable captcha solving for password login.
+//!
+//! IDAS starts requiring a captcha after a few failed attempts. When that
+//! happens the client downloads the image within the current cookie session
+//! and hands it to the configured solver before submitting the login form.
+
+use crate::core::log;
+use crate::{Result, UestcClientError};
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+
+/// Error type returned by captcha solvers
+pub type CaptchaSolveError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Solves a captcha image synchronously.
+///
+/// The image is the raw bytes returned by IDAS (usually JPEG). The returned
+/// string is submitted as the `captcha` form field.
+///
+/// Closures of the form `Fn(&[u8]) -> Result<String, CaptchaSolveError>`
+/// implement this trait.
+pub trait CaptchaSolver: Send + Sync {
+    fn solve(&self, image: &[u8]) -> std::result::Result<String, CaptchaSolveError>;
+}
+
+impl<F> CaptchaSolver for F
+where
+    F: Fn(&[u8]) -> std::result::Result<String, CaptchaSolveError> + Send + Sync,
+{
+    fn solve(&self, image: &[u8]) -> std::result::Result<String, CaptchaSolveError> {
+        self(image)
+    }
+}
+
+/// Boxed future returned by [`AsyncCaptchaSolver::solve`]
+#[cfg(feature = "async")]
+pub type CaptchaFuture<'a> =
+    Pin<Box<dyn Future<Output = std::result::Result<String, CaptchaSolveError>> + Send + 'a>>;
+
+/// Solves a captcha image asynchronously, e.g. by asking a remote service
+/// or a human through a chat bot.
+///
+/// Every [`CaptchaSolver`] is also an `AsyncCaptchaSolver`.
+#[cfg(feature = "async")]
+pub trait AsyncCaptchaSolver: Send + Sync {
+    fn solve<'a>(&'a self, image: &'a [u8]) -> CaptchaFuture<'a>;
+}
+
+#[cfg(feature = "async")]
+impl<T: CaptchaSolver> AsyncCaptchaSolver for T {
+    fn solve<'a>(&'a self, image: &'a [u8]) -> CaptchaFuture<'a> {
+        Box::pin(async move { CaptchaSolver::solve(self, image) })
+    }
+}
+
+/// The error for a login that needs a captcha when no solver is configured
+pub(crate) fn solver_missing(username: &str) -> UestcClientError {
+    log::error!("Captcha required for user {} but no solver is configured", username);
+    UestcClientError::CaptchaRequired {
+        username: Some(username.to_string()),
+    }
+}
+
+/// What a solver made of the captcha, as submitted with the login form
+pub(crate) fn answer(solved: std::result::Result<String, CaptchaSolveError>) -> Result<String> {
+    let answer = solved.map_err(|e| UestcClientError::CaptchaError {
+        message: format!("Captcha solver failed: {}", e),
+        source: Some(e),
+    })?;
+    log::debug!("Captcha solved (answer length: {})", answer.len());
+    Ok(answer.trim().to_string())
+}
@@ -0,0 +1,476 @@
+use super::builder::CookieKey;
+use super::session::{SessionSnapshot, SessionStore};
+use crate::core::cookie_jar::{self, PersistedCookie};
+use crate::core::cookie_cipher;
+use crate::core::log;
+use crate::{Result, UestcClientError};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Keeps the session in a JSON file, optionally encrypted.
+///
+/// Several processes may share one file. Reads and writes hold an advisory
+/// lock on a `.lock` file next to it, writes go to a temporary file that is
+/// renamed into place, and saves merge with what is on disk: cookies another
+/// process wrote in the meantime are kept unless this process changed the
+/// same cookie itself.
+#[derive(Debug)]
+pub struct FileSessionStore {
+    path: PathBuf,
+    key: Option<CookieKey>,
+    /// Keys derived from `key`, so a passphrase is stretched once per salt
+    keys: cookie_cipher::KeyCache,
+    /// Cookies as last loaded or saved by this process
+    baseline: Mutex<Vec<PersistedCookie>>,
+}
+
+impl FileSessionStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            key: None,
+            keys: cookie_cipher::KeyCache::new(),
+            baseline: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// A file encrypted with AES-256-GCM. A plaintext file at `path` is still
+    /// read and is encrypted on the next save.
+    pub fn encrypted(path: impl Into<PathBuf>, key: CookieKey) -> Self {
+        Self {
+            key: Some(key),
+            ..Self::new(path)
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    /// A file that cannot be parsed is moved aside to a `.corrupt` backup
+    /// and counts as no session. One that cannot be read or decrypted with
+    /// the configured key is an error and is left in place.
+    fn load(&self) -> Result<Option<SessionSnapshot>> {
+        let path = &self.path;
+        if !path.exists() {
+            log::debug!("cookie 文件不存在: {:?}", path);
+            return Ok(None);
+        }
+
+        log::debug!("发现 cookie 文件: {:?}", path);
+        let _lock = self.lock(false)?;
+        let Some(cookies) = self.read_existing()? else {
+            return Ok(None);
+        };
+        let cookies = cookie_jar::unexpired(cookies);
+        *self.baseline.lock().unwrap() = cookies.clone();
+        Ok(Some(SessionSnapshot { cookies }))
+    }
+
+    fn save(&self, snapshot: &SessionSnapshot) -> Result<()> {
+        let ours = snapshot.cookies.clone();
+
+        let _lock = self.lock(true)?;
+        let theirs = cookie_jar::unexpired(self.read_existing()?.unwrap_or_default());
+
+        let mut baseline = self.baseline.lock().unwrap();
+        let merged = cookie_jar::merge(&baseline, &ours, &theirs);
+        log::debug!("保存 {} 个 cookies 到: {:?}", merged.len(), self.path);
+
+        let json = cookie_jar::serialize_cookies(merged).map_err(|e| with_path(e, &self.path))?;
+        let content = match &self.key {
+            Some(key) => key
+                .with_source(|source| cookie_cipher::seal(json.as_bytes(), source, &self.keys))
+                .map_err(|e| with_path(e, &self.path))?,
+            None => json,
+        };
+        self.write_atomic(content.as_bytes())?;
+        *baseline = ours;
+
+        log::debug!("cookies 已成功保存");
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        let _lock = self.lock(true)?;
+        self.baseline.lock().unwrap().clear();
+        match fs::remove_file(&self.path) {
+            Ok(()) => {
+                log::debug!("已删除 cookie 文件: {:?}", self.path);
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(UestcClientError::CookieError {
+                operation: "delete".to_string(),
+                file_path: Some(self.path.display().to_string()),
+                message: format!("Failed to delete cookie file: {}", e),
+                source: Some(Box::new(e)),
+            }),
+        }
+    }
+}
+
+impl FileSessionStore {
+    /// The cookies in the file, `None` if there is no file or it could not
+    /// be parsed and was moved aside. Call with the lock held.
+    fn read_existing(&self) -> Result<Option<Vec<PersistedCookie>>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        match self.read_cookies() {
+            Ok(cookies) => Ok(Some(cookies)),
+            Err(e) if is_parse_error(&e) => {
+                self.move_aside(&e)?;
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Rename an unparseable cookie file to a `.corrupt` backup, replacing
+    /// any earlier one, so the next save does not overwrite it.
+    ///
+    /// Loads share the lock, so another process may have moved the file
+    /// first; that counts as done.
+    fn move_aside(&self, err: &UestcClientError) -> Result<()> {
+        let backup = sibling(&self.path, ".corrupt");
+        match fs::rename(&self.path, &backup) {
+            Ok(()) => {
+                log::warn!("cookie 文件无法解析，已移至 {:?}: {}", backup, err);
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                log::debug!("cookie 文件已被移走: {:?}", self.path);
+                Ok(())
+            }
+            Err(e) => Err(UestcClientError::CookieError {
+                operation: "backup".to_string(),
+                file_path: Some(self.path.display().to_string()),
+                message: format!("Failed to move unreadable cookie file aside: {}", e),
+                source: Some(Box::new(e)),
+            }),
+        }
+    }
+
+    /// Read and, if needed, decrypt the file, including expired cookies
+    fn read_cookies(&self) -> Result<Vec<PersistedCookie>> {
+        let path = &self.path;
+        let content = fs::read_to_string(path).map_err(|e| UestcClientError::CookieError {
+            operation: "read".to_string(),
+            file_path: Some(path.display().to_string()),
+            message: format!("Failed to read cookie file: {}", e),
+            source: Some(Box::new(e)),
+        })?;
+
+        let json = if cookie_cipher::is_encrypted(&content) {
+            let Some(key) = &self.key else {
+                return Err(UestcClientError::CookieError {
+                    operation: "decrypt".to_string(),
+                    file_path: Some(path.display().to_string()),
+                    message: "Cookie file is encrypted but no key is configured".to_string(),
+                    source: None,
+                });
+            };
+            let plaintext = key
+                .with_source(|source| cookie_cipher::open(&content, source, &self.keys))
+                .map_err(|e| with_path(e, path))?;
+            String::from_utf8(plaintext).map_err(|e| UestcClientError::CookieError {
+                operation: "decrypt".to_string(),
+                file_path: Some(path.display().to_string()),
+                message: "Decrypted cookie file is not valid UTF-8".to_string(),
+                source: Some(Box::new(e)),
+            })?
+        } else {
+            if self.key.is_some() {
+                log::info!("cookie 文件为明文格式，下次保存时将加密: {:?}", path);
+            }
+            content
+        };
+
+        cookie_jar::deserialize_cookies(&json).map_err(|e| with_path(e, path))
+    }
+
+    /// Take the advisory lock, released when the returned file is dropped
+    fn lock(&self, exclusive: bool) -> Result<File> {
+        let lock_path = sibling(&self.path, ".lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .and_then(|file| {
+                if exclusive {
+                    file.lock()?;
+                } else {
+                    file.lock_shared()?;
+                }
+                Ok(file)
+            });
+
+        file.map_err(|e| UestcClientError::CookieError {
+            operation: "lock".to_string(),
+            file_path: Some(lock_path.display().to_string()),
+            message: format!("Failed to lock cookie file: {}", e),
+            source: Some(Box::new(e)),
+        })
+    }
+
+    /// Write to a temporary file and rename it over the cookie file, so
+    /// readers never see a partially written file
+    fn write_atomic(&self, content: &[u8]) -> Result<()> {
+        let tmp_path = sibling(&self.path, &format!(".{}.tmp", std::process::id()));
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(content)?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&tmp_path, &self.path));
+
+        result.map_err(|e| {
+            let _ = fs::remove_file(&tmp_path);
+            UestcClientError::CookieError {
+                operation: "write".to_string(),
+                file_path: Some(self.path.display().to_string()),
+                message: format!("Failed to write cookie file: {}", e),
+                source: Some(Box::new(e)),
+            }
+        })
+    }
+}
+
+/// `path` with `suffix` appended to its file name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn is_parse_error(err: &UestcClientError) -> bool {
+    matches!(err, UestcClientError::CookieError { operation, .. } if operation == "deserialize")
+}
+
+/// Whether `err` means an encrypted cookie file could not be decrypted,
+/// for lack of the right key or of the key file
+pub(crate) fn is_decrypt_error(err: &UestcClientError) -> bool {
+    matches!(
+        err,
+        UestcClientError::CookieError { operation, .. } if operation == "decrypt" || operation == "read key"
+    )
+}
+
+/// Attach the cookie file path to an error from the cipher
+fn with_path(err: UestcClientError, path: &Path) -> UestcClientError {
+    match err {
+        UestcClientError::CookieError {
+            operation,
+            message,
+            source,
+            ..
+        } => UestcClientError::CookieError {
+            operation,
+            file_path: Some(path.display().to_string()),
+            message,
+            source,
+        },
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::session;
+    use cookie_store::CookieStore;
+    use reqwest_cookie_store::CookieStoreMutex;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("uestc_client_{}_{}", name, std::process::id()))
+    }
+
+    fn remove_cookie_file(path: &Path) {
+        fs::remove_file(path).unwrap();
+        fs::remove_file(sibling(path, ".lock")).unwrap();
+    }
+
+    fn store_with_tgc() -> CookieStoreMutex {
+        let mut store = CookieStore::default();
+        let url = url::Url::parse("https://idas.uestc.edu.cn/authserver/login").unwrap();
+        let cookie = "CASTGC=TGT-1; Path=/authserver; Secure; HttpOnly"
+            .parse::<cookie_store::RawCookie>()
+            .unwrap();
+        store.insert_raw(&cookie, &url).unwrap();
+        CookieStoreMutex::new(store)
+    }
+
+    fn save(file: &FileSessionStore, store: &CookieStoreMutex) {
+        file.save(&SessionSnapshot::capture(store)).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        let path = temp_path("cookies_encrypted.json");
+        let key_path = temp_path("cookies_encrypted.key");
+        fs::write(&key_path, [7u8; 32]).unwrap();
+        let file = FileSessionStore::encrypted(path.clone(), CookieKey::KeyFile(key_path.clone()));
+
+        save(&file, &store_with_tgc());
+        let content = fs::read_to_string(&path).unwrap();
+        let store = file.load();
+        remove_cookie_file(&path);
+        fs::remove_file(&key_path).unwrap();
+
+        assert!(!content.contains("TGT-1"));
+        let store = store.unwrap().unwrap().restore();
+        assert_eq!(store.iter_any().next().unwrap().value(), "TGT-1");
+    }
+
+    #[test]
+    fn test_encrypted_file_wrong_or_missing_key() {
+        let path = temp_path("cookies_wrong_key.json");
+        let right = temp_path("cookies_right.key");
+        let wrong = temp_path("cookies_wrong.key");
+        fs::write(&right, [1u8; 32]).unwrap();
+        fs::write(&wrong, [2u8; 32]).unwrap();
+
+        save(
+            &FileSessionStore::encrypted(path.clone(), CookieKey::KeyFile(right.clone())),
+            &store_with_tgc(),
+        );
+        let with_wrong_key =
+            FileSessionStore::encrypted(path.clone(), CookieKey::KeyFile(wrong.clone())).load();
+        let without_key = FileSessionStore::new(path.clone()).load();
+        remove_cookie_file(&path);
+        fs::remove_file(&right).unwrap();
+        fs::remove_file(&wrong).unwrap();
+
+        for result in [with_wrong_key, without_key] {
+            let err = result.err().unwrap();
+            assert!(is_decrypt_error(&err));
+            assert!(err.to_string().contains("decrypt"));
+        }
+    }
+
+    #[test]
+    fn test_plaintext_file_readable_with_key() {
+        let path = temp_path("cookies_legacy.json");
+        let key_path = temp_path("cookies_legacy.key");
+        fs::write(&key_path, [3u8; 32]).unwrap();
+        save(&FileSessionStore::new(path.clone()), &store_with_tgc());
+
+        let file = FileSessionStore::encrypted(path.clone(), CookieKey::KeyFile(key_path.clone()));
+        let store = session::restore_or_default(&file).unwrap();
+        save(&file, &store);
+        let migrated = fs::read_to_string(&path).unwrap();
+        remove_cookie_file(&path);
+        fs::remove_file(&key_path).unwrap();
+
+        assert_eq!(store.lock().unwrap().iter_any().count(), 1);
+        assert!(cookie_cipher::is_encrypted(&migrated));
+    }
+
+    #[test]
+    fn test_corrupt_file_moved_aside() {
+        let path = temp_path("cookies_corrupt.json");
+        let backup = sibling(&path, ".corrupt");
+        fs::write(&path, "{\"version\": 2, \"cook").unwrap();
+
+        let file = FileSessionStore::new(path.clone());
+        let loaded = file.load().unwrap();
+        let moved = !path.exists();
+        let kept = fs::read_to_string(&backup).unwrap();
+        save(&file, &store_with_tgc());
+        let reloaded = file.load().unwrap();
+        remove_cookie_file(&path);
+        fs::remove_file(&backup).unwrap();
+
+        assert!(loaded.is_none());
+        assert!(moved);
+        assert_eq!(kept, "{\"version\": 2, \"cook");
+        assert_eq!(reloaded.unwrap().cookies.len(), 1);
+    }
+
+    #[test]
+    fn test_corrupt_file_already_moved_aside() {
+        let path = temp_path("cookies_corrupt_race.json");
+        let backup = sibling(&path, ".corrupt");
+        fs::write(&path, "not json").unwrap();
+
+        // Another process loading the same file moves it first
+        let file = FileSessionStore::new(path.clone());
+        let err = file.read_cookies().unwrap_err();
+        fs::rename(&path, &backup).unwrap();
+        let result = file.move_aside(&err);
+        fs::remove_file(&backup).unwrap();
+
+        assert!(is_parse_error(&err));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_unreadable_file_starts_empty() {
+        // A directory in place of the file cannot be read
+        let path = temp_path("cookies_unreadable.json");
+        fs::create_dir_all(&path).unwrap();
+
+        let file = FileSessionStore::new(path.clone());
+        let loaded = file.load();
+        let store = session::restore_or_default(&file).unwrap();
+        fs::remove_dir(&path).unwrap();
+        fs::remove_file(sibling(&path, ".lock")).unwrap();
+
+        assert!(
+            matches!(loaded, Err(UestcClientError::CookieError { ref operation, .. }) if operation == "read")
+        );
+        assert_eq!(store.lock().unwrap().iter_any().count(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_saves_merge() {
+        let path = temp_path("cookies_shared.json");
+        save(&FileSessionStore::new(path.clone()), &store_with_tgc());
+
+        // Two processes start from the same file
+        let a = FileSessionStore::new(path.clone());
+        let b = FileSessionStore::new(path.clone());
+        let a_store = session::restore_or_default(&a).unwrap();
+        let b_store = session::restore_or_default(&b).unwrap();
+
+        // B logs in again, A only picks up an unrelated cookie
+        let url = url::Url::parse("https://idas.uestc.edu.cn/authserver/login").unwrap();
+        let fresh = "CASTGC=TGT-2; Path=/authserver; Secure; HttpOnly";
+        b_store
+            .lock()
+            .unwrap()
+            .insert_raw(&fresh.parse().unwrap(), &url)
+            .unwrap();
+        save(&b, &b_store);
+        a_store
+            .lock()
+            .unwrap()
+            .insert_raw(&"route=r1; Path=/".parse().unwrap(), &url)
+            .unwrap();
+        save(&a, &a_store);
+
+        let merged = FileSessionStore::new(path.clone()).load().unwrap().unwrap().restore();
+        let leftovers = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name.starts_with("uestc_client_cookies_shared") && name.ends_with(".tmp")
+            })
+            .count();
+        remove_cookie_file(&path);
+
+        assert_eq!(
+            merged.get("idas.uestc.edu.cn", "/authserver", "CASTGC").unwrap().value(),
+            "TGT-2"
+        );
+        assert!(merged.get("idas.uestc.edu.cn", "/", "route").is_some());
+        assert_eq!(leftovers, 0);
+    }
+}
@@ -0,0 +1,242 @@
+//! Credentials and where to get them from when the client has to log in
+//! without a caller at hand, e.g. after the session expired.
+
+use crate::core::secret::Secret;
+use crate::{Result, UestcClientError};
+use serde::Deserialize;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+pub const DEFAULT_USERNAME_VAR: &str = "UESTC_USERNAME";
+pub const DEFAULT_PASSWORD_VAR: &str = "UESTC_PASSWORD";
+
+/// A username and password for IDAS password login
+#[derive(Debug, Clone, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret,
+}
+
+impl Credentials {
+    pub fn new(username: impl Into<String>, password: impl Into<Secret>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+/// Source of credentials for logins the caller does not drive directly.
+///
+/// Called every time credentials are needed, so implementations may pick
+/// up rotated passwords. Closures returning `Result<Credentials>` implement
+/// this trait.
+pub trait CredentialProvider: Send + Sync {
+    fn credentials(&self) -> Result<Credentials>;
+}
+
+impl CredentialProvider for Credentials {
+    fn credentials(&self) -> Result<Credentials> {
+        Ok(self.clone())
+    }
+}
+
+impl<F> CredentialProvider for F
+where
+    F: Fn() -> Result<Credentials> + Send + Sync,
+{
+    fn credentials(&self) -> Result<Credentials> {
+        self()
+    }
+}
+
+/// Reads credentials from environment variables,
+/// `UESTC_USERNAME` and `UESTC_PASSWORD` by default
+#[derive(Debug, Clone)]
+pub struct EnvCredentials {
+    username_var: String,
+    password_var: String,
+}
+
+impl Default for EnvCredentials {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EnvCredentials {
+    pub fn new() -> Self {
+        Self::with_vars(DEFAULT_USERNAME_VAR, DEFAULT_PASSWORD_VAR)
+    }
+
+    pub fn with_vars(username_var: impl Into<String>, password_var: impl Into<String>) -> Self {
+        Self {
+            username_var: username_var.into(),
+            password_var: password_var.into(),
+        }
+    }
+
+    fn var(name: &str) -> Result<String> {
+        std::env::var(name).map_err(|e| UestcClientError::CredentialError {
+            message: format!("Environment variable {} is not usable", name),
+            source: Some(Box::new(e)),
+        })
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn credentials(&self) -> Result<Credentials> {
+        Ok(Credentials::new(
+            Self::var(&self.username_var)?,
+            Self::var(&self.password_var)?,
+        ))
+    }
+}
+
+/// Reads credentials from a JSON file of the form
+/// `{"username": "...", "password": "..."}`.
+///
+/// The file is read on every call. Keep it readable only by the account
+/// running the job.
+#[derive(Debug, Clone)]
+pub struct FileCredentials {
+    path: PathBuf,
+}
+
+impl FileCredentials {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl CredentialProvider for FileCredentials {
+    fn credentials(&self) -> Result<Credentials> {
+        let content =
+            std::fs::read_to_string(&self.path).map(Zeroizing::new).map_err(|e| UestcClientError::CredentialError {
+                message: format!("Failed to read credential file {}", self.path.display()),
+                source: Some(Box::new(e)),
+            })?;
+        serde_json::from_str(&content).map_err(|e| UestcClientError::CredentialError {
+            message: format!("Invalid credential file {}", self.path.display()),
+            source: Some(Box::new(e)),
+        })
+    }
+}
+
+/// Asks on the terminal, reading the password without echo.
+///
+/// Only suitable for interactive use, a job without a terminal fails
+/// with [`UestcClientError::CredentialError`].
+#[derive(Debug, Clone, Default)]
+pub struct PromptCredentials {
+    username: Option<String>,
+}
+
+impl PromptCredentials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only prompt for the password of this account
+    pub fn with_username(username: impl Into<String>) -> Self {
+        Self {
+            username: Some(username.into()),
+        }
+    }
+
+    fn prompt_error(e: io::Error) -> UestcClientError {
+        UestcClientError::CredentialError {
+            message: "Failed to read credentials from terminal".to_string(),
+            source: Some(Box::new(e)),
+        }
+    }
+}
+
+impl CredentialProvider for PromptCredentials {
+    fn credentials(&self) -> Result<Credentials> {
+        let username = match &self.username {
+            Some(username) => {
+                eprintln!("统一身份认证登录: {}", username);
+                username.clone()
+            }
+            None => {
+                eprint!("学号/工号: ");
+                io::stderr().flush().map_err(Self::prompt_error)?;
+                let mut line = String::new();
+                io::stdin()
+                    .lock()
+                    .read_line(&mut line)
+                    .map_err(Self::prompt_error)?;
+                line.trim().to_string()
+            }
+        };
+
+        let password = rpassword::prompt_password("密码: ").map_err(Self::prompt_error)?;
+        Ok(Credentials::new(username, password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_closure_provider() {
+        let provider = || Ok(Credentials::new("2023000000", "secret"));
+        let credentials = provider.credentials().unwrap();
+        assert_eq!(credentials.username, "2023000000");
+        assert_eq!(credentials.password.expose(), "secret");
+    }
+
+    #[test]
+    fn test_env_credentials_missing_var() {
+        let provider = EnvCredentials::with_vars(
+            "UESTC_CLIENT_TEST_MISSING_USER",
+            "UESTC_CLIENT_TEST_MISSING_PASSWORD",
+        );
+        let err = provider.credentials().unwrap_err();
+        assert!(matches!(err, UestcClientError::CredentialError { .. }));
+        assert!(err.to_string().contains("UESTC_CLIENT_TEST_MISSING_USER"));
+    }
+
+    #[test]
+    fn test_file_credentials() {
+        let path = std::env::temp_dir().join(format!(
+            "uestc_client_credentials_{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, r#"{"username": "2023000000", "password": "secret"}"#).unwrap();
+        let credentials = FileCredentials::new(&path).credentials().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(credentials.username, "2023000000");
+        assert_eq!(credentials.password.expose(), "secret");
+    }
+
+    #[test]
+    fn test_file_credentials_invalid() {
+        let path = std::env::temp_dir().join(format!(
+            "uestc_client_credentials_invalid_{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, r#"{"username": "2023000000"}"#).unwrap();
+        let result = FileCredentials::new(&path).credentials();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(UestcClientError::CredentialError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_debug_hides_password() {
+        let credentials = Credentials::new("2023000000", "secret");
+        assert!(!format!("{:?}", credentials).contains("secret"));
+    }
+}
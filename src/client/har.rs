@@ -0,0 +1,395 @@
+//! Recording the client's traffic as an HTTP Archive for bug reports.
+
+use super::message::{ClientRequest, ClientResponse};
+use crate::core::har::{Entry, Exchange, Har};
+use crate::core::log;
+use crate::{Result, UestcClientError};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::{Method, ResponseBuilderExt, StatusCode, Version};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use url::Url;
+
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Headers not sent on to another host when following a redirect
+const CROSS_HOST_HEADERS: [header::HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::PROXY_AUTHORIZATION,
+    header::WWW_AUTHENTICATE,
+];
+
+/// Records the requests the client sends and their responses in HTTP
+/// Archive (HAR 1.2) format, e.g. to attach to a report of a broken login.
+///
+/// While recording, the client follows redirects itself one hop at a time,
+/// so every hop of a CAS redirect chain gets an entry of its own. Headers,
+/// cookies and the first `max_body_size` bytes of each response body are
+/// kept, with tickets, codes, passwords and cookie values masked. Entries
+/// are kept in memory and written to `path` by [`save`](Self::save), and
+/// when the recorder and the clients using it are dropped.
+///
+/// Everything the client sends itself is recorded, as are requests passed
+/// to `send_authenticated`. Requests sent directly with the `reqwest`
+/// builders returned by `get`, `post` and so on are not.
+#[derive(Debug, Clone)]
+pub struct HarRecorder {
+    max_body_size: usize,
+    archive: Arc<Archive>,
+}
+
+/// The entries and where they go, shared by all clones of a recorder
+#[derive(Debug)]
+struct Archive {
+    path: PathBuf,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    recorded: Vec<Entry>,
+    /// Whether there are entries `path` does not have yet
+    unsaved: bool,
+}
+
+impl HarRecorder {
+    /// Write the archive to `path`. Keeps up to 64 KiB of each response body.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            archive: Arc::new(Archive {
+                path: path.into(),
+                entries: Mutex::new(Entries::default()),
+            }),
+        }
+    }
+
+    /// Keep at most this many bytes of each response body
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.max_body_size = bytes;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.archive.path
+    }
+
+    /// Number of requests recorded so far
+    pub fn len(&self) -> usize {
+        self.archive.entries.lock().unwrap().recorded.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the archive to `path`, replacing the file
+    pub fn save(&self) -> Result<()> {
+        self.archive.save()
+    }
+
+    pub(crate) fn record(&self, exchange: &Exchange<'_>) {
+        let mut entries = self.archive.entries.lock().unwrap();
+        entries.recorded.push(Entry::new(exchange, self.max_body_size));
+        entries.unsaved = true;
+    }
+}
+
+impl Archive {
+    fn save(&self) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        Self::write(&self.path, &entries.recorded)?;
+        entries.unsaved = false;
+        Ok(())
+    }
+
+    fn write(path: &Path, entries: &[Entry]) -> Result<()> {
+        let io_error = |message: String, source: Box<dyn std::error::Error + Send + Sync>| {
+            UestcClientError::IoError {
+                operation: "save HAR".to_string(),
+                file_path: Some(path.display().to_string()),
+                message,
+                source: Some(source),
+            }
+        };
+
+        let json = serde_json::to_string_pretty(&Har::new(entries))
+            .map_err(|e| io_error(format!("Failed to serialize HAR: {}", e), Box::new(e)))?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .map_err(|e| io_error(format!("Failed to create directory: {}", e), Box::new(e)))?;
+        }
+        fs::write(path, json)
+            .map_err(|e| io_error(format!("Failed to write HAR file: {}", e), Box::new(e)))
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        if !self.entries.get_mut().map(|entries| entries.unsaved).unwrap_or(false) {
+            return;
+        }
+        if let Err(e) = self.save() {
+            log::warn!("Failed to write HAR file: {}", e);
+        }
+    }
+}
+
+/// A request being sent while recording, captured before it goes out
+#[derive(Debug)]
+pub(crate) struct PendingExchange {
+    started: SystemTime,
+    timer: Instant,
+    method: Method,
+    url: Url,
+    headers: HeaderMap,
+    body: Option<Vec<u8>>,
+    cookies: Vec<String>,
+}
+
+impl PendingExchange {
+    /// `headers` should include those the client adds to every request,
+    /// `cookies` the names of the cookies sent
+    pub(crate) fn new(request: &impl ClientRequest, headers: HeaderMap, cookies: Vec<String>) -> Self {
+        Self {
+            started: SystemTime::now(),
+            timer: Instant::now(),
+            method: request.method().clone(),
+            url: request.url().clone(),
+            headers,
+            body: request.body_bytes().map(<[u8]>::to_vec),
+            cookies,
+        }
+    }
+
+    /// Record the exchange once the response has been read. The response is
+    /// handed back rebuilt around the body read for the recording.
+    pub(crate) fn finish<B: AsRef<[u8]>>(self, har: &HarRecorder, head: ResponseHead, body: B) -> http::Response<B> {
+        let ResponseHead {
+            status,
+            version,
+            headers,
+            url: final_url,
+        } = head;
+        har.record(&Exchange {
+            started: self.started,
+            time: self.timer.elapsed(),
+            method: self.method.as_str(),
+            url: &self.url,
+            version,
+            request_headers: &self.headers,
+            request_cookies: self.cookies,
+            request_body: self.body.as_deref(),
+            status,
+            response_headers: &headers,
+            response_body: body.as_ref(),
+        });
+
+        let mut rebuilt = http::Response::builder().status(status).version(version).url(final_url);
+        if let Some(rebuilt_headers) = rebuilt.headers_mut() {
+            *rebuilt_headers = headers;
+        }
+        rebuilt.body(body).expect("response parts are valid")
+    }
+}
+
+/// A response before its body is read
+#[derive(Debug)]
+pub(crate) struct ResponseHead {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    url: Url,
+}
+
+impl ResponseHead {
+    pub(crate) fn of(resp: &impl ClientResponse) -> Self {
+        Self {
+            status: resp.status(),
+            version: resp.version(),
+            headers: resp.headers().clone(),
+            url: resp.url().clone(),
+        }
+    }
+}
+
+/// Follows redirects one hop at a time while recording, so that each hop
+/// gets an entry
+pub(crate) struct Hops<R> {
+    /// Copy of the request in flight, to build the next hop from
+    sent: Option<R>,
+    /// Where the chain started, for the error once it runs too long
+    start: Url,
+    redirects: usize,
+    limit: usize,
+}
+
+impl<R: ClientRequest> Hops<R> {
+    /// Start with `request`, about to be sent. A `limit` of 0 follows no
+    /// redirects.
+    pub(crate) fn new(request: &R, limit: usize) -> Self {
+        Self {
+            sent: request.try_clone().filter(|_| limit > 0),
+            start: request.url().clone(),
+            redirects: 0,
+            limit,
+        }
+    }
+
+    /// The request for the next hop if `resp` redirects the one sent last.
+    ///
+    /// Fails with [`UestcClientError::TooManyRedirects`] if that would go
+    /// past the limit.
+    pub(crate) fn follow(&mut self, resp: &impl ClientResponse) -> Result<Option<R>> {
+        let Some(next) = self.sent.take().and_then(|sent| sent.redirected(resp.status(), resp.headers()))
+        else {
+            return Ok(None);
+        };
+        if self.redirects >= self.limit {
+            return Err(UestcClientError::TooManyRedirects {
+                url: self.start.to_string(),
+                limit: self.limit,
+            });
+        }
+        self.redirects += 1;
+        self.sent = next.try_clone();
+        Ok(Some(next))
+    }
+}
+
+/// Turn a request into the one for the next hop if its response redirects,
+/// as `reqwest` does when following redirects itself.
+///
+/// Returns `None` if the response is not a redirect, otherwise whether the
+/// request body must be dropped.
+pub(crate) fn follow_redirect(
+    status: StatusCode,
+    response_headers: &HeaderMap,
+    method: &mut Method,
+    url: &mut Url,
+    headers: &mut HeaderMap,
+) -> Option<bool> {
+    let drop_body = match status {
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER => true,
+        StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => false,
+        _ => return None,
+    };
+    let next = crate::core::cas::redirect_target(url, response_headers)?;
+
+    if drop_body {
+        for name in [
+            header::TRANSFER_ENCODING,
+            header::CONTENT_ENCODING,
+            header::CONTENT_TYPE,
+            header::CONTENT_LENGTH,
+        ] {
+            headers.remove(name);
+        }
+        if *method != Method::GET && *method != Method::HEAD {
+            *method = Method::GET;
+        }
+    }
+    if next.host_str() != url.host_str() || next.port_or_known_default() != url.port_or_known_default() {
+        for name in &CROSS_HOST_HEADERS {
+            headers.remove(name);
+        }
+    }
+    // No referer when going from HTTPS to HTTP
+    if !(url.scheme() == "https" && next.scheme() == "http") {
+        let mut referer = url.clone();
+        let _ = referer.set_username("");
+        let _ = referer.set_password(None);
+        referer.set_fragment(None);
+        if let Ok(value) = HeaderValue::from_str(referer.as_str()) {
+            headers.insert(header::REFERER, value);
+        }
+    }
+
+    *url = next;
+    Some(drop_body)
+}
+
+/// Names of the cookies the session sends to `url`
+pub(crate) fn request_cookies(cookie_store: &reqwest_cookie_store::CookieStoreMutex, url: &Url) -> Vec<String> {
+    cookie_store
+        .lock()
+        .unwrap()
+        .get_request_values(url)
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_written_on_save_and_drop() {
+        let path = std::env::temp_dir().join(format!("uestc_client_recorder_{}.har", std::process::id()));
+        let url = Url::parse("https://idas.uestc.edu.cn/authserver/login").unwrap();
+        let headers = HeaderMap::new();
+        let exchange = Exchange {
+            started: std::time::SystemTime::now(),
+            time: std::time::Duration::from_millis(5),
+            method: "GET",
+            url: &url,
+            version: reqwest::Version::HTTP_11,
+            request_headers: &headers,
+            request_cookies: Vec::new(),
+            request_body: None,
+            status: StatusCode::OK,
+            response_headers: &headers,
+            response_body: b"",
+        };
+        let entries = |path: &Path| {
+            let har: serde_json::Value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+            har["log"]["entries"].as_array().unwrap().len()
+        };
+
+        let recorder = HarRecorder::new(&path);
+        let clone = recorder.clone();
+        recorder.record(&exchange);
+        assert!(!path.exists());
+        recorder.save().unwrap();
+        assert_eq!(entries(&path), 1);
+
+        clone.record(&exchange);
+        drop(recorder);
+        assert_eq!(entries(&path), 1);
+        drop(clone);
+        assert_eq!(entries(&path), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_follow_redirect() {
+        let mut method = Method::POST;
+        let mut url = Url::parse("https://idas.uestc.edu.cn/authserver/login").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded"));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic abc"));
+        let mut location = HeaderMap::new();
+        location.insert(
+            header::LOCATION,
+            HeaderValue::from_static("https://eportal.uestc.edu.cn/?ticket=ST-1"),
+        );
+
+        assert_eq!(
+            follow_redirect(StatusCode::FOUND, &location, &mut method, &mut url, &mut headers),
+            Some(true)
+        );
+        assert_eq!(method, Method::GET);
+        assert_eq!(url.as_str(), "https://eportal.uestc.edu.cn/?ticket=ST-1");
+        assert!(headers.get(header::CONTENT_TYPE).is_none());
+        assert!(headers.get(header::AUTHORIZATION).is_none());
+        assert_eq!(headers[header::REFERER], "https://idas.uestc.edu.cn/authserver/login");
+
+        assert_eq!(
+            follow_redirect(StatusCode::OK, &location, &mut method, &mut url, &mut headers),
+            None
+        );
+    }
+}
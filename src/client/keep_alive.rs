@@ -0,0 +1,256 @@
+//! Periodic session checks that keep the CAS session from idling out.
+
+use super::state::ClientState;
+use crate::core::flow::SessionStatus;
+use crate::core::log;
+use crate::{Result, UestcClientError};
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use crate::core::wechat_login::CancellationToken;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How often the keep-alive checks the session and what it does when it lapses
+#[derive(Debug, Clone)]
+pub struct KeepAliveConfig {
+    pub(crate) interval: Duration,
+    pub(crate) relogin: bool,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeepAliveConfig {
+    /// Check every 10 minutes and log in again when the session has lapsed
+    pub fn new() -> Self {
+        Self {
+            interval: DEFAULT_INTERVAL,
+            relogin: true,
+        }
+    }
+
+    /// Time between two session checks
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Whether to log in through the client's credential provider when the
+    /// session has lapsed. Without a provider, lapses are only reported.
+    pub fn relogin(mut self, relogin: bool) -> Self {
+        self.relogin = relogin;
+        self
+    }
+}
+
+/// Reported by the keep-alive when the session state changes
+#[derive(Debug)]
+pub enum KeepAliveEvent {
+    /// The session is active, either at the first check or again after a lapse
+    SessionActive,
+    /// The session is no longer active
+    SessionExpired,
+    /// The session lapsed and the client logged in again
+    Relogged,
+    /// Logging in again failed, the next check will retry
+    ReloginFailed(UestcClientError),
+    /// The session could not be checked, e.g. on a network error. The state
+    /// is left as it was and no re-login is attempted.
+    CheckFailed(UestcClientError),
+}
+
+/// Turn the result of a session check into an event if the state changed
+pub(crate) fn state_change(last_active: &mut Option<bool>, active: bool) -> Option<KeepAliveEvent> {
+    if *last_active == Some(active) {
+        return None;
+    }
+    *last_active = Some(active);
+    Some(if active {
+        KeepAliveEvent::SessionActive
+    } else {
+        KeepAliveEvent::SessionExpired
+    })
+}
+
+/// What a keep-alive has reported so far, and what it does after each check
+#[derive(Debug, Default)]
+pub(crate) struct KeepAliveState {
+    last_active: Option<bool>,
+}
+
+impl KeepAliveState {
+    /// Save the cookies after `client`'s session checked out and report the
+    /// outcome, see [`checked`](Self::checked)
+    pub(crate) fn observe(
+        &mut self,
+        client: &ClientState,
+        config: &KeepAliveConfig,
+        status: SessionStatus,
+        on_event: &mut impl FnMut(KeepAliveEvent),
+    ) -> bool {
+        if status.is_active()
+            && let Err(e) = client.save_cookie_store()
+        {
+            log::warn!("Keep-alive: failed to save cookies: {}", e);
+        }
+        let relogin = config.relogin && client.credential_provider.is_some();
+        self.checked(status, relogin, on_event)
+    }
+
+    /// Report the outcome of a session check. Returns whether to log in
+    /// again, which `relogin` allows.
+    pub(crate) fn checked(
+        &mut self,
+        status: SessionStatus,
+        relogin: bool,
+        on_event: &mut impl FnMut(KeepAliveEvent),
+    ) -> bool {
+        let active = match status {
+            SessionStatus::Active => true,
+            SessionStatus::LoggedOut => false,
+            SessionStatus::Undetermined(e) => {
+                log::warn!("Keep-alive: session check failed: {}", e);
+                on_event(KeepAliveEvent::CheckFailed(e));
+                return false;
+            }
+        };
+        if let Some(event) = state_change(&mut self.last_active, active) {
+            on_event(event);
+        }
+        if active || !relogin {
+            return false;
+        }
+
+        log::info!("Keep-alive: session expired, logging in again");
+        true
+    }
+
+    /// Report the outcome of logging in again
+    pub(crate) fn relogged(&mut self, result: Result<()>, on_event: &mut impl FnMut(KeepAliveEvent)) {
+        match result {
+            Ok(()) => {
+                self.last_active = Some(true);
+                on_event(KeepAliveEvent::Relogged);
+            }
+            Err(e) => {
+                log::warn!("Keep-alive re-login failed: {}", e);
+                on_event(KeepAliveEvent::ReloginFailed(e));
+            }
+        }
+    }
+}
+
+/// Controls a keep-alive task started by
+/// [`UestcClient::spawn_keep_alive`](crate::UestcClient::spawn_keep_alive).
+///
+/// Dropping the handle stops the task at its next check.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct KeepAliveHandle {
+    pub(crate) token: CancellationToken,
+    pub(crate) task: tokio::task::JoinHandle<()>,
+}
+
+#[cfg(feature = "async")]
+impl KeepAliveHandle {
+    /// Stop the task and wait for a check in progress to finish
+    pub async fn stop(mut self) {
+        self.token.cancel();
+        let _ = (&mut self.task).await;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for KeepAliveHandle {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+/// Controls a keep-alive thread started by
+/// [`UestcBlockingClient::spawn_keep_alive`](crate::UestcBlockingClient::spawn_keep_alive).
+///
+/// Dropping the handle stops the thread at its next check without waiting for it.
+#[cfg(feature = "blocking")]
+#[derive(Debug)]
+pub struct BlockingKeepAliveHandle {
+    pub(crate) stop: std::sync::mpsc::Sender<()>,
+    pub(crate) thread: std::thread::JoinHandle<()>,
+}
+
+#[cfg(feature = "blocking")]
+impl BlockingKeepAliveHandle {
+    /// Stop the thread and wait for a check in progress to finish
+    pub fn stop(self) {
+        drop(self.stop);
+        let _ = self.thread.join();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_change() {
+        let mut last_active = None;
+        assert!(matches!(
+            state_change(&mut last_active, true),
+            Some(KeepAliveEvent::SessionActive)
+        ));
+        assert!(state_change(&mut last_active, true).is_none());
+        assert!(matches!(
+            state_change(&mut last_active, false),
+            Some(KeepAliveEvent::SessionExpired)
+        ));
+        assert!(state_change(&mut last_active, false).is_none());
+    }
+
+    #[test]
+    fn test_relogin_only_when_lapsed() {
+        let mut state = KeepAliveState::default();
+        let mut events = Vec::new();
+        let mut on_event = |event| events.push(event);
+
+        assert!(!state.checked(SessionStatus::Active, true, &mut on_event));
+        assert!(!state.checked(SessionStatus::LoggedOut, false, &mut on_event));
+        assert!(state.checked(SessionStatus::LoggedOut, true, &mut on_event));
+        state.relogged(Ok(()), &mut on_event);
+        assert!(!state.checked(SessionStatus::Active, true, &mut on_event));
+        let failed = SessionStatus::Undetermined(UestcClientError::SessionExpired);
+        assert!(!state.checked(failed, true, &mut on_event));
+
+        assert!(matches!(
+            events.as_slice(),
+            [
+                KeepAliveEvent::SessionActive,
+                KeepAliveEvent::SessionExpired,
+                KeepAliveEvent::Relogged,
+                KeepAliveEvent::CheckFailed(_),
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let config = KeepAliveConfig::new();
+        assert_eq!(config.interval, Duration::from_secs(600));
+        assert!(config.relogin);
+
+        let config = config.interval(Duration::from_secs(60)).relogin(false);
+        assert_eq!(config.interval, Duration::from_secs(60));
+        assert!(!config.relogin);
+    }
+}
@@ -0,0 +1,391 @@
+//! Local guard against locking an account with repeated failed logins.
+
+use crate::core::log;
+use crate::{LoginFailureReason, Result, UestcClientError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const GUARD_FILE: &str = "login_guard.json";
+
+/// Refuses password logins that would likely fail again, before IDAS locks
+/// the account or starts asking for captchas.
+///
+/// Failed attempts are counted per username in `login_guard.json` inside a
+/// directory, usually the one holding the session files, so processes that
+/// share it share the counts. After `max_failures` failures in a row, attempts
+/// are refused for `cooldown`; then one attempt is let through, and another
+/// failure starts a new cooldown. Attempts for one username are also kept
+/// `min_interval` apart. A refused attempt fails with
+/// [`UestcClientError::LoginThrottled`] right after the login page is
+/// fetched, before the captcha check or the password is sent, and a
+/// successful login resets the count.
+#[derive(Debug, Clone)]
+pub struct LoginGuard {
+    path: PathBuf,
+    max_failures: u32,
+    cooldown: Duration,
+    min_interval: Duration,
+}
+
+/// What the guard remembers about one username, times in Unix milliseconds
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct AttemptRecord {
+    failures: u32,
+    last_failure: Option<u64>,
+    last_attempt: Option<u64>,
+}
+
+type Records = HashMap<String, AttemptRecord>;
+
+impl LoginGuard {
+    /// Keep the counts in `dir`, created if missing. Defaults to 3 failures,
+    /// a 30 minute cooldown and 10 seconds between attempts.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            path: dir.into().join(GUARD_FILE),
+            max_failures: 3,
+            cooldown: Duration::from_secs(30 * 60),
+            min_interval: Duration::from_secs(10),
+        }
+    }
+
+    /// Refuse attempts after this many failures in a row
+    pub fn max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures;
+        self
+    }
+
+    /// How long attempts are refused once `max_failures` is reached
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Minimum time between two attempts for the same username
+    pub fn min_interval(mut self, interval: Duration) -> Self {
+        self.min_interval = interval;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Failed attempts in a row recorded for `username`
+    pub fn failures(&self, username: &str) -> Result<u32> {
+        Ok(self.read()?.get(username).map_or(0, |r| r.failures))
+    }
+
+    /// Forget the failures of `username`, e.g. after fixing its password
+    pub fn reset(&self, username: &str) -> Result<()> {
+        self.update(|records| {
+            records.remove(username);
+            Ok(())
+        })
+    }
+
+    /// Record an attempt for `username`, or refuse it
+    pub(crate) fn begin_attempt(&self, username: &str) -> Result<()> {
+        self.update(|records| {
+            let now = now_millis();
+            let record = records.entry(username.to_string()).or_default();
+            if let Some((retry_after, message)) = self.refusal(username, record, now) {
+                log::warn!("{}", message);
+                return Err(UestcClientError::LoginThrottled {
+                    message,
+                    username: username.to_string(),
+                    retry_after,
+                });
+            }
+            record.last_attempt = Some(now);
+            Ok(())
+        })
+    }
+
+    /// Count a failed attempt for `username` or reset the count after a
+    /// success. Errors that say nothing about the password are ignored.
+    pub(crate) fn record_outcome(&self, username: &str, outcome: &Result<()>) {
+        let result = self.update(|records| {
+            let record = records.entry(username.to_string()).or_default();
+            match outcome {
+                Ok(()) => {
+                    record.failures = 0;
+                    record.last_failure = None;
+                }
+                Err(UestcClientError::LoginFailed { reason, .. }) => match reason {
+                    LoginFailureReason::InvalidCredentials => {
+                        record.failures += 1;
+                        record.last_failure = Some(now_millis());
+                    }
+                    // IDAS locked the account already, wait out a full cooldown
+                    LoginFailureReason::AccountLocked { .. } => {
+                        record.failures = (record.failures + 1).max(self.max_failures);
+                        record.last_failure = Some(now_millis());
+                    }
+                    _ => {}
+                },
+                Err(_) => {}
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            log::warn!("Failed to update login guard: {}", e);
+        }
+    }
+
+    /// Why an attempt at `now` must wait, and for how long
+    fn refusal(
+        &self,
+        username: &str,
+        record: &AttemptRecord,
+        now: u64,
+    ) -> Option<(Duration, String)> {
+        if record.failures >= self.max_failures
+            && let Some(last_failure) = record.last_failure
+        {
+            let until = last_failure.saturating_add(self.cooldown.as_millis() as u64);
+            if now < until {
+                let wait = Duration::from_millis(until - now);
+                return Some((
+                    wait,
+                    format!(
+                        "{} failed logins in a row for {}, next attempt allowed in {:?}",
+                        record.failures,
+                        username,
+                        Duration::from_secs(wait.as_secs())
+                    ),
+                ));
+            }
+        }
+
+        if let Some(last_attempt) = record.last_attempt {
+            let until = last_attempt.saturating_add(self.min_interval.as_millis() as u64);
+            if now < until {
+                return Some((
+                    Duration::from_millis(until - now),
+                    format!(
+                        "Logins for {} must be at least {:?} apart",
+                        username, self.min_interval
+                    ),
+                ));
+            }
+        }
+        None
+    }
+
+    /// Read, change and write back the records under an exclusive advisory
+    /// lock on a `.lock` file next to them. Nothing is written if `f` fails.
+    fn update<T>(&self, f: impl FnOnce(&mut Records) -> Result<T>) -> Result<T> {
+        let io_error = |e: std::io::Error| UestcClientError::IoError {
+            operation: "update login guard".to_string(),
+            file_path: Some(self.path.display().to_string()),
+            message: format!("Failed to update login guard file: {}", e),
+            source: Some(Box::new(e)),
+        };
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        let _lock = lock(&sibling(&self.path, ".lock")).map_err(io_error)?;
+
+        let mut records = self.read()?;
+        let output = f(&mut records)?;
+
+        let json =
+            serde_json::to_string_pretty(&records).map_err(|e| UestcClientError::IoError {
+                operation: "update login guard".to_string(),
+                file_path: Some(self.path.display().to_string()),
+                message: format!("Failed to serialize login guard: {}", e),
+                source: Some(Box::new(e)),
+            })?;
+        self.write_atomic(json.as_bytes()).map_err(io_error)?;
+        Ok(output)
+    }
+
+    /// The records on disk, none if there is no file yet. Writes replace the
+    /// file in one rename, so reading needs no lock.
+    fn read(&self) -> Result<Records> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Records::new()),
+            Err(e) => {
+                return Err(UestcClientError::IoError {
+                    operation: "read login guard".to_string(),
+                    file_path: Some(self.path.display().to_string()),
+                    message: format!("Failed to read login guard file: {}", e),
+                    source: Some(Box::new(e)),
+                });
+            }
+        };
+        if content.trim().is_empty() {
+            return Ok(Records::new());
+        }
+        Ok(serde_json::from_str(&content).unwrap_or_else(|e| {
+            log::warn!(
+                "Ignoring unreadable login guard file {:?}: {}",
+                self.path,
+                e
+            );
+            Records::new()
+        }))
+    }
+
+    /// Write to a temporary file and rename it over the records, so that a
+    /// crash never leaves a truncated file behind
+    fn write_atomic(&self, content: &[u8]) -> std::io::Result<()> {
+        let tmp_path = sibling(&self.path, &format!(".{}.tmp", std::process::id()));
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(content)?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&tmp_path, &self.path));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+}
+
+/// Take an exclusive lock on `path`, created if missing, released when the
+/// file is dropped
+fn lock(path: &Path) -> std::io::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// `path` with `suffix` appended to its file name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("uestc_client_{}_{}", name, std::process::id()))
+    }
+
+    fn rejected() -> Result<()> {
+        Err(UestcClientError::LoginFailed {
+            message: "您提供的用户名或者密码有误".to_string(),
+            username: Some("2022010901001".to_string()),
+            reason: LoginFailureReason::InvalidCredentials,
+        })
+    }
+
+    #[test]
+    fn test_refusal() {
+        let guard = LoginGuard::new("sessions")
+            .max_failures(2)
+            .cooldown(Duration::from_secs(60))
+            .min_interval(Duration::from_secs(5));
+        let mut record = AttemptRecord {
+            failures: 1,
+            last_failure: Some(100_000),
+            last_attempt: Some(100_000),
+        };
+
+        assert!(guard.refusal("a", &record, 104_000).is_some());
+        assert!(guard.refusal("a", &record, 105_000).is_none());
+
+        record.failures = 2;
+        let (wait, _) = guard.refusal("a", &record, 130_000).unwrap();
+        assert_eq!(wait, Duration::from_secs(30));
+        assert!(guard.refusal("a", &record, 160_000).is_none());
+    }
+
+    #[test]
+    fn test_failures_are_shared_through_the_file() {
+        let dir = temp_dir("login_guard");
+        let _ = fs::remove_dir_all(&dir);
+        let guard = LoginGuard::new(&dir)
+            .max_failures(2)
+            .min_interval(Duration::ZERO);
+
+        for _ in 0..2 {
+            guard.begin_attempt("2022010901001").unwrap();
+            guard.record_outcome("2022010901001", &rejected());
+        }
+
+        // Another process using the same directory
+        let other = LoginGuard::new(&dir).max_failures(2);
+        assert_eq!(other.failures("2022010901001").unwrap(), 2);
+        assert!(matches!(
+            other.begin_attempt("2022010901001"),
+            Err(UestcClientError::LoginThrottled { retry_after, .. }) if retry_after > Duration::ZERO
+        ));
+        // Other accounts are not affected
+        other.begin_attempt("2022010901002").unwrap();
+        // Only the password's fault counts
+        other.record_outcome("2022010901002", &Err(UestcClientError::SessionExpired));
+        assert_eq!(other.failures("2022010901002").unwrap(), 0);
+
+        other.reset("2022010901001").unwrap();
+        other.begin_attempt("2022010901001").unwrap();
+        assert!(matches!(
+            other.begin_attempt("2022010901001"),
+            Err(UestcClientError::LoginThrottled { .. })
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failures_writes_nothing() {
+        let dir = temp_dir("login_guard_read");
+        let _ = fs::remove_dir_all(&dir);
+        let guard = LoginGuard::new(&dir);
+
+        assert_eq!(guard.failures("2022010901001").unwrap(), 0);
+        assert!(!dir.exists());
+
+        guard.record_outcome("2022010901001", &rejected());
+        let written = fs::read(guard.path()).unwrap();
+        assert_eq!(guard.failures("2022010901001").unwrap(), 1);
+        assert_eq!(fs::read(guard.path()).unwrap(), written);
+        // Only the records and the lock file, no temporary file left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unwritable_dir_is_an_io_error() {
+        // The guard's directory is a file
+        let dir = temp_dir("login_guard_blocked");
+        fs::write(&dir, "").unwrap();
+        let guard = LoginGuard::new(&dir);
+        let update = guard.reset("2022010901001");
+        let read = guard.failures("2022010901001");
+        fs::remove_file(&dir).unwrap();
+
+        assert!(matches!(
+            update,
+            Err(UestcClientError::IoError { ref operation, .. }) if operation == "update login guard"
+        ));
+        assert!(matches!(
+            read,
+            Err(UestcClientError::IoError { ref operation, .. }) if operation == "read login guard"
+        ));
+    }
+}
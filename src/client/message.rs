@@ -0,0 +1,177 @@
+//! The request and response types of both `reqwest` flavours behind one
+//! interface, so that building flow requests, following redirects by hand
+//! and recording are written once for both clients.
+
+use super::har;
+use crate::core::flow::HttpRequest;
+use crate::{Result, UestcClientError};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::{Method, StatusCode, Version};
+use std::time::Duration;
+use url::Url;
+
+/// An async or blocking `reqwest::Request`
+pub(crate) trait ClientRequest: Sized {
+    fn new(method: Method, url: Url) -> Self;
+    fn method(&self) -> &Method;
+    fn method_mut(&mut self) -> &mut Method;
+    fn url(&self) -> &Url;
+    fn url_mut(&mut self) -> &mut Url;
+    fn headers(&self) -> &HeaderMap;
+    fn headers_mut(&mut self) -> &mut HeaderMap;
+    /// The body, unless it is streamed
+    fn body_bytes(&self) -> Option<&[u8]>;
+    fn set_body(&mut self, body: Option<Vec<u8>>);
+    fn set_timeout(&mut self, timeout: Option<Duration>);
+    fn try_clone(&self) -> Option<Self>;
+
+    /// The request a login flow asked for
+    fn from_flow(request: &HttpRequest) -> Result<Self> {
+        let url = Url::parse(&request.url).map_err(|e| UestcClientError::InvalidArgument {
+            message: format!("Invalid request URL {:?}: {}", request.url, e),
+        })?;
+        let mut built = Self::new(request.method.clone(), url);
+        if let Some(fields) = request.form_fields() {
+            let body = url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(fields)
+                .finish();
+            built.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/x-www-form-urlencoded"),
+            );
+            built.set_body(Some(body.into_bytes()));
+        }
+        built.set_timeout(request.timeout);
+        Ok(built)
+    }
+
+    /// The request for the next hop if a response with `status` and
+    /// `headers` redirects this one, see [`har::follow_redirect`]
+    fn redirected(mut self, status: StatusCode, headers: &HeaderMap) -> Option<Self> {
+        let mut method = self.method().clone();
+        let mut url = self.url().clone();
+        let mut request_headers = self.headers().clone();
+        let drop_body = har::follow_redirect(status, headers, &mut method, &mut url, &mut request_headers)?;
+        *self.method_mut() = method;
+        *self.url_mut() = url;
+        *self.headers_mut() = request_headers;
+        if drop_body {
+            self.set_body(None);
+        }
+        Some(self)
+    }
+}
+
+/// An async or blocking `reqwest::Response`
+pub(crate) trait ClientResponse {
+    fn status(&self) -> StatusCode;
+    fn version(&self) -> Version;
+    /// The final URL, after any redirects `reqwest` followed
+    fn url(&self) -> &Url;
+    fn headers(&self) -> &HeaderMap;
+}
+
+macro_rules! impl_messages {
+    ($request:ty, $response:ty) => {
+        impl ClientRequest for $request {
+            fn new(method: Method, url: Url) -> Self {
+                <$request>::new(method, url)
+            }
+
+            fn method(&self) -> &Method {
+                <$request>::method(self)
+            }
+
+            fn method_mut(&mut self) -> &mut Method {
+                <$request>::method_mut(self)
+            }
+
+            fn url(&self) -> &Url {
+                <$request>::url(self)
+            }
+
+            fn url_mut(&mut self) -> &mut Url {
+                <$request>::url_mut(self)
+            }
+
+            fn headers(&self) -> &HeaderMap {
+                <$request>::headers(self)
+            }
+
+            fn headers_mut(&mut self) -> &mut HeaderMap {
+                <$request>::headers_mut(self)
+            }
+
+            fn body_bytes(&self) -> Option<&[u8]> {
+                self.body().and_then(|body| body.as_bytes())
+            }
+
+            fn set_body(&mut self, body: Option<Vec<u8>>) {
+                *self.body_mut() = body.map(Into::into);
+            }
+
+            fn set_timeout(&mut self, timeout: Option<Duration>) {
+                *self.timeout_mut() = timeout;
+            }
+
+            fn try_clone(&self) -> Option<Self> {
+                <$request>::try_clone(self)
+            }
+        }
+
+        impl ClientResponse for $response {
+            fn status(&self) -> StatusCode {
+                <$response>::status(self)
+            }
+
+            fn version(&self) -> Version {
+                <$response>::version(self)
+            }
+
+            fn url(&self) -> &Url {
+                <$response>::url(self)
+            }
+
+            fn headers(&self) -> &HeaderMap {
+                <$response>::headers(self)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "async")]
+impl_messages!(reqwest::Request, reqwest::Response);
+
+#[cfg(feature = "blocking")]
+impl_messages!(reqwest::blocking::Request, reqwest::blocking::Response);
+
+#[cfg(all(test, feature = "async"))]
+mod tests {
+    use super::*;
+    use crate::core::secret::Secret;
+
+    #[test]
+    fn test_from_flow() {
+        let request = HttpRequest::post_form(
+            "https://idas.uestc.edu.cn/authserver/login",
+            [("username".to_string(), Secret::from("2022010901001"))],
+        )
+        .field("dynamicCode", "12 34")
+        .timeout(Duration::from_secs(5));
+
+        let built = reqwest::Request::from_flow(&request).unwrap();
+        assert_eq!(built.method(), Method::POST);
+        assert_eq!(built.headers()[header::CONTENT_TYPE], "application/x-www-form-urlencoded");
+        assert_eq!(built.timeout(), Some(&Duration::from_secs(5)));
+        let body = std::str::from_utf8(built.body_bytes().unwrap()).unwrap();
+        let mut fields: Vec<_> = body.split('&').collect();
+        fields.sort();
+        assert_eq!(fields, ["dynamicCode=12+34", "username=2022010901001"]);
+
+        let invalid = HttpRequest::get("not a url");
+        assert!(matches!(
+            reqwest::Request::from_flow(&invalid),
+            Err(UestcClientError::InvalidArgument { .. })
+        ));
+    }
+}
@@ -0,0 +1,488 @@
+//! Many accounts behind one handle, each with its own cookie jar.
+
+use super::builder::{CookieKey, CookiePersistence, UestcClientBuilder};
+use super::credentials::CredentialProvider;
+use super::session::SessionStore;
+use crate::core::log;
+use crate::{Result, UestcClientError};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "async")]
+use super::UestcClient;
+
+#[cfg(feature = "blocking")]
+use super::UestcBlockingClient;
+
+/// Creates an account's session store from its student ID
+type StoreFactory = Arc<dyn Fn(&str) -> Arc<dyn SessionStore> + Send + Sync>;
+
+/// Where the pool keeps each account's session
+enum AccountPersistence {
+    Memory,
+    Dir {
+        dir: PathBuf,
+        key: Option<CookieKey>,
+    },
+    Custom(StoreFactory),
+}
+
+/// Login state of an account in an [`AccountPool`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccountStatus {
+    /// Added but not used yet
+    Idle,
+    /// The last login succeeded or found an active session
+    LoggedIn,
+    /// The last login failed with this error message
+    LoginFailed(String),
+    /// A request found the session expired since the last login
+    SessionExpired,
+}
+
+struct Account<C> {
+    credentials: Arc<dyn CredentialProvider>,
+    client: Mutex<Option<Arc<C>>>,
+    /// Shared with the client, which marks the session expired
+    status: Arc<Mutex<AccountStatus>>,
+    /// Held while logging in, so concurrent callers wait for one login
+    #[cfg(feature = "async")]
+    async_login: tokio::sync::Mutex<()>,
+    #[cfg(feature = "blocking")]
+    login: Mutex<()>,
+}
+
+/// A set of clients keyed by student ID.
+///
+/// Each account gets its own client built from a shared template builder, so
+/// cookie jars and saved sessions never mix. Clients are created and logged in
+/// lazily on first use.
+///
+/// reqwest ties a cookie jar to its connection pool, so accounts cannot share
+/// connections without sharing cookies; what they share is the configuration
+/// (endpoints, proxy, timeouts, headers, root certificates).
+///
+/// ```no_run
+/// # #[cfg(feature = "async")]
+/// # async fn run() -> uestc_client::Result<()> {
+/// use uestc_client::{AccountPool, Credentials, UestcClient};
+///
+/// let pool = AccountPool::<UestcClient>::new(UestcClient::builder()).cookie_dir("sessions");
+/// pool.add("2022000000001", Credentials::new("2022000000001", "password"))?;
+///
+/// let client = pool.client("2022000000001").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AccountPool<C> {
+    template: UestcClientBuilder,
+    persistence: AccountPersistence,
+    accounts: Mutex<BTreeMap<String, Arc<Account<C>>>>,
+}
+
+impl<C> fmt::Debug for AccountPool<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccountPool")
+            .field("template", &self.template)
+            .field("accounts", &self.accounts())
+            .finish()
+    }
+}
+
+impl<C> AccountPool<C> {
+    /// A pool building its clients from `template`.
+    ///
+    /// The template's cookie persistence is ignored: sessions are kept in
+    /// memory unless [`cookie_dir`](Self::cookie_dir) or
+    /// [`session_stores`](Self::session_stores) says otherwise.
+    pub fn new(template: UestcClientBuilder) -> Self {
+        Self {
+            template,
+            persistence: AccountPersistence::Memory,
+            accounts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Save each account's cookies to `<dir>/<student id>.json`
+    pub fn cookie_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.persistence = AccountPersistence::Dir {
+            dir: dir.into(),
+            key: None,
+        };
+        self
+    }
+
+    /// Like [`cookie_dir`](Self::cookie_dir), with every file encrypted under `key`
+    pub fn encrypted_cookie_dir(mut self, dir: impl Into<PathBuf>, key: CookieKey) -> Self {
+        self.persistence = AccountPersistence::Dir {
+            dir: dir.into(),
+            key: Some(key),
+        };
+        self
+    }
+
+    /// Create each account's session store with `stores`, given its student ID
+    pub fn session_stores<S, F>(mut self, stores: F) -> Self
+    where
+        S: SessionStore + 'static,
+        F: Fn(&str) -> S + Send + Sync + 'static,
+    {
+        self.persistence = AccountPersistence::Custom(Arc::new(move |id| Arc::new(stores(id))));
+        self
+    }
+
+    /// Add an account, replacing any account with the same student ID.
+    ///
+    /// Nothing is sent until the account is first used.
+    pub fn add<P: CredentialProvider + 'static>(
+        &self,
+        student_id: &str,
+        credentials: P,
+    ) -> Result<()> {
+        if student_id.is_empty()
+            || !student_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(UestcClientError::InvalidArgument {
+                message: format!("Invalid student ID {:?}", student_id),
+            });
+        }
+
+        let account = Account {
+            credentials: Arc::new(credentials),
+            client: Mutex::new(None),
+            status: Arc::new(Mutex::new(AccountStatus::Idle)),
+            #[cfg(feature = "async")]
+            async_login: tokio::sync::Mutex::new(()),
+            #[cfg(feature = "blocking")]
+            login: Mutex::new(()),
+        };
+        self.accounts
+            .lock()
+            .unwrap()
+            .insert(student_id.to_string(), Arc::new(account));
+        Ok(())
+    }
+
+    /// Remove an account. Its saved session is left in place.
+    pub fn remove(&self, student_id: &str) -> bool {
+        self.accounts.lock().unwrap().remove(student_id).is_some()
+    }
+
+    /// Student IDs of all accounts, in order
+    pub fn accounts(&self) -> Vec<String> {
+        self.accounts.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Status of an account, `None` if it was never added
+    pub fn status(&self, student_id: &str) -> Option<AccountStatus> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(student_id)
+            .map(|account| account.status.lock().unwrap().clone())
+    }
+
+    fn account(&self, student_id: &str) -> Result<Arc<Account<C>>> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(student_id)
+            .cloned()
+            .ok_or_else(|| UestcClientError::InvalidArgument {
+                message: format!("No account {:?} in the pool", student_id),
+            })
+    }
+
+    fn persistence_for(&self, student_id: &str) -> CookiePersistence {
+        match &self.persistence {
+            AccountPersistence::Memory => CookiePersistence::Memory,
+            AccountPersistence::Dir { dir, key } => {
+                let path = dir.join(format!("{}.json", student_id));
+                match key {
+                    Some(key) => CookiePersistence::EncryptedFile {
+                        path,
+                        key: key.clone(),
+                    },
+                    None => CookiePersistence::File(path),
+                }
+            }
+            AccountPersistence::Custom(stores) => CookiePersistence::Store(stores(student_id)),
+        }
+    }
+
+    /// The account's client, building it with `build` on first use
+    fn client_for(
+        &self,
+        student_id: &str,
+        account: &Account<C>,
+        build: impl FnOnce(UestcClientBuilder, &Account<C>) -> Result<C>,
+    ) -> Result<Arc<C>> {
+        let mut client = account.client.lock().unwrap();
+        if let Some(client) = &*client {
+            return Ok(client.clone());
+        }
+
+        log::debug!("为账号 {} 创建客户端", student_id);
+        let builder = self
+            .template
+            .clone()
+            .cookie_persistence(self.persistence_for(student_id));
+        let built = Arc::new(build(builder, account)?);
+        *client = Some(built.clone());
+        Ok(built)
+    }
+}
+
+impl<C> Account<C> {
+    fn is_logged_in(&self) -> bool {
+        *self.status.lock().unwrap() == AccountStatus::LoggedIn
+    }
+
+    /// Hook for the client, so the next `client` call logs in again
+    fn expire(&self) -> impl Fn() + Send + Sync + 'static {
+        let status = self.status.clone();
+        move || {
+            let mut status = status.lock().unwrap();
+            if *status == AccountStatus::LoggedIn {
+                log::info!("账号会话已过期，下次使用时重新登录");
+                *status = AccountStatus::SessionExpired;
+            }
+        }
+    }
+
+    fn record(&self, result: &Result<()>) {
+        *self.status.lock().unwrap() = match result {
+            Ok(()) => AccountStatus::LoggedIn,
+            Err(e) => AccountStatus::LoginFailed(e.to_string()),
+        };
+    }
+}
+
+#[cfg(feature = "async")]
+impl AccountPool<UestcClient> {
+    /// The account's client, logged in.
+    ///
+    /// The client is built and logged in through the account's credential
+    /// provider on first use, and again after a failed login or once a
+    /// request found the session expired. Concurrent calls for one account
+    /// share a single login.
+    pub async fn client(&self, student_id: &str) -> Result<Arc<UestcClient>> {
+        let account = self.account(student_id)?;
+        let client = self.client_for(student_id, &account, |builder, account| {
+            let credentials = account.credentials.clone();
+            Ok(builder
+                .build_async()?
+                .with_credential_provider(move || credentials.credentials())
+                .on_session_expired(account.expire()))
+        })?;
+        if account.is_logged_in() {
+            return Ok(client);
+        }
+
+        // Whoever waited on another caller's login can use its session
+        let _login = account.async_login.lock().await;
+        if account.is_logged_in() {
+            return Ok(client);
+        }
+        let result = client.login_with_provider().await;
+        account.record(&result);
+        result.map(|()| client)
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl AccountPool<UestcBlockingClient> {
+    /// The account's client, logged in.
+    ///
+    /// The client is built and logged in through the account's credential
+    /// provider on first use, and again after a failed login or once a
+    /// request found the session expired. Concurrent calls for one account
+    /// share a single login.
+    pub fn client(&self, student_id: &str) -> Result<Arc<UestcBlockingClient>> {
+        let account = self.account(student_id)?;
+        let client = self.client_for(student_id, &account, |builder, account| {
+            let credentials = account.credentials.clone();
+            Ok(builder
+                .build_blocking()?
+                .with_credential_provider(move || credentials.credentials())
+                .on_session_expired(account.expire()))
+        })?;
+        if account.is_logged_in() {
+            return Ok(client);
+        }
+
+        // Whoever waited on another caller's login can use its session
+        let _login = account.login.lock().unwrap();
+        if account.is_logged_in() {
+            return Ok(client);
+        }
+        let result = client.login_with_provider();
+        account.record(&result);
+        result.map(|()| client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Credentials;
+
+    #[cfg(feature = "blocking")]
+    type Pool = AccountPool<UestcBlockingClient>;
+    #[cfg(not(feature = "blocking"))]
+    type Pool = AccountPool<UestcClient>;
+
+    #[test]
+    fn test_accounts_and_status() {
+        let pool = Pool::new(UestcClientBuilder::new());
+        pool.add("2022000000002", Credentials::new("2022000000002", "pw"))
+            .unwrap();
+        pool.add("2022000000001", Credentials::new("2022000000001", "pw"))
+            .unwrap();
+        assert!(matches!(
+            pool.add("../escape", Credentials::new("x", "pw")),
+            Err(UestcClientError::InvalidArgument { .. })
+        ));
+
+        assert_eq!(pool.accounts(), ["2022000000001", "2022000000002"]);
+        assert_eq!(pool.status("2022000000001"), Some(AccountStatus::Idle));
+        assert_eq!(pool.status("2022000000003"), None);
+        assert!(pool.remove("2022000000002"));
+        assert_eq!(pool.accounts(), ["2022000000001"]);
+    }
+
+    #[test]
+    fn test_per_account_cookie_files() {
+        let pool = Pool::new(UestcClientBuilder::new()).cookie_dir("sessions");
+        match pool.persistence_for("2022000000001") {
+            CookiePersistence::File(path) => {
+                assert_eq!(path, PathBuf::from("sessions/2022000000001.json"))
+            }
+            other => panic!("unexpected persistence {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_lazy_login() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        // Each login attempt finds an active session
+        let base_url = spawn_server(4, |request_line| {
+            if request_line.starts_with("GET /authserver/login") {
+                response(
+                    "302 Found",
+                    &[("Location", "/personalInfo/personCenter/index.html")],
+                    "",
+                )
+            } else {
+                response("200 OK", &[], "ok")
+            }
+        });
+
+        let pool = Pool::new(test_client(&base_url));
+        pool.add("2022000000001", Credentials::new("2022000000001", "pw"))
+            .unwrap();
+        pool.add("2022000000002", Credentials::new("2022000000002", "pw"))
+            .unwrap();
+
+        let first = pool.client("2022000000001").unwrap();
+        assert_eq!(pool.status("2022000000001"), Some(AccountStatus::LoggedIn));
+        assert_eq!(pool.status("2022000000002"), Some(AccountStatus::Idle));
+
+        // Logged-in accounts are handed out without another round trip
+        let again = pool.client("2022000000001").unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+
+        let second = pool.client("2022000000002").unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(matches!(
+            pool.client("2022000000003"),
+            Err(UestcClientError::InvalidArgument { .. })
+        ));
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_expired_session_logs_in_again() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Logins find an active session, the user info request bounces to
+        // the login page
+        static CHECKS: AtomicUsize = AtomicUsize::new(0);
+        let base_url = spawn_server(6, |request_line| {
+            if request_line.starts_with("GET /authserver/login ") {
+                CHECKS.fetch_add(1, Ordering::SeqCst);
+                response(
+                    "302 Found",
+                    &[("Location", "/personalInfo/personCenter/index.html")],
+                    "",
+                )
+            } else if request_line.starts_with("POST /personalInfo/common/getUserConf") {
+                response("302 Found", &[("Location", "/authserver/login?service=x")], "")
+            } else {
+                response("200 OK", &[], "ok")
+            }
+        });
+
+        let pool = Pool::new(test_client(&base_url));
+        pool.add("2022000000001", Credentials::new("2022000000001", "pw"))
+            .unwrap();
+
+        let client = pool.client("2022000000001").unwrap();
+        assert!(matches!(
+            client.whoami(),
+            Err(UestcClientError::SessionExpired)
+        ));
+        assert_eq!(
+            pool.status("2022000000001"),
+            Some(AccountStatus::SessionExpired)
+        );
+
+        pool.client("2022000000001").unwrap();
+        assert_eq!(pool.status("2022000000001"), Some(AccountStatus::LoggedIn));
+        assert_eq!(CHECKS.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_concurrent_callers_share_one_login() {
+        use crate::client::test_util::{response, spawn_server, test_client};
+
+        // A slow session check, so the second caller arrives mid-login
+        let base_url = spawn_server(2, |request_line| {
+            if request_line.starts_with("GET /authserver/login") {
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                response(
+                    "302 Found",
+                    &[("Location", "/personalInfo/personCenter/index.html")],
+                    "",
+                )
+            } else {
+                response("200 OK", &[], "ok")
+            }
+        });
+
+        let pool = Arc::new(Pool::new(test_client(&base_url)));
+        pool.add("2022000000001", Credentials::new("2022000000001", "pw"))
+            .unwrap();
+
+        let callers: Vec<_> = (0..2)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || pool.client("2022000000001"))
+            })
+            .collect();
+        let clients: Vec<_> = callers
+            .into_iter()
+            .map(|caller| caller.join().unwrap().unwrap())
+            .collect();
+        assert!(Arc::ptr_eq(&clients[0], &clients[1]));
+        assert_eq!(pool.status("2022000000001"), Some(AccountStatus::LoggedIn));
+    }
+}
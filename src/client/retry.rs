@@ -0,0 +1,316 @@
+//! Retries and deadlines for login flows.
+
+use crate::core::flow::HttpRequest;
+use crate::core::log;
+use crate::{Result, UestcClientError};
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// How login flows cope with transient failures.
+///
+/// A request is sent again, after an exponentially growing delay, only if it
+/// is idempotent and failed with an error for which
+/// [`UestcClientError::is_retryable`] holds. Submitting a password or a code
+/// is never repeated. The default retries twice and sets no deadline.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub(crate) max_retries: usize,
+    pub(crate) initial_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+    pub(crate) jitter: bool,
+    pub(crate) deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(5),
+            jitter: true,
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send every request once
+    pub fn none() -> Self {
+        Self::new().max_retries(0)
+    }
+
+    /// Retry a failed request up to `max_retries` times
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Wait `initial` before the first retry, doubling for each further
+    /// retry up to `max`
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Randomise each delay between half and all of its nominal value, so
+    /// that many clients do not retry in lockstep. On by default.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Fail a login flow with [`UestcClientError::Timeout`] if it has not
+    /// finished within `deadline`, retries included.
+    ///
+    /// Waiting for a WeChat scan is bounded by
+    /// [`WechatLoginOptions::deadline`](crate::WechatLoginOptions::deadline) instead.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Start the deadline for a login flow, if one is configured
+    pub(crate) fn start_deadline(&self) -> Option<Deadline> {
+        self.deadline.map(Deadline::after)
+    }
+
+    /// Nominal delay before retry number `attempt + 1`
+    pub(crate) fn nominal_backoff(&self, attempt: usize) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.min(31) as u32);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// How long to wait before sending `request` again after it failed with
+    /// `error` on attempt `attempt` (counting from 0), or `None` to give up
+    pub(crate) fn retry_delay(
+        &self,
+        request: &HttpRequest,
+        attempt: usize,
+        error: &UestcClientError,
+        deadline: Option<&Deadline>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_retries || !request.idempotent || !error.is_retryable() {
+            return None;
+        }
+
+        let mut delay = self.nominal_backoff(attempt);
+        if self.jitter && !delay.is_zero() {
+            delay = rand::rng().random_range(delay / 2..=delay);
+        }
+        // No point in waiting if the flow times out before the retry
+        match deadline {
+            Some(deadline) if deadline.remaining_time() <= delay => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+/// The point by which a login flow must finish
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline {
+    at: Instant,
+    limit: Duration,
+}
+
+impl Deadline {
+    pub(crate) fn after(limit: Duration) -> Self {
+        Self {
+            at: Instant::now() + limit,
+            limit,
+        }
+    }
+
+    fn remaining_time(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    /// Time left, or [`UestcClientError::Timeout`] once it has passed
+    pub(crate) fn remaining(&self) -> Result<Duration> {
+        let remaining = self.remaining_time();
+        if remaining.is_zero() {
+            return Err(self.timeout_error());
+        }
+        Ok(remaining)
+    }
+
+    /// Cap the timeout of `request` at the time left. Returns whether the
+    /// deadline, rather than the request's own timeout, is what limits it.
+    pub(crate) fn limit(&self, request: &mut HttpRequest) -> Result<bool> {
+        let remaining = self.remaining()?;
+        let capped = request.timeout.is_none_or(|timeout| remaining <= timeout);
+        request.timeout = Some(request.timeout.map_or(remaining, |t| t.min(remaining)));
+        Ok(capped)
+    }
+
+    /// Report a request the deadline cut short as [`UestcClientError::Timeout`]
+    /// rather than as a network error
+    pub(crate) fn expired(&self, error: UestcClientError) -> UestcClientError {
+        match error {
+            UestcClientError::NetworkError { ref source, .. } if source.is_timeout() => {
+                self.timeout_error()
+            }
+            error => error,
+        }
+    }
+
+    fn timeout_error(&self) -> UestcClientError {
+        UestcClientError::Timeout {
+            message: format!("Login did not finish within {:?}", self.limit),
+        }
+    }
+}
+
+/// The retries of one request of a login flow. The clients send the
+/// request and wait out the delays.
+pub(crate) struct Attempts<'a> {
+    policy: &'a RetryPolicy,
+    deadline: Option<&'a Deadline>,
+    retries: usize,
+    /// Whether the deadline, not the request's own timeout, limits the
+    /// attempt in flight
+    capped: bool,
+}
+
+impl<'a> Attempts<'a> {
+    pub(crate) fn new(policy: &'a RetryPolicy, deadline: Option<&'a Deadline>) -> Self {
+        Self {
+            policy,
+            deadline,
+            retries: 0,
+            capped: false,
+        }
+    }
+
+    /// Prepare `request` for the next attempt, failing with
+    /// [`UestcClientError::Timeout`] once the deadline has passed
+    pub(crate) fn start(&mut self, request: &mut HttpRequest) -> Result<()> {
+        self.capped = match self.deadline {
+            Some(deadline) => deadline.limit(request)?,
+            None => false,
+        };
+        Ok(())
+    }
+
+    /// After an attempt failed with `error`: how long to wait before the
+    /// next one, or the error to give up with
+    pub(crate) fn failed(&mut self, request: &HttpRequest, error: UestcClientError) -> Result<Duration> {
+        let error = match self.deadline {
+            Some(deadline) if self.capped => deadline.expired(error),
+            _ => error,
+        };
+        let Some(delay) = self.policy.retry_delay(request, self.retries, &error, self.deadline) else {
+            return Err(error);
+        };
+        self.retries += 1;
+        log::warn!(
+            "Request to {} failed, retrying in {:?} ({}/{}): {}",
+            request.url,
+            delay,
+            self.retries,
+            self.policy.max_retries,
+            error
+        );
+        Ok(delay)
+    }
+
+    pub(crate) fn retries(&self) -> usize {
+        self.retries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let policy = RetryPolicy::new()
+            .backoff(Duration::from_millis(100), Duration::from_millis(350))
+            .jitter(false);
+        assert_eq!(policy.nominal_backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.nominal_backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.nominal_backoff(2), Duration::from_millis(350));
+        assert_eq!(policy.nominal_backoff(100), Duration::from_millis(350));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_only_idempotent_requests_with_retryable_errors_are_retried() {
+        use std::collections::HashMap;
+
+        let policy = RetryPolicy::new().max_retries(1);
+        let get = HttpRequest::get("https://idas.uestc.edu.cn/authserver/login");
+        let post = HttpRequest::post_form(
+            "https://idas.uestc.edu.cn/authserver/login",
+            HashMap::<String, String>::new(),
+        );
+        let refused = UestcClientError::NetworkError {
+            message: "refused".to_string(),
+            source: connect_error(),
+        };
+
+        let delay = policy.retry_delay(&get, 0, &refused, None).unwrap();
+        assert!(delay >= Duration::from_millis(250) && delay <= Duration::from_millis(500));
+        assert!(policy.retry_delay(&get, 1, &refused, None).is_none());
+        assert!(policy.retry_delay(&post, 0, &refused, None).is_none());
+        let timed_out = UestcClientError::Timeout {
+            message: "poll".to_string(),
+        };
+        assert!(policy.retry_delay(&get, 0, &timed_out, None).is_none());
+        assert!(
+            RetryPolicy::none()
+                .retry_delay(&get, 0, &refused, None)
+                .is_none()
+        );
+
+        let deadline = Deadline::after(Duration::from_millis(100));
+        assert!(
+            policy
+                .retry_delay(&get, 0, &refused, Some(&deadline))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_deadline_limits_request_timeout() {
+        let mut request =
+            HttpRequest::get("https://open.weixin.qq.com").timeout(Duration::from_secs(30));
+        assert!(
+            Deadline::after(Duration::from_secs(5))
+                .limit(&mut request)
+                .unwrap()
+        );
+        assert!(request.timeout.unwrap() <= Duration::from_secs(5));
+        assert!(
+            !Deadline::after(Duration::from_secs(60))
+                .limit(&mut request)
+                .unwrap()
+        );
+
+        let passed = Deadline::after(Duration::ZERO);
+        assert!(matches!(
+            passed.limit(&mut request),
+            Err(UestcClientError::Timeout { .. })
+        ));
+    }
+
+    /// A connection refused by a closed local port
+    #[cfg(feature = "async")]
+    fn connect_error() -> reqwest::Error {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let client = reqwest::Client::builder().no_proxy().build().unwrap();
+            client.get("http://127.0.0.1:1/").send().await.unwrap_err()
+        })
+    }
+}
@@ -0,0 +1,182 @@
+//! Where a client keeps its session between runs.
+
+use super::cookies;
+use crate::core::cookie_jar::{self, PersistedCookie};
+use crate::core::log;
+use crate::Result;
+use cookie_store::CookieStore;
+use reqwest_cookie_store::CookieStoreMutex;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Persistence for a client's session cookies.
+///
+/// Implement it to keep sessions in a database or secret manager;
+/// [`SessionSnapshot::to_json`] and [`SessionSnapshot::from_json`] give a
+/// string form to store. Built-in implementations are
+/// [`FileSessionStore`](crate::FileSessionStore) and [`MemorySessionStore`].
+pub trait SessionStore: Send + Sync {
+    /// The saved session, `None` if nothing has been saved yet
+    fn load(&self) -> Result<Option<SessionSnapshot>>;
+
+    fn save(&self, snapshot: &SessionSnapshot) -> Result<()>;
+
+    /// Forget the saved session, e.g. after logout
+    fn clear(&self) -> Result<()>;
+}
+
+impl<T: SessionStore + ?Sized> SessionStore for Arc<T> {
+    fn load(&self) -> Result<Option<SessionSnapshot>> {
+        (**self).load()
+    }
+
+    fn save(&self, snapshot: &SessionSnapshot) -> Result<()> {
+        (**self).save(snapshot)
+    }
+
+    fn clear(&self) -> Result<()> {
+        (**self).clear()
+    }
+}
+
+/// The cookies of a session at one point in time
+#[derive(Clone, Default, PartialEq)]
+pub struct SessionSnapshot {
+    pub(crate) cookies: Vec<PersistedCookie>,
+}
+
+impl fmt::Debug for SessionSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.cookies.iter().map(|c| c.name.as_str()).collect();
+        f.debug_struct("SessionSnapshot").field("cookies", &names).finish()
+    }
+}
+
+impl SessionSnapshot {
+    /// Capture every unexpired cookie in `store`
+    pub(crate) fn capture(store: &CookieStoreMutex) -> Self {
+        Self {
+            cookies: cookie_jar::snapshot(&store.lock().unwrap()),
+        }
+    }
+
+    /// A cookie store holding the cookies that have not expired since
+    pub(crate) fn restore(&self) -> CookieStore {
+        cookie_jar::into_store(&cookie_jar::unexpired(self.cookies.clone()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+
+    /// Serialize in the same format as the cookie file
+    pub fn to_json(&self) -> Result<String> {
+        cookie_jar::serialize_cookies(self.cookies.clone())
+    }
+
+    /// Parse a snapshot written by [`to_json`](Self::to_json) or a cookie file
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(Self {
+            cookies: cookie_jar::deserialize_cookies(json)?,
+        })
+    }
+}
+
+/// Load the saved session into a new cookie store, empty if there is none.
+///
+/// Only a cookie file that cannot be decrypted is an error; a session that
+/// fails to load otherwise is logged and the store starts empty.
+pub(crate) fn restore_or_default(sessions: &dyn SessionStore) -> Result<Arc<CookieStoreMutex>> {
+    let store = match sessions.load() {
+        Ok(Some(snapshot)) => {
+            log::debug!("成功加载 {} 个 cookies", snapshot.len());
+            snapshot.restore()
+        }
+        Ok(None) => CookieStore::default(),
+        Err(e) if cookies::is_decrypt_error(&e) => return Err(e),
+        Err(e) => {
+            log::warn!("加载已保存的会话失败，使用空的 cookie 存储: {}", e);
+            CookieStore::default()
+        }
+    };
+    Ok(Arc::new(CookieStoreMutex::new(store)))
+}
+
+/// Keeps the session in memory, e.g. to hand it from one client to the next
+/// within a process. Share it between clients through an `Arc`.
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    snapshot: Mutex<Option<SessionSnapshot>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self) -> Result<Option<SessionSnapshot>> {
+        Ok(self.snapshot.lock().unwrap().clone())
+    }
+
+    fn save(&self, snapshot: &SessionSnapshot) -> Result<()> {
+        *self.snapshot.lock().unwrap() = Some(snapshot.clone());
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        *self.snapshot.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_with(set_cookie: &str) -> CookieStoreMutex {
+        let url = url::Url::parse("https://idas.uestc.edu.cn/authserver/login").unwrap();
+        let mut store = CookieStore::default();
+        store.insert_raw(&set_cookie.parse().unwrap(), &url).unwrap();
+        CookieStoreMutex::new(store)
+    }
+
+    #[test]
+    fn test_memory_store_round_trip() {
+        let sessions = MemorySessionStore::new();
+        assert!(sessions.load().unwrap().is_none());
+
+        let snapshot = SessionSnapshot::capture(&store_with("CASTGC=TGT-1; Path=/authserver"));
+        sessions.save(&snapshot).unwrap();
+        let loaded = sessions.load().unwrap().unwrap();
+        assert_eq!(loaded, snapshot);
+        assert_eq!(
+            loaded.restore().get("idas.uestc.edu.cn", "/authserver", "CASTGC").unwrap().value(),
+            "TGT-1"
+        );
+
+        sessions.clear().unwrap();
+        assert!(sessions.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_snapshot_json_round_trip() {
+        let snapshot = SessionSnapshot::capture(&store_with("CASTGC=TGT-1; Path=/authserver"));
+        let parsed = SessionSnapshot::from_json(&snapshot.to_json().unwrap()).unwrap();
+        assert_eq!(parsed, snapshot);
+        assert_eq!(parsed.len(), 1);
+    }
+
+    #[test]
+    fn test_debug_hides_values() {
+        let snapshot = SessionSnapshot::capture(&store_with("CASTGC=TGT-1; Path=/authserver"));
+        let debug = format!("{:?}", snapshot);
+        assert!(debug.contains("CASTGC"));
+        assert!(!debug.contains("TGT-1"));
+    }
+}
@@ -0,0 +1,315 @@
+//! What the async and blocking clients share besides their `reqwest` client.
+
+use super::autosave::{Autosave, TrackedCookieStore};
+use super::builder::UestcClientBuilder;
+use super::credentials::{CredentialProvider, Credentials};
+use super::har::{self, HarRecorder, PendingExchange};
+use super::lockout::LoginGuard;
+use super::message::{ClientRequest, ClientResponse};
+use super::retry::RetryPolicy;
+use super::session::{SessionSnapshot, SessionStore};
+use super::ServiceLogin;
+use crate::core::cas::ServiceRedirects;
+use crate::core::endpoints::{DEFAULT_AUTH_SERVER_URL, Endpoints};
+use crate::core::flow::{Input, Step};
+use crate::core::log;
+use crate::core::logout;
+use crate::{Result, UestcClientError, core};
+use reqwest::header::HeaderMap;
+use reqwest::redirect;
+use reqwest_cookie_store::CookieStoreMutex;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// A `reqwest` client of either flavour, `$builder`, set up as `$config`
+/// describes with `$cookies` as its cookie provider and following
+/// redirects by `$policy`. `$read_timeout` names the builder method the
+/// read timeout goes to.
+macro_rules! build_client {
+    ($builder:expr, $config:expr, $cookies:expr, $policy:expr, $read_timeout:ident) => {{
+        let config: &UestcClientBuilder = $config;
+        let mut builder = $builder
+            .default_headers(config.headers.clone())
+            .cookie_provider(Arc::clone($cookies))
+            .redirect($policy);
+
+        if config.no_proxy {
+            builder = builder.no_proxy();
+        } else {
+            for proxy in &config.proxies {
+                builder = builder.proxy(proxy.clone());
+            }
+        }
+        if let Some(timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = config.read_timeout {
+            builder = builder.$read_timeout(timeout);
+        }
+        for cert in &config.root_certificates {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+
+        builder.build()
+    }};
+}
+
+pub(crate) use build_client;
+
+pub(crate) struct ClientState {
+    pub endpoints: Endpoints,
+    /// The session's cookies. `None` when a custom client was supplied,
+    /// whose cookie store, if any, can't be reached from here.
+    pub cookie_store: Option<Arc<CookieStoreMutex>>,
+    /// `None` when cookies are not persisted
+    session_store: Option<Arc<dyn SessionStore>>,
+    /// Writes cookie changes in the background, flushing when dropped
+    _autosave: Option<Autosave>,
+    pub retry_policy: RetryPolicy,
+    pub login_guard: Option<LoginGuard>,
+    pub credential_provider: Option<Arc<dyn CredentialProvider>>,
+    /// Origins of systems entered with `login_to_service`, for logout
+    entered_services: Mutex<HashSet<String>>,
+    pub har_recorder: Option<HarRecorder>,
+    /// Called whenever a request finds the session expired, e.g. by a pool
+    pub session_expired_hook: Option<Arc<dyn Fn() + Send + Sync>>,
+    /// Redirects followed per request while recording
+    pub redirect_limit: usize,
+    /// Headers the underlying client adds to every request, for recording
+    default_headers: HeaderMap,
+}
+
+impl ClientState {
+    /// For a client wrapping a `reqwest` client supplied by the caller
+    pub fn custom() -> Self {
+        Self {
+            endpoints: Endpoints::new(DEFAULT_AUTH_SERVER_URL).expect("Invalid default auth server URL"),
+            cookie_store: None,
+            session_store: None,
+            _autosave: None,
+            retry_policy: RetryPolicy::default(),
+            login_guard: None,
+            credential_provider: None,
+            entered_services: Mutex::new(HashSet::new()),
+            har_recorder: None,
+            session_expired_hook: None,
+            redirect_limit: 0,
+            default_headers: HeaderMap::new(),
+        }
+    }
+
+    /// The state `config` describes, with the client following redirects as
+    /// configured and the redirectless one, both made by `build` around the
+    /// session's cookies, see [`build_client`]
+    pub fn from_builder<C>(
+        config: &UestcClientBuilder,
+        build: impl Fn(&Arc<TrackedCookieStore>, redirect::Policy) -> reqwest::Result<C>,
+    ) -> Result<(Self, C, C)> {
+        let endpoints = Endpoints::new(&config.auth_server_url)?;
+        let (cookie_store, session_store) = config.cookie_parts()?;
+        let autosave = match (&session_store, config.autosave) {
+            (Some(sessions), Some(delay)) => Some(Autosave::spawn(sessions.clone(), cookie_store.clone(), delay)),
+            _ => None,
+        };
+        let cookies = Arc::new(TrackedCookieStore::new(cookie_store.clone(), autosave.as_ref()));
+
+        let state = Self {
+            endpoints,
+            cookie_store: Some(cookie_store),
+            session_store,
+            _autosave: autosave,
+            retry_policy: config.retry_policy.clone(),
+            login_guard: config.login_guard.clone(),
+            credential_provider: None,
+            entered_services: Mutex::new(HashSet::new()),
+            har_recorder: config.har_recorder.clone(),
+            session_expired_hook: None,
+            redirect_limit: config.redirect_limit,
+            default_headers: config.headers.clone(),
+        };
+        let build = |policy| {
+            build(&cookies, policy).map_err(|e| UestcClientError::ClientInitError {
+                message: format!("Failed to build client: {}", e),
+            })
+        };
+        Ok((state, build(config.redirect_policy())?, build(redirect::Policy::none())?))
+    }
+
+    /// [`UestcClientError::SessionExpired`], after telling the hook
+    pub fn session_expired(&self) -> UestcClientError {
+        if let Some(hook) = &self.session_expired_hook {
+            hook();
+        }
+        UestcClientError::SessionExpired
+    }
+
+    pub fn save_cookie_store(&self) -> Result<()> {
+        match (&self.session_store, &self.cookie_store) {
+            (Some(sessions), Some(cookies)) => sessions.save(&SessionSnapshot::capture(cookies)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Save the cookies of a session that just logged in
+    fn save_session(&self) {
+        if let Err(e) = self.save_cookie_store() {
+            log::warn!("Failed to save cookies after login: {}", e);
+        }
+    }
+
+    /// Perform a flow step that needs no I/O: the login guard's bookkeeping
+    /// and saving or clearing the session.
+    ///
+    /// # Panics
+    ///
+    /// On a step that needs I/O, which the client performs itself.
+    pub fn perform<T>(&self, step: Step<T>) -> Result<Input> {
+        match step {
+            Step::BeginAttempt(username) => {
+                self.begin_attempt(&username)?;
+                Ok(Input::Continue)
+            }
+            Step::SaveSession => {
+                self.save_session();
+                Ok(Input::Continue)
+            }
+            Step::ClearSession => {
+                let (cookies, saved_session) = self.clear_session();
+                Ok(Input::SessionCleared {
+                    cookies,
+                    saved_session,
+                })
+            }
+            _ => unreachable!("the client performs steps that need I/O"),
+        }
+    }
+
+    /// Empty the cookie store and clear the saved session. Returns how many
+    /// cookies were removed and whether the saved session was cleared.
+    fn clear_session(&self) -> (usize, bool) {
+        let cookies = self.cookie_store.as_deref().map_or(0, logout::clear_cookies);
+        let saved_session = match &self.session_store {
+            Some(sessions) => match sessions.clear() {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("Failed to clear saved session after logout: {}", e);
+                    false
+                }
+            },
+            None => false,
+        };
+        (cookies, saved_session)
+    }
+
+    /// Finish walking the redirects of a service login that ended at
+    /// `resp`, remembering the system entered for logout
+    pub fn service_login<R: ClientResponse>(
+        &self,
+        walk: ServiceRedirects,
+        service_url: &str,
+        resp: R,
+    ) -> Result<ServiceLogin<R>> {
+        let landing_url = resp.url().clone();
+        let status = resp.status();
+        let login = walk
+            .finish(resp, &landing_url, status)
+            .ok_or_else(|| self.session_expired())?;
+        self.enter_service(service_url);
+        Ok(login)
+    }
+
+    /// Remember the system behind `service_url` for logout
+    pub fn enter_service(&self, service_url: &str) {
+        if let Some(origin) = logout::origin(service_url) {
+            self.entered_services.lock().unwrap().insert(origin);
+        }
+    }
+
+    /// The systems entered since the last logout
+    pub fn take_entered_services(&self) -> HashSet<String> {
+        std::mem::take(&mut *self.entered_services.lock().unwrap())
+    }
+
+    fn begin_attempt(&self, username: &str) -> Result<()> {
+        match &self.login_guard {
+            Some(guard) => guard.begin_attempt(username),
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_outcome(&self, username: &str, result: &Result<()>) {
+        if let Some(guard) = &self.login_guard {
+            guard.record_outcome(username, result);
+        }
+    }
+
+    /// Credentials from the provider, [`UestcClientError::CredentialError`]
+    /// if there is none
+    pub fn credentials(&self) -> Result<Credentials> {
+        let Some(provider) = &self.credential_provider else {
+            return Err(UestcClientError::CredentialError {
+                message: "No credential provider configured".to_string(),
+                source: None,
+            });
+        };
+        provider.credentials()
+    }
+
+    /// Credentials to log in again with after the session expired,
+    /// [`UestcClientError::SessionExpired`] if there is no provider
+    pub fn relogin_credentials(&self) -> Result<Credentials> {
+        if self.credential_provider.is_none() {
+            log::warn!("No credentials configured for re-login");
+            return Err(UestcClientError::SessionExpired);
+        }
+        self.credentials()
+    }
+
+    /// Whether a response landed on, or redirects to, the CAS login page
+    pub fn is_login_redirect(&self, resp: &impl ClientResponse) -> bool {
+        core::cas::is_login_redirect(resp.url(), resp.status(), resp.headers(), &self.endpoints.auth_server_url)
+    }
+
+    /// [`UestcClientError::SessionExpired`] if a request that needs a
+    /// session landed on the CAS login page
+    pub fn require_session(&self, resp: &impl ClientResponse) -> Result<()> {
+        if core::cas::is_login_page(resp.url(), &self.endpoints.auth_server_url) {
+            log::info!("No active session, cannot fetch user info");
+            return Err(self.session_expired());
+        }
+        Ok(())
+    }
+
+    /// An authenticated request landed on the login page: the copy of it to
+    /// send again after logging in, or the error if it can't be replayed
+    pub fn replay_after_expiry<R>(&self, replay: Option<R>) -> Result<R> {
+        log::info!("Request redirected to login page, session expired");
+        let expired = self.session_expired();
+        replay.ok_or_else(|| {
+            log::warn!("Request body cannot be replayed, not logging in again");
+            expired
+        })
+    }
+
+    /// The response to a request replayed after logging in again,
+    /// [`UestcClientError::SessionExpired`] if it still hit the login page
+    pub fn replayed<R: ClientResponse>(&self, resp: R) -> Result<R> {
+        if self.is_login_redirect(&resp) {
+            log::error!("Replayed request still redirected to login page");
+            return Err(self.session_expired());
+        }
+        Ok(resp)
+    }
+
+    /// Capture a request about to be recorded
+    pub fn start_exchange(&self, request: &impl ClientRequest) -> PendingExchange {
+        let mut headers = self.default_headers.clone();
+        headers.extend(request.headers().clone());
+        let cookies = self
+            .cookie_store
+            .as_deref()
+            .map(|cookies| har::request_cookies(cookies, request.url()))
+            .unwrap_or_default();
+        PendingExchange::new(request, headers, cookies)
+    }
+}
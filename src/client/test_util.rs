@@ -0,0 +1,50 @@
+//! A minimal HTTP server standing in for IDAS in tests.
+
+use crate::{CookiePersistence, UestcClientBuilder};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+
+/// Serve `requests` connections, answering each request line with `respond`.
+///
+/// Returns the base URL, e.g. `http://127.0.0.1:12345`.
+pub(crate) fn spawn_server(requests: usize, respond: fn(&str) -> String) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().take(requests) {
+            let mut stream = stream.unwrap();
+            let mut request_line = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+            }
+
+            stream.write_all(respond(&request_line).as_bytes()).unwrap();
+        }
+    });
+
+    format!("http://{}", addr)
+}
+
+/// A `Connection: close` response with the given status line, extra headers and body
+pub(crate) fn response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+    let mut resp = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        resp.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    resp.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    resp
+}
+
+/// A builder for a client that talks to the test server at `base_url`,
+/// without a proxy and with cookies kept in memory
+pub(crate) fn test_client(base_url: &str) -> UestcClientBuilder {
+    UestcClientBuilder::new()
+        .auth_server_url(format!("{}/authserver", base_url))
+        .no_proxy()
+        .cookie_persistence(CookiePersistence::Memory)
+}
@@ -1,3 +1,20 @@
+pub mod captcha;
+pub mod cas;
+pub mod cookie_cipher;
+pub mod cookie_jar;
 pub mod crypto;
+pub mod dynamic_code;
+pub mod dynamic_code_login;
+pub mod endpoints;
+pub mod flow;
+pub mod har;
+pub mod log;
+pub mod logout;
 pub mod parser;
+pub mod password_login;
+pub mod secret;
+pub mod trace;
+pub mod user_info;
+pub mod verification;
 pub mod wechat;
+pub mod wechat_login;
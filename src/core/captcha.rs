@@ -0,0 +1,80 @@
+use crate::core::log;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+struct NeedCaptchaResponse {
+    #[serde(rename = "isNeed")]
+    is_need: serde_json::Value,
+}
+
+fn timestamp_millis() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or(std::time::Duration::from_secs(0))
+        .as_millis()
+}
+
+/// Build the URL asking IDAS whether `username` must solve a captcha
+pub fn build_check_url(auth_server_url: &str, username: &str) -> String {
+    format!(
+        "{}/checkNeedCaptcha.htl?username={}&_={}",
+        auth_server_url,
+        urlencoding::encode(username),
+        timestamp_millis()
+    )
+}
+
+/// Build the URL of the captcha image bound to the current cookie session
+pub fn build_image_url(auth_server_url: &str) -> String {
+    format!("{}/getCaptcha.htl?{}", auth_server_url, timestamp_millis())
+}
+
+/// Parse the `checkNeedCaptcha.htl` response, e.g. `{"isNeed":true}`.
+///
+/// An answer that can't be understood, e.g. an error page, is taken to mean
+/// no captcha is needed: login went ahead without asking before captchas
+/// were supported, and IDAS still rejects the form if one was required.
+pub fn parse_need_captcha(text: &str) -> bool {
+    let need = match serde_json::from_str::<NeedCaptchaResponse>(text.trim()) {
+        Ok(NeedCaptchaResponse {
+            is_need: serde_json::Value::Bool(b),
+        }) => b,
+        Ok(NeedCaptchaResponse {
+            is_need: serde_json::Value::String(s),
+        }) => s.eq_ignore_ascii_case("true"),
+        Ok(NeedCaptchaResponse { is_need }) => {
+            log::warn!("Unexpected isNeed value {}, assuming no captcha", is_need);
+            false
+        }
+        Err(e) => {
+            log::warn!("Failed to parse captcha check response, assuming no captcha: {}", e);
+            false
+        }
+    };
+
+    log::debug!("Captcha required: {}", need);
+    need
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_need_captcha() {
+        assert!(parse_need_captcha(r#"{"isNeed":true}"#));
+        assert!(!parse_need_captcha(r#"{"isNeed":false}"#));
+        assert!(parse_need_captcha(r#"{"isNeed":"true"}"#));
+        assert!(!parse_need_captcha(r#"{"isNeed":null}"#));
+        assert!(!parse_need_captcha("<html></html>"));
+        assert!(!parse_need_captcha(""));
+    }
+
+    #[test]
+    fn test_build_check_url() {
+        let url = build_check_url("https://idas.uestc.edu.cn/authserver", "2022 001");
+        assert!(url.starts_with(
+            "https://idas.uestc.edu.cn/authserver/checkNeedCaptcha.htl?username=2022%20001&_="
+        ));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::core::log;
+use crate::{Result, UestcClientError};
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, LOCATION};
+use url::Url;
+
+/// Outcome of presenting a CAS service ticket to a downstream system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TicketValidation {
+    /// The service accepted the ticket and served the landing page
+    Accepted,
+    /// The service bounced the ticket back to CAS or answered with an error
+    Rejected { status: StatusCode },
+    /// The landing page was reached but the ticket hop was not visible,
+    /// e.g. because the underlying client follows redirects on its own
+    NotObserved,
+}
+
+/// Maximum number of redirects followed while walking a service ticket
+pub const MAX_SERVICE_REDIRECTS: usize = 10;
+
+/// Result of `login_to_service` on either client
+#[derive(Debug)]
+pub struct ServiceLogin<R> {
+    /// The final (non-redirect) response from the downstream system
+    pub response: R,
+    /// The service ticket issued by CAS, if it was observed
+    pub ticket: Option<String>,
+    /// Every redirect target followed, in order
+    pub redirects: Vec<Url>,
+    /// Whether the downstream system accepted the ticket
+    pub validation: TicketValidation,
+}
+
+/// Follows the redirects of a CAS `service=` login one hop at a time,
+/// picking up the service ticket on the way
+#[derive(Debug)]
+pub struct ServiceRedirects {
+    auth_server_url: String,
+    login_url: String,
+    current: Url,
+    redirects: Vec<Url>,
+    ticket: Option<String>,
+}
+
+impl ServiceRedirects {
+    /// Start at the CAS login URL issuing a ticket for `service_url`
+    pub fn new(auth_server_url: &str, service_url: &str) -> Result<Self> {
+        let login_url = build_service_login_url(auth_server_url, service_url);
+        let current = Url::parse(&login_url).map_err(|e| UestcClientError::InvalidArgument {
+            message: format!("Invalid service URL {:?}: {}", service_url, e),
+        })?;
+        Ok(Self {
+            auth_server_url: auth_server_url.to_string(),
+            login_url,
+            current,
+            redirects: Vec::new(),
+            ticket: None,
+        })
+    }
+
+    /// The URL to request next
+    pub fn url(&self) -> &Url {
+        &self.current
+    }
+
+    /// Move on to the next hop if the response to [`url`](Self::url)
+    /// redirects. `false` once the response is the final one.
+    pub fn follow(&mut self, status: StatusCode, headers: &HeaderMap) -> Result<bool> {
+        if !status.is_redirection() {
+            return Ok(false);
+        }
+        let Some(next) = redirect_target(&self.current, headers) else {
+            return Ok(false);
+        };
+        if self.redirects.len() >= MAX_SERVICE_REDIRECTS {
+            return Err(UestcClientError::TooManyRedirects {
+                url: self.login_url.clone(),
+                limit: MAX_SERVICE_REDIRECTS,
+            });
+        }
+
+        if self.ticket.is_none() {
+            self.ticket = extract_ticket(&next);
+            if self.ticket.is_some() {
+                log::debug!("Received service ticket for {}", next.host_str().unwrap_or(""));
+            }
+        }
+        log::debug!("Following redirect to: {}", next);
+        self.redirects.push(next.clone());
+        self.current = next;
+        Ok(true)
+    }
+
+    /// The outcome, given the final response and where it came from.
+    /// `None` if CAS asked for credentials, i.e. there is no CAS session.
+    pub fn finish<R>(self, response: R, landing_url: &Url, status: StatusCode) -> Option<ServiceLogin<R>> {
+        let Some(validation) =
+            validation_outcome(landing_url, status, self.ticket.is_some(), &self.auth_server_url)
+        else {
+            log::info!("No active CAS session, cannot log in to service");
+            return None;
+        };
+
+        log::info!(
+            "Service login finished (status: {}, validation: {:?})",
+            status,
+            validation
+        );
+        Some(ServiceLogin {
+            response,
+            ticket: self.ticket,
+            redirects: self.redirects,
+            validation,
+        })
+    }
+}
+
+/// Build the CAS login URL that issues a service ticket for `service_url`
+pub fn build_service_login_url(auth_server_url: &str, service_url: &str) -> String {
+    format!(
+        "{}/login?service={}",
+        auth_server_url,
+        urlencoding::encode(service_url)
+    )
+}
+
+/// Resolve the `Location` header of a redirect response against the request URL
+pub fn redirect_target(current: &Url, headers: &HeaderMap) -> Option<Url> {
+    let location = headers.get(LOCATION)?.to_str().ok()?;
+    match current.join(location) {
+        Ok(url) => Some(url),
+        Err(e) => {
+            log::warn!("Invalid redirect location {:?}: {}", location, e);
+            None
+        }
+    }
+}
+
+/// Extract the CAS service ticket (`ticket=ST-...`) from a URL
+pub fn extract_ticket(url: &Url) -> Option<String> {
+    url.query_pairs()
+        .find(|(k, _)| k == "ticket")
+        .map(|(_, v)| v.into_owned())
+        .filter(|t| !t.is_empty())
+}
+
+/// Whether `url` points at the CAS login page of `auth_server_url`
+pub fn is_login_page(url: &Url, auth_server_url: &str) -> bool {
+    let login_url = format!("{}/login", auth_server_url);
+    url.as_str().starts_with(&login_url)
+}
+
+/// Whether a response landed on, or redirects to, the CAS login page
+pub fn is_login_redirect(
+    url: &Url,
+    status: StatusCode,
+    headers: &HeaderMap,
+    auth_server_url: &str,
+) -> bool {
+    if is_login_page(url, auth_server_url) {
+        return true;
+    }
+    status.is_redirection()
+        && redirect_target(url, headers).is_some_and(|target| is_login_page(&target, auth_server_url))
+}
+
+/// Decide the ticket validation outcome from the walked redirect chain.
+///
+/// Returns `None` when the flow ended on the CAS login page without issuing a
+/// ticket, i.e. there is no CAS session to log in with.
+pub fn validation_outcome(
+    landing_url: &Url,
+    status: StatusCode,
+    ticket_issued: bool,
+    auth_server_url: &str,
+) -> Option<TicketValidation> {
+    let on_login_page = is_login_page(landing_url, auth_server_url);
+
+    if !ticket_issued {
+        if on_login_page {
+            return None;
+        }
+        return Some(TicketValidation::NotObserved);
+    }
+
+    if on_login_page || status.is_client_error() || status.is_server_error() {
+        Some(TicketValidation::Rejected { status })
+    } else {
+        Some(TicketValidation::Accepted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTH: &str = "https://idas.uestc.edu.cn/authserver";
+
+    #[test]
+    fn test_build_service_login_url() {
+        let url = build_service_login_url(AUTH, "https://eportal.uestc.edu.cn/new/index.html?a=1");
+        assert_eq!(
+            url,
+            "https://idas.uestc.edu.cn/authserver/login?service=https%3A%2F%2Feportal.uestc.edu.cn%2Fnew%2Findex.html%3Fa%3D1"
+        );
+    }
+
+    #[test]
+    fn test_redirect_target_and_ticket() {
+        let current = Url::parse("https://idas.uestc.edu.cn/authserver/login?service=x").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LOCATION,
+            "https://online.uestc.edu.cn/common/actionCasLogin?ticket=ST-123-abc"
+                .parse()
+                .unwrap(),
+        );
+        let next = redirect_target(&current, &headers).unwrap();
+        assert_eq!(next.host_str(), Some("online.uestc.edu.cn"));
+        assert_eq!(extract_ticket(&next).as_deref(), Some("ST-123-abc"));
+
+        headers.insert(LOCATION, "/page/".parse().unwrap());
+        let relative = redirect_target(&next, &headers).unwrap();
+        assert_eq!(relative.as_str(), "https://online.uestc.edu.cn/page/");
+        assert_eq!(extract_ticket(&relative), None);
+    }
+
+    #[test]
+    fn test_is_login_redirect() {
+        let page = Url::parse("https://online.uestc.edu.cn/site/bedroom").unwrap();
+        let mut headers = HeaderMap::new();
+        assert!(!is_login_redirect(&page, StatusCode::OK, &headers, AUTH));
+
+        headers.insert(
+            LOCATION,
+            "https://idas.uestc.edu.cn/authserver/login?service=https%3A%2F%2Fonline.uestc.edu.cn%2F"
+                .parse()
+                .unwrap(),
+        );
+        assert!(is_login_redirect(&page, StatusCode::FOUND, &headers, AUTH));
+
+        let login = Url::parse("https://idas.uestc.edu.cn/authserver/login?service=x").unwrap();
+        assert!(is_login_redirect(&login, StatusCode::OK, &HeaderMap::new(), AUTH));
+    }
+
+    #[test]
+    fn test_validation_outcome() {
+        let landing = Url::parse("https://online.uestc.edu.cn/page/").unwrap();
+        let login = Url::parse("https://idas.uestc.edu.cn/authserver/login?service=x").unwrap();
+
+        assert_eq!(
+            validation_outcome(&landing, StatusCode::OK, true, AUTH),
+            Some(TicketValidation::Accepted)
+        );
+        assert_eq!(
+            validation_outcome(&landing, StatusCode::FORBIDDEN, true, AUTH),
+            Some(TicketValidation::Rejected {
+                status: StatusCode::FORBIDDEN
+            })
+        );
+        assert_eq!(
+            validation_outcome(&landing, StatusCode::OK, false, AUTH),
+            Some(TicketValidation::NotObserved)
+        );
+        assert_eq!(validation_outcome(&login, StatusCode::OK, false, AUTH), None);
+    }
+
+    #[test]
+    fn test_service_redirects() {
+        let mut walk = ServiceRedirects::new(AUTH, "https://online.uestc.edu.cn/page/").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LOCATION,
+            "https://online.uestc.edu.cn/common/actionCasLogin?ticket=ST-123-abc"
+                .parse()
+                .unwrap(),
+        );
+        assert!(walk.follow(StatusCode::FOUND, &headers).unwrap());
+        assert_eq!(walk.url().host_str(), Some("online.uestc.edu.cn"));
+        headers.insert(LOCATION, "/page/".parse().unwrap());
+        assert!(walk.follow(StatusCode::FOUND, &headers).unwrap());
+        assert!(!walk.follow(StatusCode::OK, &headers).unwrap());
+
+        let landing = walk.url().clone();
+        let login = walk.finish((), &landing, StatusCode::OK).unwrap();
+        assert_eq!(login.ticket.as_deref(), Some("ST-123-abc"));
+        assert_eq!(login.redirects.len(), 2);
+        assert_eq!(login.validation, TicketValidation::Accepted);
+    }
+}
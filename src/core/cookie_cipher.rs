@@ -0,0 +1,338 @@
+//! AES-256-GCM encryption of the cookie file at rest.
+//!
+//! The encrypted file is a JSON envelope recording how the key was derived,
+//! so it can be told apart from the plaintext format and opened again.
+
+use crate::{Result, UestcClientError};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+const FORMAT: &str = "uestc-client/encrypted-cookies";
+const VERSION: u32 = 1;
+const CIPHER: &str = "aes-256-gcm";
+const PBKDF2_ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Secret the file key is derived from
+#[derive(Clone, Copy)]
+pub enum KeySource<'a> {
+    /// Stretched with PBKDF2-HMAC-SHA256 and a random salt
+    Passphrase(&'a str),
+    /// Contents of a key file, hashed with SHA-256
+    KeyMaterial(&'a [u8]),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "name", rename_all = "kebab-case")]
+enum Kdf {
+    Pbkdf2Sha256 { iterations: u32, salt: String },
+    Sha256,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope {
+    format: String,
+    version: u32,
+    cipher: String,
+    kdf: Kdf,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn crypto_error(operation: &str, message: impl Into<String>) -> UestcClientError {
+    UestcClientError::CookieError {
+        operation: operation.to_string(),
+        file_path: None,
+        message: message.into(),
+        source: None,
+    }
+}
+
+/// Keys derived from one passphrase, by salt.
+///
+/// PBKDF2 is slow on purpose, so whoever seals and opens with the same
+/// passphrase keeps one cache and derives each salt's key only once.
+/// Sealing reuses the salt last used rather than drawing a new one.
+#[derive(Default)]
+pub struct KeyCache {
+    inner: Mutex<CachedKeys>,
+}
+
+#[derive(Default)]
+struct CachedKeys {
+    keys: HashMap<Kdf, Zeroizing<[u8; 32]>>,
+    last: Option<Kdf>,
+}
+
+impl fmt::Debug for KeyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyCache")
+            .field("keys", &self.inner.lock().unwrap().keys.len())
+            .finish()
+    }
+}
+
+impl KeyCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The key for `kdf`, deriving it unless a passphrase key is cached
+    fn key(&self, source: KeySource<'_>, kdf: &Kdf) -> Result<Zeroizing<[u8; 32]>> {
+        if !matches!(kdf, Kdf::Pbkdf2Sha256 { .. }) {
+            return derive_key(source, kdf);
+        }
+        let mut cached = self.inner.lock().unwrap();
+        let key = match cached.keys.get(kdf) {
+            Some(key) => key.clone(),
+            None => {
+                let key = derive_key(source, kdf)?;
+                cached.keys.insert(kdf.clone(), key.clone());
+                key
+            }
+        };
+        cached.last = Some(kdf.clone());
+        Ok(key)
+    }
+
+    /// The salt last used with `iterations`, if any
+    fn last_salt(&self, iterations: u32) -> Option<String> {
+        match &self.inner.lock().unwrap().last {
+            Some(Kdf::Pbkdf2Sha256 {
+                iterations: last,
+                salt,
+            }) if *last == iterations => Some(salt.clone()),
+            _ => None,
+        }
+    }
+}
+
+fn derive_key(source: KeySource<'_>, kdf: &Kdf) -> Result<Zeroizing<[u8; 32]>> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    match (source, kdf) {
+        (KeySource::Passphrase(passphrase), Kdf::Pbkdf2Sha256 { iterations, salt }) => {
+            let salt = decode("salt", salt)?;
+            pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), &salt, *iterations, &mut *key);
+        }
+        (KeySource::KeyMaterial(material), Kdf::Sha256) => {
+            key.copy_from_slice(&Sha256::digest(material));
+        }
+        (KeySource::Passphrase(_), Kdf::Sha256) => {
+            return Err(crypto_error(
+                "decrypt",
+                "Cookie file was encrypted with a key file, not a passphrase",
+            ));
+        }
+        (KeySource::KeyMaterial(_), Kdf::Pbkdf2Sha256 { .. }) => {
+            return Err(crypto_error(
+                "decrypt",
+                "Cookie file was encrypted with a passphrase, not a key file",
+            ));
+        }
+    }
+    Ok(key)
+}
+
+fn decode(field: &str, value: &str) -> Result<Vec<u8>> {
+    STANDARD.decode(value).map_err(|e| {
+        crypto_error(
+            "decrypt",
+            format!("Invalid {} in encrypted cookie file: {}", field, e),
+        )
+    })
+}
+
+/// Whether `content` is an encrypted cookie file rather than plaintext cookies
+pub fn is_encrypted(content: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(content)
+        .ok()
+        .and_then(|value| {
+            value
+                .get("format")
+                .and_then(|f| f.as_str())
+                .map(|f| f == FORMAT)
+        })
+        .unwrap_or(false)
+}
+
+/// Encrypt `plaintext` into a JSON envelope
+pub fn seal(plaintext: &[u8], source: KeySource<'_>, keys: &KeyCache) -> Result<String> {
+    seal_with_iterations(plaintext, source, keys, PBKDF2_ITERATIONS)
+}
+
+fn seal_with_iterations(
+    plaintext: &[u8],
+    source: KeySource<'_>,
+    keys: &KeyCache,
+    iterations: u32,
+) -> Result<String> {
+    let mut rng = rand::rng();
+    let kdf = match source {
+        KeySource::Passphrase(_) => {
+            let salt = keys.last_salt(iterations).unwrap_or_else(|| {
+                let mut salt = [0u8; SALT_LEN];
+                rng.fill(&mut salt);
+                STANDARD.encode(salt)
+            });
+            Kdf::Pbkdf2Sha256 { iterations, salt }
+        }
+        KeySource::KeyMaterial(_) => Kdf::Sha256,
+    };
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce);
+
+    let key = keys.key(source, &kdf)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&*key));
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: FORMAT.as_bytes(),
+            },
+        )
+        .map_err(|_| crypto_error("encrypt", "Failed to encrypt cookies"))?;
+
+    let envelope = Envelope {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        cipher: CIPHER.to_string(),
+        kdf,
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    };
+    serde_json::to_string_pretty(&envelope).map_err(|e| {
+        crypto_error(
+            "encrypt",
+            format!("Failed to serialize encrypted cookies: {}", e),
+        )
+    })
+}
+
+/// Decrypt a JSON envelope produced by [`seal`]
+pub fn open(content: &str, source: KeySource<'_>, keys: &KeyCache) -> Result<Vec<u8>> {
+    let envelope: Envelope = serde_json::from_str(content)
+        .map_err(|e| crypto_error("decrypt", format!("Invalid encrypted cookie file: {}", e)))?;
+    if envelope.format != FORMAT || envelope.version != VERSION || envelope.cipher != CIPHER {
+        return Err(crypto_error(
+            "decrypt",
+            format!(
+                "Unsupported encrypted cookie file (version {}, cipher {})",
+                envelope.version, envelope.cipher
+            ),
+        ));
+    }
+
+    let nonce = decode("nonce", &envelope.nonce)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(crypto_error(
+            "decrypt",
+            "Invalid nonce length in encrypted cookie file",
+        ));
+    }
+    let ciphertext = decode("ciphertext", &envelope.ciphertext)?;
+
+    let key = keys.key(source, &envelope.kdf)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&*key));
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: FORMAT.as_bytes(),
+            },
+        )
+        .map_err(|_| {
+            crypto_error(
+                "decrypt",
+                "Failed to decrypt cookie file: wrong key or the file was modified",
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAINTEXT: &[u8] = br#"[{"name":"CASTGC","value":"TGT-1"}]"#;
+
+    #[test]
+    fn test_key_file_round_trip() {
+        let sealed = seal(PLAINTEXT, KeySource::KeyMaterial(b"0123456789abcdef"), &KeyCache::new()).unwrap();
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.contains("TGT-1"));
+
+        let opened = open(&sealed, KeySource::KeyMaterial(b"0123456789abcdef"), &KeyCache::new()).unwrap();
+        assert_eq!(opened, PLAINTEXT);
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let sealed = seal(PLAINTEXT, KeySource::KeyMaterial(b"right"), &KeyCache::new()).unwrap();
+        let err = open(&sealed, KeySource::KeyMaterial(b"wrong"), &KeyCache::new()).unwrap_err();
+        assert!(matches!(
+            err,
+            UestcClientError::CookieError { ref operation, .. } if operation == "decrypt"
+        ));
+    }
+
+    #[test]
+    fn test_key_kind_mismatch() {
+        let sealed = seal(PLAINTEXT, KeySource::KeyMaterial(b"key"), &KeyCache::new()).unwrap();
+        let err = open(&sealed, KeySource::Passphrase("key"), &KeyCache::new()).unwrap_err();
+        assert!(err.to_string().contains("key file"));
+    }
+
+    #[test]
+    fn test_passphrase_round_trip() {
+        // The iteration count is read back from the envelope
+        let sealed =
+            seal_with_iterations(
+            PLAINTEXT,
+            KeySource::Passphrase("correct horse"),
+            &KeyCache::new(),
+            1_000,
+        ).unwrap();
+        let opened = open(&sealed, KeySource::Passphrase("correct horse"), &KeyCache::new()).unwrap();
+        assert_eq!(opened, PLAINTEXT);
+        assert!(open(&sealed, KeySource::Passphrase("battery staple"), &KeyCache::new()).is_err());
+    }
+
+    #[test]
+    fn test_key_cache_reuses_salt() {
+        let keys = KeyCache::new();
+        let passphrase = KeySource::Passphrase("correct horse");
+        let first = seal_with_iterations(PLAINTEXT, passphrase, &keys, 1_000).unwrap();
+        let second = seal_with_iterations(PLAINTEXT, passphrase, &keys, 1_000).unwrap();
+        let salt = |sealed: &str| match serde_json::from_str::<Envelope>(sealed).unwrap().kdf {
+            Kdf::Pbkdf2Sha256 { salt, .. } => salt,
+            Kdf::Sha256 => unreachable!(),
+        };
+        assert_eq!(salt(&first), salt(&second));
+        assert_ne!(first, second);
+        assert_eq!(keys.inner.lock().unwrap().keys.len(), 1);
+
+        // A file sealed elsewhere adds its salt, which later seals reuse
+        let other = seal_with_iterations(PLAINTEXT, passphrase, &KeyCache::new(), 1_000).unwrap();
+        assert_eq!(open(&other, passphrase, &keys).unwrap(), PLAINTEXT);
+        assert_eq!(keys.inner.lock().unwrap().keys.len(), 2);
+        let third = seal_with_iterations(PLAINTEXT, passphrase, &keys, 1_000).unwrap();
+        assert_eq!(salt(&third), salt(&other));
+    }
+
+    #[test]
+    fn test_plaintext_is_not_encrypted() {
+        assert!(!is_encrypted(r#"[{"name":"CASTGC","value":"TGT-1"}]"#));
+        assert!(!is_encrypted("not json"));
+    }
+}
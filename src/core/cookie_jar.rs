@@ -0,0 +1,413 @@
+//! On-disk format of the cookie file.
+//!
+//! Version 2 is an object `{"version": 2, "cookies": [...]}` that keeps each
+//! cookie's absolute expiry, host-only flag, path and SameSite as the cookie
+//! store holds them. Version 1 was a bare array that dropped expiry and scope;
+//! it is still read so existing files keep working, and is rewritten as
+//! version 2 on the next save.
+
+use crate::core::log;
+use crate::{Result, UestcClientError};
+use cookie::time::OffsetDateTime;
+use cookie::{Cookie as RawCookie, SameSite};
+use cookie_store::{Cookie, CookieDomain, CookieExpiration, CookieStore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use url::Url;
+
+pub const FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PersistedCookie {
+    pub name: String,
+    pub value: String,
+    /// Host for host-only cookies, otherwise the Domain attribute
+    pub domain: String,
+    pub host_only: bool,
+    pub path: String,
+    /// Unix timestamp in seconds, `None` for session cookies
+    pub expires: Option<i64>,
+    pub secure: bool,
+    pub http_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub same_site: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CookieFileV2 {
+    version: u32,
+    cookies: Vec<PersistedCookie>,
+}
+
+/// Version 1 entry, only read for migration
+#[derive(Deserialize, Debug)]
+struct LegacyCookie {
+    name: String,
+    value: String,
+    domain: String,
+    path: String,
+    expires: Option<i64>,
+    secure: bool,
+    http_only: bool,
+}
+
+fn format_error(operation: &str, message: String) -> UestcClientError {
+    UestcClientError::CookieError {
+        operation: operation.to_string(),
+        file_path: None,
+        message,
+        source: None,
+    }
+}
+
+impl PersistedCookie {
+    /// Capture a cookie from the store, `None` if it has no usable domain
+    pub fn from_cookie(cookie: &Cookie<'_>) -> Option<Self> {
+        let (domain, host_only) = match &cookie.domain {
+            CookieDomain::HostOnly(host) => (host.clone(), true),
+            CookieDomain::Suffix(domain) => (domain.clone(), false),
+            CookieDomain::NotPresent | CookieDomain::Empty => return None,
+        };
+        let expires = match cookie.expires {
+            CookieExpiration::AtUtc(at) => Some(at.unix_timestamp()),
+            CookieExpiration::SessionEnd => None,
+        };
+        let same_site = cookie.same_site().map(|s| match s {
+            SameSite::Strict => "Strict".to_string(),
+            SameSite::Lax => "Lax".to_string(),
+            SameSite::None => "None".to_string(),
+        });
+
+        Some(Self {
+            name: cookie.name().to_string(),
+            value: cookie.value().to_string(),
+            domain,
+            host_only,
+            path: cookie.path.as_ref().to_string(),
+            expires,
+            secure: cookie.secure().unwrap_or(false),
+            http_only: cookie.http_only().unwrap_or(false),
+            same_site,
+        })
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Rebuild the cookie exactly as it was captured
+    pub fn to_cookie(&self) -> Option<Cookie<'static>> {
+        let mut raw = RawCookie::build((self.name.clone(), self.value.clone()))
+            .path(self.path.clone())
+            .secure(self.secure)
+            .http_only(self.http_only);
+        // A Domain attribute makes the cookie apply to subdomains, so only
+        // cookies that had one get it back
+        if !self.host_only {
+            raw = raw.domain(self.domain.clone());
+        }
+        if let Some(expires) = self.expires {
+            raw = raw.expires(OffsetDateTime::from_unix_timestamp(expires).ok()?);
+        }
+        match self.same_site.as_deref() {
+            Some("Strict") => raw = raw.same_site(SameSite::Strict),
+            Some("Lax") => raw = raw.same_site(SameSite::Lax),
+            Some("None") => raw = raw.same_site(SameSite::None),
+            _ => {}
+        }
+
+        let url = self.origin()?;
+        Cookie::try_from_raw_cookie(&raw.build(), &url)
+            .ok()
+            .map(Cookie::into_owned)
+    }
+
+    /// A URL the cookie could have been set from
+    pub fn origin(&self) -> Option<Url> {
+        Url::parse(&format!("https://{}{}", self.domain, self.path)).ok()
+    }
+}
+
+/// Serialize cookies in the current format
+pub fn serialize_cookies(cookies: Vec<PersistedCookie>) -> Result<String> {
+    let file = CookieFileV2 {
+        version: FORMAT_VERSION,
+        cookies,
+    };
+    serde_json::to_string_pretty(&file)
+        .map_err(|e| format_error("serialize", format!("Failed to serialize cookies: {}", e)))
+}
+
+/// Capture every unexpired cookie in `store`
+pub fn snapshot(store: &CookieStore) -> Vec<PersistedCookie> {
+    store.iter_unexpired().filter_map(PersistedCookie::from_cookie).collect()
+}
+
+/// Drop cookies that have already expired
+pub fn unexpired(cookies: Vec<PersistedCookie>) -> Vec<PersistedCookie> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    cookies
+        .into_iter()
+        .filter(|cookie| {
+            let expired = cookie.is_expired(now);
+            if expired {
+                log::debug!("丢弃已过期的 cookie: {}", cookie.name);
+            }
+            !expired
+        })
+        .collect()
+}
+
+pub fn into_store(cookies: &[PersistedCookie]) -> CookieStore {
+    let mut store = CookieStore::default();
+    for cookie in cookies {
+        insert(&mut store, cookie);
+    }
+    store
+}
+
+/// Insert a persisted cookie into `store`, logging cookies that do not fit
+pub fn insert(store: &mut CookieStore, cookie: &PersistedCookie) {
+    let (Some(restored), Some(url)) = (cookie.to_cookie(), cookie.origin()) else {
+        log::debug!("无法恢复 cookie: {} ({})", cookie.name, cookie.domain);
+        return;
+    };
+    if let Err(e) = store.insert(restored, &url) {
+        log::debug!("插入 cookie 失败: {:?}", e);
+    }
+}
+
+/// Parse either format into persisted cookies, including expired ones
+pub fn deserialize_cookies(json: &str) -> Result<Vec<PersistedCookie>> {
+    let value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| format_error("deserialize", format!("Failed to deserialize cookies: {}", e)))?;
+
+    if value.is_array() {
+        log::info!("读取旧版 cookie 文件格式，下次保存时将升级");
+        let legacy: Vec<LegacyCookie> = serde_json::from_value(value).map_err(|e| {
+            format_error("deserialize", format!("Failed to deserialize cookies: {}", e))
+        })?;
+        return Ok(legacy.into_iter().filter_map(migrate_legacy).collect());
+    }
+
+    let version = value.get("version").and_then(|v| v.as_u64());
+    if version != Some(u64::from(FORMAT_VERSION)) {
+        return Err(format_error(
+            "deserialize",
+            format!("Unsupported cookie file version: {:?}", version),
+        ));
+    }
+    let file: CookieFileV2 = serde_json::from_value(value)
+        .map_err(|e| format_error("deserialize", format!("Failed to deserialize cookies: {}", e)))?;
+    Ok(file.cookies)
+}
+
+/// Version 1 files lost scope and expiry, so restore them the way they were
+/// always loaded: as domain cookies for the rest of the session
+fn migrate_legacy(legacy: LegacyCookie) -> Option<PersistedCookie> {
+    if legacy.domain.is_empty() {
+        log::debug!("跳过空 domain 的 cookie: {}", legacy.name);
+        return None;
+    }
+    let expires = legacy
+        .expires
+        .map(|max_age| OffsetDateTime::now_utc().unix_timestamp() + max_age);
+
+    Some(PersistedCookie {
+        name: legacy.name,
+        value: legacy.value,
+        domain: legacy.domain.trim_start_matches('.').to_string(),
+        host_only: false,
+        path: legacy.path,
+        expires,
+        secure: legacy.secure,
+        http_only: legacy.http_only,
+        same_site: None,
+    })
+}
+
+type CookieKey<'a> = (&'a str, &'a str, &'a str);
+
+fn identity(cookie: &PersistedCookie) -> CookieKey<'_> {
+    (&cookie.domain, &cookie.path, &cookie.name)
+}
+
+/// Three-way merge of the cookie file, as cookie stores identify cookies by
+/// domain, path and name.
+///
+/// `base` is what this process last loaded or saved, `ours` what it holds
+/// now and `theirs` what is on disk now. Cookies this process changed,
+/// added or removed since `base` win; for all others the disk copy wins, so
+/// a process holding stale cookies does not overwrite fresher ones written
+/// by another process.
+pub fn merge(
+    base: &[PersistedCookie],
+    ours: &[PersistedCookie],
+    theirs: &[PersistedCookie],
+) -> Vec<PersistedCookie> {
+    let base: HashMap<_, _> = base.iter().map(|c| (identity(c), c)).collect();
+    let ours_by_key: HashMap<_, _> = ours.iter().map(|c| (identity(c), c)).collect();
+    let theirs_by_key: HashMap<_, _> = theirs.iter().map(|c| (identity(c), c)).collect();
+
+    let keys = ours
+        .iter()
+        .map(identity)
+        .chain(theirs.iter().map(identity).filter(|k| !ours_by_key.contains_key(k)));
+
+    keys.filter_map(|key| {
+        let ours = ours_by_key.get(&key).copied();
+        if ours != base.get(&key).copied() {
+            ours
+        } else {
+            theirs_by_key.get(&key).copied()
+        }
+    })
+    .cloned()
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialize(store: &CookieStore) -> Result<String> {
+        serialize_cookies(snapshot(store))
+    }
+
+    fn deserialize(json: &str) -> Result<CookieStore> {
+        Ok(into_store(&unexpired(deserialize_cookies(json)?)))
+    }
+
+    fn store_from(set_cookies: &[&str], url: &str) -> CookieStore {
+        let url = Url::parse(url).unwrap();
+        let mut store = CookieStore::default();
+        for set_cookie in set_cookies {
+            store
+                .insert_raw(&set_cookie.parse::<RawCookie>().unwrap(), &url)
+                .unwrap();
+        }
+        store
+    }
+
+    fn matching<'a>(store: &'a CookieStore, url: &str) -> Vec<&'a str> {
+        let url = Url::parse(url).unwrap();
+        let mut names: Vec<_> = store.matches(&url).iter().map(|c| c.name()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_round_trip_keeps_scope_and_expiry() {
+        let store = store_from(
+            &[
+                "CASTGC=TGT-1; Path=/authserver; Secure; HttpOnly",
+                "route=abc",
+                "shared=1; Domain=uestc.edu.cn; Path=/; Max-Age=3600; SameSite=Lax",
+            ],
+            "https://idas.uestc.edu.cn/authserver/login",
+        );
+
+        let restored = deserialize(&serialize(&store).unwrap()).unwrap();
+        assert_eq!(
+            matching(&restored, "https://idas.uestc.edu.cn/authserver/login"),
+            vec!["CASTGC", "route", "shared"]
+        );
+        // Host-only cookies stay on their host, domain cookies are shared
+        assert_eq!(matching(&restored, "https://eams.uestc.edu.cn/authserver/x"), vec!["shared"]);
+        assert!(matching(&restored, "https://idas.uestc.edu.cn/other").contains(&"shared"));
+
+        let original = store.get("uestc.edu.cn", "/", "shared").unwrap();
+        let shared = restored.get("uestc.edu.cn", "/", "shared").unwrap();
+        assert_eq!(shared.expires, original.expires);
+        assert_eq!(shared.same_site(), Some(SameSite::Lax));
+        let tgc = restored.get("idas.uestc.edu.cn", "/authserver", "CASTGC").unwrap();
+        assert_eq!(tgc.expires, CookieExpiration::SessionEnd);
+        assert_eq!(tgc.http_only(), Some(true));
+    }
+
+    #[test]
+    fn test_expired_cookies_dropped() {
+        let json = serialize_cookies(vec![PersistedCookie {
+            name: "old".to_string(),
+            value: "1".to_string(),
+            domain: "idas.uestc.edu.cn".to_string(),
+            host_only: true,
+            path: "/".to_string(),
+            expires: Some(1_000_000),
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }])
+        .unwrap();
+
+        assert_eq!(deserialize_cookies(&json).unwrap().len(), 1);
+        assert_eq!(deserialize(&json).unwrap().iter_any().count(), 0);
+    }
+
+    #[test]
+    fn test_migrate_v1() {
+        let json = r#"[
+            {"name": "CASTGC", "value": "TGT-1", "domain": "idas.uestc.edu.cn",
+             "path": "/authserver", "expires": null, "secure": true, "http_only": true},
+            {"name": "bad", "value": "1", "domain": "", "path": "/",
+             "expires": null, "secure": false, "http_only": false}
+        ]"#;
+
+        let store = deserialize(json).unwrap();
+        assert_eq!(matching(&store, "https://idas.uestc.edu.cn/authserver/login"), vec!["CASTGC"]);
+
+        let upgraded = serialize(&store).unwrap();
+        assert!(upgraded.contains("\"version\": 2"));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let result = deserialize(r#"{"version": 99, "cookies": []}"#);
+        assert!(matches!(result, Err(UestcClientError::CookieError { .. })));
+    }
+
+    fn persisted(name: &str, value: &str) -> PersistedCookie {
+        PersistedCookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: "idas.uestc.edu.cn".to_string(),
+            host_only: true,
+            path: "/authserver".to_string(),
+            expires: None,
+            secure: true,
+            http_only: true,
+            same_site: None,
+        }
+    }
+
+    fn values(cookies: &[PersistedCookie]) -> Vec<(&str, &str)> {
+        let mut values: Vec<_> = cookies.iter().map(|c| (c.name.as_str(), c.value.as_str())).collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn test_merge_keeps_fresher_disk_cookies() {
+        let base = vec![persisted("CASTGC", "old"), persisted("route", "a")];
+        // We still hold the old ticket but picked up a new cookie
+        let ours = vec![persisted("CASTGC", "old"), persisted("route", "a"), persisted("JSESSIONID", "x")];
+        // Another process logged in again and dropped `route`
+        let theirs = vec![persisted("CASTGC", "new"), persisted("MOD_AUTH_CAS", "y")];
+
+        let merged = merge(&base, &ours, &theirs);
+        assert_eq!(
+            values(&merged),
+            vec![("CASTGC", "new"), ("JSESSIONID", "x"), ("MOD_AUTH_CAS", "y")]
+        );
+    }
+
+    #[test]
+    fn test_merge_keeps_our_changes() {
+        let base = vec![persisted("CASTGC", "old"), persisted("route", "a")];
+        let ours = vec![persisted("CASTGC", "mine")];
+        let theirs = vec![persisted("CASTGC", "old"), persisted("route", "a")];
+
+        let merged = merge(&base, &ours, &theirs);
+        assert_eq!(values(&merged), vec![("CASTGC", "mine")]);
+    }
+}
@@ -1,8 +1,10 @@
+use crate::core::log;
 use crate::{Result, UestcClientError};
 use aes::cipher::generic_array::GenericArray;
 use aes::cipher::{BlockEncrypt, KeyInit};
 use aes::{Aes128, Aes192, Aes256};
 use rand::Rng;
+use zeroize::Zeroizing;
 
 const AES_CHARS: &[u8] = b"ABCDEFGHJKMNPQRSTWXYZabcdefhijkmnprstwxyz2345678";
 
@@ -30,13 +32,13 @@ pub fn encrypt_password(password: &str, pwd_encrypt_salt: &str) -> Result<String
     let iv = iv_str.as_bytes();
 
     let prefix = random_string(64);
-    let plaintext = format!("{}{}", prefix, password);
+    let plaintext = Zeroizing::new(format!("{}{}", prefix, password));
     let plaintext_bytes = plaintext.as_bytes();
 
     // PKCS7 Padding
     let padding_len = 16 - (plaintext_bytes.len() % 16);
-    let mut padded_input = plaintext_bytes.to_vec();
-    padded_input.extend(std::iter::repeat(padding_len as u8).take(padding_len));
+    let mut padded_input = Zeroizing::new(plaintext_bytes.to_vec());
+    padded_input.extend(std::iter::repeat_n(padding_len as u8, padding_len));
 
     let mut ciphertext = Vec::with_capacity(padded_input.len());
     let mut current_iv = GenericArray::clone_from_slice(iv);
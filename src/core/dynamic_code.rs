@@ -0,0 +1,197 @@
+use crate::core::log;
+use crate::core::secret::Secret;
+use crate::{Result, UestcClientError};
+use scraper::{Html, Selector};
+use serde::Deserialize;
+use std::collections::HashMap;
+use url::Url;
+
+#[derive(Deserialize, Debug)]
+struct SendCodeResponse {
+    res: String,
+    #[serde(rename = "returnMessage", default)]
+    return_message: Option<String>,
+    #[serde(rename = "codeTime", default)]
+    code_time: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReauthSubmitResponse {
+    code: String,
+    #[serde(default)]
+    msg: Option<String>,
+}
+
+pub fn build_send_code_url(auth_server_url: &str) -> String {
+    format!("{}/dynamicCode/getDynamicCode.htl", auth_server_url)
+}
+
+pub fn build_reauth_send_code_url(auth_server_url: &str) -> String {
+    format!("{}/dynamicCode/getDynamicCodeByReauth.do", auth_server_url)
+}
+
+pub fn build_reauth_submit_url(auth_server_url: &str) -> String {
+    format!("{}/reAuthCheck/reAuthSubmit.do", auth_server_url)
+}
+
+/// Form fields for requesting a reauth code for `username`
+pub fn reauth_send_code_form(username: &str) -> HashMap<String, String> {
+    HashMap::from([
+        ("userName".to_string(), username.to_string()),
+        (
+            "authCodeTypeName".to_string(),
+            "reAuthDynamicCodeType".to_string(),
+        ),
+    ])
+}
+
+/// Form fields for submitting a reauth code received by SMS
+pub fn reauth_submit_form(reauth_url: &str, code: &Secret) -> HashMap<String, Secret> {
+    let service = Url::parse(reauth_url)
+        .ok()
+        .and_then(|url| {
+            url.query_pairs()
+                .find(|(k, _)| k == "service")
+                .map(|(_, v)| v.into_owned())
+        })
+        .unwrap_or_default();
+
+    HashMap::from([
+        ("service".to_string(), service.into()),
+        ("reAuthType".to_string(), "3".into()),
+        ("isMultifactor".to_string(), "true".into()),
+        ("password".to_string(), Secret::default()),
+        ("dynamicCode".to_string(), Secret::new(code.expose().trim())),
+        ("uuid".to_string(), Secret::default()),
+        ("answer1".to_string(), Secret::default()),
+        ("answer2".to_string(), Secret::default()),
+        ("otpCode".to_string(), Secret::default()),
+        ("skipTmpReAuth".to_string(), "true".into()),
+    ])
+}
+
+/// Parse the hidden fields of the dynamic-code login form
+pub fn parse_dynamic_login_form(html: &str) -> Result<HashMap<String, String>> {
+    let document = Html::parse_document(html);
+    let selector = Selector::parse("div#phoneLoginDiv input, form#phoneFromId input").map_err(|e| {
+        UestcClientError::HtmlParseError {
+            message: format!("Failed to parse dynamic login selector: {:?}", e),
+            source: None,
+        }
+    })?;
+
+    let mut form_data = HashMap::new();
+    for element in document.select(&selector) {
+        let input = element.value();
+        let Some(key) = input.attr("name").or_else(|| input.attr("id")) else {
+            continue;
+        };
+        if let Some(value) = input.attr("value") {
+            form_data.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    if !form_data.contains_key("execution") {
+        log::error!("Failed to find 'execution' field in dynamic login form");
+        return Err(UestcClientError::HtmlParseError {
+            message: "Failed to find dynamic-code login form in login page".to_string(),
+            source: None,
+        });
+    }
+
+    log::debug!("Found {} fields in dynamic login form", form_data.len());
+    Ok(form_data)
+}
+
+/// Parse the response to a code request, returning the resend cooldown in seconds
+pub fn parse_send_code_response(text: &str) -> Result<Option<u64>> {
+    let resp: SendCodeResponse =
+        serde_json::from_str(text.trim()).map_err(|e| UestcClientError::VerificationFailed {
+            message: format!("Failed to parse dynamic code response: {}", e),
+        })?;
+
+    if resp.res != "success" {
+        return Err(UestcClientError::VerificationFailed {
+            message: resp
+                .return_message
+                .filter(|m| !m.is_empty())
+                .unwrap_or_else(|| format!("Failed to send dynamic code: {}", resp.res)),
+        });
+    }
+
+    let cooldown = resp.code_time.and_then(|v| match v {
+        serde_json::Value::Number(n) => n.as_u64(),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    });
+    Ok(cooldown)
+}
+
+/// Parse the response to a reauth code submission
+pub fn parse_reauth_submit_response(text: &str) -> Result<()> {
+    let resp: ReauthSubmitResponse =
+        serde_json::from_str(text.trim()).map_err(|e| UestcClientError::VerificationFailed {
+            message: format!("Failed to parse verification response: {}", e),
+        })?;
+
+    if resp.code == "reAuth_success" {
+        Ok(())
+    } else {
+        Err(UestcClientError::VerificationFailed {
+            message: resp
+                .msg
+                .filter(|m| !m.is_empty())
+                .unwrap_or_else(|| format!("Verification rejected: {}", resp.code)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dynamic_login_form() {
+        let html = r#"
+            <div id="phoneLoginDiv">
+                <input type="text" id="phone" name="username" />
+                <input type="hidden" name="lt" value="" />
+                <input type="hidden" name="dllt" value="dynamicLogin" />
+                <input type="hidden" name="execution" value="e1s1" />
+                <input type="hidden" name="_eventId" value="submit" />
+            </div>"#;
+        let form = parse_dynamic_login_form(html).unwrap();
+        assert_eq!(form.get("dllt").map(String::as_str), Some("dynamicLogin"));
+        assert_eq!(form.get("execution").map(String::as_str), Some("e1s1"));
+        assert!(!form.contains_key("username"));
+
+        assert!(parse_dynamic_login_form("<html></html>").is_err());
+    }
+
+    #[test]
+    fn test_parse_send_code_response() {
+        assert_eq!(
+            parse_send_code_response(r#"{"res":"success","returnMessage":"","codeTime":60}"#).unwrap(),
+            Some(60)
+        );
+        match parse_send_code_response(r#"{"res":"code_time_fail","returnMessage":"请稍后再试"}"#) {
+            Err(UestcClientError::VerificationFailed { message }) => {
+                assert_eq!(message, "请稍后再试")
+            }
+            other => panic!("Expected VerificationFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reauth_submit() {
+        let form = reauth_submit_form(
+            "https://idas.uestc.edu.cn/authserver/reAuthCheck/reAuthLoginView.do?isMultifactor=true&service=https%3A%2F%2Feportal.uestc.edu.cn%2F",
+            &Secret::from(" 123456\n"),
+        );
+        assert_eq!(form["service"].expose(), "https://eportal.uestc.edu.cn/");
+        assert_eq!(form["dynamicCode"].expose(), "123456");
+
+        assert!(parse_reauth_submit_response(r#"{"code":"reAuth_success","msg":""}"#).is_ok());
+        assert!(parse_reauth_submit_response(r#"{"code":"reAuth_failed","msg":"动态码错误"}"#).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//! SMS code login and secondary verification as I/O-free flows.
+
+use super::endpoints::Endpoints;
+use super::flow::{Flow, HttpRequest, Input, Step, login_outcome, unexpected};
+use super::secret::Secret;
+use super::verification::{DynamicCodeLogin, PendingVerification};
+use crate::core::dynamic_code;
+use crate::core::log;
+use crate::Result;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Have IDAS text a login code: read the hidden fields of the dynamic-code
+/// form, then request the code, with a captcha answer if asked to
+#[derive(Debug)]
+pub struct CodeRequest {
+    endpoints: Endpoints,
+    account: String,
+    captcha: bool,
+    state: RequestState,
+}
+
+#[derive(Debug)]
+enum RequestState {
+    Start,
+    LoginPage,
+    Captcha(HashMap<String, String>),
+    Sent(HashMap<String, String>),
+}
+
+impl CodeRequest {
+    /// With `captcha`, the IDAS captcha guarding the request is solved first
+    pub fn new(endpoints: &Endpoints, account: &str, captcha: bool) -> Self {
+        Self {
+            endpoints: endpoints.clone(),
+            account: account.to_string(),
+            captcha,
+            state: RequestState::Start,
+        }
+    }
+
+    fn send_code(&mut self, form_data: HashMap<String, String>, captcha: Option<String>) -> Step<DynamicCodeLogin> {
+        let send_url = dynamic_code::build_send_code_url(&self.endpoints.auth_server_url);
+        let mut request = HttpRequest::post_form(send_url, [("mobile".to_string(), self.account.clone())])
+            .step("send_dynamic_code");
+        if let Some(captcha) = captcha {
+            request = request.field("captcha", captcha);
+        }
+        self.state = RequestState::Sent(form_data);
+        Step::Send(request)
+    }
+}
+
+impl Flow for CodeRequest {
+    type Output = DynamicCodeLogin;
+
+    fn resume(&mut self, input: Input) -> Result<Step<DynamicCodeLogin>> {
+        let input = match input {
+            Input::Response(Err(e)) => return Err(e),
+            input => input,
+        };
+        match (std::mem::replace(&mut self.state, RequestState::Start), input) {
+            (RequestState::Start, Input::Start) => {
+                log::info!("Requesting dynamic code for: {}", self.account);
+                self.state = RequestState::LoginPage;
+                Ok(Step::Send(
+                    HttpRequest::get(self.endpoints.login_url()).step("fetch_login_page"),
+                ))
+            }
+            (RequestState::LoginPage, Input::Response(Ok(resp))) => {
+                let form_data = dynamic_code::parse_dynamic_login_form(&resp.body)?;
+                if self.captcha {
+                    self.state = RequestState::Captcha(form_data);
+                    return Ok(Step::SolveCaptcha(self.account.clone()));
+                }
+                Ok(self.send_code(form_data, None))
+            }
+            (RequestState::Captcha(form_data), Input::Captcha(answer)) => {
+                Ok(self.send_code(form_data, Some(answer)))
+            }
+            (RequestState::Sent(form_data), Input::Response(Ok(resp))) => {
+                let cooldown = dynamic_code::parse_send_code_response(&resp.body)?;
+                log::info!("Dynamic code sent to: {}", self.account);
+                Ok(Step::Done(DynamicCodeLogin {
+                    account: self.account.clone(),
+                    form_data,
+                    resend_after: cooldown.map(Duration::from_secs),
+                }))
+            }
+            (_, input) => unexpected(input),
+        }
+    }
+}
+
+/// Have IDAS text the secondary verification code to the bound phone
+#[derive(Debug)]
+pub struct VerificationCodeRequest {
+    request: Option<HttpRequest>,
+}
+
+impl VerificationCodeRequest {
+    pub fn new(endpoints: &Endpoints, pending: &PendingVerification) -> Self {
+        let send_url = dynamic_code::build_reauth_send_code_url(&endpoints.auth_server_url);
+        let form_data = dynamic_code::reauth_send_code_form(&pending.username);
+        Self {
+            request: Some(HttpRequest::post_form(send_url, form_data).step("send_verification_code")),
+        }
+    }
+}
+
+impl Flow for VerificationCodeRequest {
+    type Output = ();
+
+    fn resume(&mut self, input: Input) -> Result<Step<()>> {
+        match (self.request.take(), input) {
+            (Some(request), Input::Start) => Ok(Step::Send(request)),
+            (None, Input::Response(resp)) => {
+                dynamic_code::parse_send_code_response(&resp?.body)?;
+                Ok(Step::Done(()))
+            }
+            (_, input) => unexpected(input),
+        }
+    }
+}
+
+/// Log in with a code received by SMS, either a dynamic login code or the
+/// code resuming a login paused for secondary verification
+#[derive(Debug)]
+pub struct CodeLogin {
+    endpoints: Endpoints,
+    username: String,
+    state: LoginState,
+}
+
+#[derive(Debug)]
+enum LoginState {
+    /// Submit the verification code, then revisit the login page
+    Reauth(HttpRequest),
+    ReauthSubmitted,
+    /// Submit the dynamic-code login form
+    Login(HttpRequest),
+    Submitted,
+    Saved,
+}
+
+impl CodeLogin {
+    /// Complete a login started with a [`CodeRequest`]
+    pub fn dynamic(endpoints: &Endpoints, pending: DynamicCodeLogin, code: &Secret) -> Self {
+        let request = HttpRequest::post_form(endpoints.login_url(), pending.form_data)
+            .step("submit_login_form")
+            .field("username", &pending.account)
+            .field("dynamicCode", Secret::new(code.expose().trim()));
+        Self {
+            endpoints: endpoints.clone(),
+            username: pending.account,
+            state: LoginState::Login(request),
+        }
+    }
+
+    /// Resume a login paused by [`UestcClientError::VerificationRequired`](crate::UestcClientError::VerificationRequired)
+    pub fn verification(endpoints: &Endpoints, pending: PendingVerification, code: &Secret) -> Self {
+        let submit_url = dynamic_code::build_reauth_submit_url(&endpoints.auth_server_url);
+        let form_data = dynamic_code::reauth_submit_form(&pending.reauth_url, code);
+        Self {
+            endpoints: endpoints.clone(),
+            username: pending.username,
+            state: LoginState::Reauth(
+                HttpRequest::post_form(submit_url, form_data).step("submit_verification_code"),
+            ),
+        }
+    }
+}
+
+impl Flow for CodeLogin {
+    type Output = ();
+
+    fn resume(&mut self, input: Input) -> Result<Step<()>> {
+        let input = match input {
+            Input::Response(Err(e)) => return Err(e),
+            input => input,
+        };
+        match (std::mem::replace(&mut self.state, LoginState::Saved), input) {
+            (LoginState::Reauth(request), Input::Start) => {
+                log::info!("Submitting verification code for user: {}", self.username);
+                self.state = LoginState::ReauthSubmitted;
+                Ok(Step::Send(request))
+            }
+            (LoginState::ReauthSubmitted, Input::Response(Ok(resp))) => {
+                dynamic_code::parse_reauth_submit_response(&resp.body)?;
+                // CAS issues the TGC once verification passes, revisit the login page to pick it up
+                self.state = LoginState::Submitted;
+                Ok(Step::Send(
+                    HttpRequest::get(self.endpoints.login_url())
+                        .step("fetch_login_page")
+                        .error_for_status(),
+                ))
+            }
+            (LoginState::Login(request), Input::Start) => {
+                log::info!("Starting dynamic code login for: {}", self.username);
+                self.state = LoginState::Submitted;
+                Ok(Step::Send(request))
+            }
+            (LoginState::Submitted, Input::Response(Ok(resp))) => {
+                login_outcome(&resp, &self.username, &self.endpoints.auth_server_url)?;
+                Ok(Step::SaveSession)
+            }
+            (LoginState::Saved, Input::Continue) => Ok(Step::Done(())),
+            (_, input) => unexpected(input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UestcClientError;
+    use crate::core::flow::tests::{endpoints, response};
+    use reqwest::StatusCode;
+
+    const DYNAMIC_FORM: &str = r#"<html><body><div id="phoneLoginDiv">
+        <input name="execution" value="e1s1"/>
+        <input name="_eventId" value="submit"/>
+        </div></body></html>"#;
+
+    fn send(step: Step<impl std::fmt::Debug>) -> HttpRequest {
+        match step {
+            Step::Send(request) => request,
+            other => panic!("expected a request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_code_request_with_captcha() {
+        let endpoints = endpoints();
+        let mut flow = CodeRequest::new(&endpoints, "13800000000", true);
+        let page = send(flow.resume(Input::Start).unwrap());
+        let step = flow
+            .resume(response(&page.url, StatusCode::OK, DYNAMIC_FORM))
+            .unwrap();
+        assert!(matches!(step, Step::SolveCaptcha(ref account) if account == "13800000000"));
+
+        let request = send(flow.resume(Input::Captcha("a1b2".to_string())).unwrap());
+        let fields = request.form.as_ref().unwrap();
+        assert_eq!(fields["mobile"].expose(), "13800000000");
+        assert_eq!(fields["captcha"].expose(), "a1b2");
+
+        let step = flow
+            .resume(response(&request.url, StatusCode::OK, r#"{"res":"success","codeTime":60}"#))
+            .unwrap();
+        let Step::Done(pending) = step else {
+            panic!("expected the pending login");
+        };
+        assert_eq!(pending.resend_after(), Some(Duration::from_secs(60)));
+        assert_eq!(pending.form_data["execution"], "e1s1");
+    }
+
+    #[test]
+    fn test_verification_revisits_login_page() {
+        let endpoints = endpoints();
+        let pending = PendingVerification {
+            username: "2022010901001".to_string(),
+            reauth_url: format!("{}/reAuthCheck/reAuthLoginView.do", endpoints.auth_server_url),
+        };
+        let mut flow = CodeLogin::verification(&endpoints, pending, &Secret::from("123456"));
+        let submit = send(flow.resume(Input::Start).unwrap());
+        assert_eq!(submit.step, "submit_verification_code");
+
+        let revisit = send(
+            flow.resume(response(&submit.url, StatusCode::OK, r#"{"code":"reAuth_success"}"#))
+                .unwrap(),
+        );
+        assert_eq!(revisit.url, endpoints.login_url());
+        let step = flow
+            .resume(response(&endpoints.personal_center_url, StatusCode::OK, ""))
+            .unwrap();
+        assert!(matches!(step, Step::SaveSession));
+        assert!(matches!(flow.resume(Input::Continue).unwrap(), Step::Done(())));
+    }
+
+    #[test]
+    fn test_rejected_verification_code() {
+        let endpoints = endpoints();
+        let pending = PendingVerification {
+            username: "2022010901001".to_string(),
+            reauth_url: format!("{}/reAuthCheck/reAuthLoginView.do", endpoints.auth_server_url),
+        };
+        let mut flow = CodeLogin::verification(&endpoints, pending, &Secret::from("000000"));
+        let submit = send(flow.resume(Input::Start).unwrap());
+        let result = flow.resume(response(
+            &submit.url,
+            StatusCode::OK,
+            r#"{"code":"reAuth_failed","msg":"动态码错误"}"#,
+        ));
+        assert!(matches!(result, Err(UestcClientError::VerificationFailed { .. })));
+    }
+}
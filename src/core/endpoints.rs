@@ -0,0 +1,46 @@
+//! The IDAS URLs a client talks to.
+
+use crate::{Result, UestcClientError};
+use url::Url;
+
+pub(crate) const DEFAULT_AUTH_SERVER_URL: &str = "https://idas.uestc.edu.cn/authserver";
+
+const PERSONAL_CENTER_PATH: &str = "/personalInfo/personCenter/index.html";
+
+const USER_INFO_PATH: &str = "/personalInfo/common/getUserConf";
+
+/// IDAS endpoints derived from the configured auth server base URL
+#[derive(Debug, Clone)]
+pub(crate) struct Endpoints {
+    pub auth_server_url: String,
+    /// Where CAS lands after a login without `service`
+    pub personal_center_url: String,
+    /// Personal center endpoint describing the logged-in account
+    pub user_info_url: String,
+}
+
+impl Endpoints {
+    pub fn new(auth_server_url: &str) -> Result<Self> {
+        let join = |path: &str| {
+            Url::parse(auth_server_url)
+                .and_then(|url| url.join(path))
+                .map_err(|e| UestcClientError::ClientInitError {
+                    message: format!("Invalid auth server URL {:?}: {}", auth_server_url, e),
+                })
+        };
+
+        Ok(Self {
+            auth_server_url: auth_server_url.to_string(),
+            personal_center_url: join(PERSONAL_CENTER_PATH)?.to_string(),
+            user_info_url: join(USER_INFO_PATH)?.to_string(),
+        })
+    }
+
+    pub fn login_url(&self) -> String {
+        format!("{}/login", self.auth_server_url)
+    }
+
+    pub fn logout_url(&self) -> String {
+        format!("{}/logout", self.auth_server_url)
+    }
+}
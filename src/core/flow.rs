@@ -0,0 +1,377 @@
+//! I/O-free login flows shared by the async and blocking clients.
+//!
+//! A flow is a state machine. [`Flow::resume`] returns the next [`Step`],
+//! e.g. a request to send, and the client feeds the outcome back as the next
+//! [`Input`]. The clients only perform the I/O, so a flow can be tested by
+//! feeding it canned responses.
+
+use crate::core::endpoints::Endpoints;
+use crate::core::log;
+use crate::core::secret::Secret;
+use crate::core::wechat_login::WechatLoginEvent;
+use crate::{LoginFailureReason, Result, UestcClientError, core};
+use reqwest::{Method, StatusCode};
+use std::collections::HashMap;
+use std::time::Duration;
+use url::Url;
+
+/// A request a flow wants sent with the client's session
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    /// What the request is for, e.g. `submit_login_form`, used as its span name
+    pub step: &'static str,
+    /// Sent as `application/x-www-form-urlencoded`. The values are wiped
+    /// when the request is dropped, as a form may carry a one-time code
+    pub form: Option<HashMap<String, Secret>>,
+    pub timeout: Option<Duration>,
+    /// Report 4xx and 5xx responses as [`UestcClientError::NetworkError`]
+    pub error_for_status: bool,
+    /// Safe to send again if it fails, see [`RetryPolicy`](crate::RetryPolicy)
+    pub idempotent: bool,
+}
+
+impl HttpRequest {
+    pub fn get(url: impl Into<String>) -> Self {
+        Self {
+            method: Method::GET,
+            url: url.into(),
+            step: "request",
+            form: None,
+            timeout: None,
+            error_for_status: false,
+            idempotent: true,
+        }
+    }
+
+    pub fn post_form<V: Into<Secret>>(
+        url: impl Into<String>,
+        form: impl IntoIterator<Item = (String, V)>,
+    ) -> Self {
+        Self {
+            method: Method::POST,
+            form: Some(form.into_iter().map(|(k, v)| (k, v.into())).collect()),
+            idempotent: false,
+            ..Self::get(url)
+        }
+    }
+
+    /// Add a field to the form, replacing a field of the same name
+    pub fn field(mut self, name: &str, value: impl Into<Secret>) -> Self {
+        self.form
+            .get_or_insert_with(HashMap::new)
+            .insert(name.to_string(), value.into());
+        self
+    }
+
+    /// The form fields to encode as the request body
+    pub fn form_fields(&self) -> Option<Vec<(&str, &str)>> {
+        self.form
+            .as_ref()
+            .map(|form| form.iter().map(|(k, v)| (k.as_str(), v.expose())).collect())
+    }
+
+    pub fn step(mut self, step: &'static str) -> Self {
+        self.step = step;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn error_for_status(mut self) -> Self {
+        self.error_for_status = true;
+        self
+    }
+
+    /// Never send this request twice, e.g. because it redeems a one-time code
+    pub fn not_idempotent(mut self) -> Self {
+        self.idempotent = false;
+        self
+    }
+}
+
+/// The response to an [`HttpRequest`], after redirects
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub url: Url,
+    pub status: StatusCode,
+    pub body: String,
+}
+
+/// What the client hands back to a flow
+#[derive(Debug)]
+pub enum Input {
+    /// First call to [`Flow::resume`]
+    Start,
+    /// Outcome of a [`Step::Send`]
+    Response(Result<HttpResponse>),
+    /// Answer to a [`Step::SolveCaptcha`]
+    Captcha(String),
+    /// Outcome of a [`Step::ClearSession`]: how many cookies were removed and
+    /// whether the saved session was cleared
+    SessionCleared { cookies: usize, saved_session: bool },
+    /// After any other step
+    Continue,
+}
+
+/// What a flow asks the client to do next
+#[derive(Debug)]
+pub enum Step<T> {
+    Send(HttpRequest),
+    /// Solve the IDAS captcha for this account
+    SolveCaptcha(String),
+    /// A password is about to be submitted for this account; fail if the
+    /// client's [`LoginGuard`](crate::LoginGuard) refuses the attempt
+    BeginAttempt(String),
+    /// Persist the cookies, the login has succeeded
+    SaveSession,
+    /// Forget the session: empty the cookie store and clear the saved session
+    ClearSession,
+    Sleep(Duration),
+    /// Pass this on to the caller's event callback
+    Event(WechatLoginEvent),
+    Done(T),
+}
+
+pub trait Flow {
+    type Output;
+
+    fn resume(&mut self, input: Input) -> Result<Step<Self::Output>>;
+}
+
+/// A client fed a flow an input it did not ask for
+pub(crate) fn unexpected(input: Input) -> ! {
+    unreachable!("login flow received unexpected input: {:?}", input)
+}
+
+/// Outcome of a session check
+#[derive(Debug)]
+pub enum SessionStatus {
+    /// IDAS recognises the session
+    Active,
+    /// IDAS asks for a login
+    LoggedOut,
+    /// The check failed, e.g. on a network error, so the session may well be
+    /// active. Logging in again on this can get an account captcha-locked.
+    Undetermined(UestcClientError),
+}
+
+impl SessionStatus {
+    pub fn is_active(&self) -> bool {
+        matches!(self, SessionStatus::Active)
+    }
+
+    /// Classify where `GET /authserver/login` ended up
+    pub(crate) fn from_landing(
+        url: &Url,
+        status: StatusCode,
+        endpoints: &Endpoints,
+    ) -> Self {
+        if url.as_str() == endpoints.personal_center_url {
+            return SessionStatus::Active;
+        }
+        if status.is_success()
+            && (core::cas::is_login_page(url, &endpoints.auth_server_url)
+                || core::parser::is_reauth_page(url))
+        {
+            return SessionStatus::LoggedOut;
+        }
+        SessionStatus::Undetermined(UestcClientError::HtmlParseError {
+            message: format!("Unexpected page while checking session: {} ({})", url, status),
+            source: None,
+        })
+    }
+}
+
+/// Checks whether IDAS still recognises the session
+#[derive(Debug)]
+pub struct SessionCheck {
+    endpoints: Endpoints,
+}
+
+impl SessionCheck {
+    pub fn new(endpoints: &Endpoints) -> Self {
+        Self {
+            endpoints: endpoints.clone(),
+        }
+    }
+}
+
+impl Flow for SessionCheck {
+    type Output = SessionStatus;
+
+    fn resume(&mut self, input: Input) -> Result<Step<SessionStatus>> {
+        let status = match input {
+            Input::Start => {
+                log::debug!("Checking session status");
+                let request = HttpRequest::get(self.endpoints.login_url())
+                    .step("check_session")
+                    .error_for_status();
+                return Ok(Step::Send(request));
+            }
+            Input::Response(Ok(resp)) => {
+                SessionStatus::from_landing(&resp.url, resp.status, &self.endpoints)
+            }
+            Input::Response(Err(e)) => SessionStatus::Undetermined(e),
+            other => unexpected(other),
+        };
+
+        match &status {
+            SessionStatus::Active => log::debug!("Session is active"),
+            SessionStatus::LoggedOut => log::debug!("Session is not active"),
+            SessionStatus::Undetermined(e) => log::debug!("Session check failed: {}", e),
+        }
+        Ok(Step::Done(status))
+    }
+}
+
+/// Evaluate the response to a submitted login form
+pub fn login_outcome(resp: &HttpResponse, username: &str, auth_server_url: &str) -> Result<()> {
+    let status = resp.status;
+    let final_url = &resp.url;
+
+    log::debug!("Login response status: {}, URL: {}", status, final_url);
+
+    if core::parser::is_reauth_page(final_url) {
+        log::info!("Secondary verification required for user: {}", username);
+        return Err(UestcClientError::VerificationRequired {
+            pending: core::verification::PendingVerification {
+                username: username.to_string(),
+                reauth_url: final_url.to_string(),
+            },
+        });
+    }
+
+    // Login is successful if we're not on the login page
+    if (status.is_redirection() || status.is_success())
+        && !core::cas::is_login_page(final_url, auth_server_url)
+    {
+        log::info!("Login successful for user: {}", username);
+        return Ok(());
+    }
+
+    // If we're still on login page, extract error message
+    let (error_msg, reason) = match core::parser::extract_error_message(&resp.body) {
+        Some(msg) => {
+            let reason = LoginFailureReason::from_error_message(&msg);
+            (msg, reason)
+        }
+        None => {
+            let msg = format!("Login failed with status: {}", status);
+            (msg.clone(), LoginFailureReason::Unknown(msg))
+        }
+    };
+
+    log::error!(
+        "Login failed for user {}: {} ({:?})",
+        username,
+        error_msg,
+        reason
+    );
+
+    Err(UestcClientError::LoginFailed {
+        message: error_msg,
+        username: Some(username.to_string()),
+        reason,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn endpoints() -> Endpoints {
+        Endpoints::new("https://idas.uestc.edu.cn/authserver").unwrap()
+    }
+
+    pub(crate) fn response(url: &str, status: StatusCode, body: &str) -> Input {
+        Input::Response(Ok(HttpResponse {
+            url: Url::parse(url).unwrap(),
+            status,
+            body: body.to_string(),
+        }))
+    }
+
+    #[test]
+    fn test_session_check() {
+        let endpoints = endpoints();
+        let mut check = SessionCheck::new(&endpoints);
+        let Step::Send(request) = check.resume(Input::Start).unwrap() else {
+            panic!("expected a request");
+        };
+        assert_eq!(request.url, endpoints.login_url());
+        assert!(request.error_for_status);
+
+        let step = check
+            .resume(response(&endpoints.personal_center_url, StatusCode::OK, ""))
+            .unwrap();
+        assert!(matches!(step, Step::Done(SessionStatus::Active)));
+
+        let mut check = SessionCheck::new(&endpoints);
+        check.resume(Input::Start).unwrap();
+        let step = check
+            .resume(Input::Response(Err(UestcClientError::Timeout {
+                message: "connect".to_string(),
+            })))
+            .unwrap();
+        assert!(matches!(step, Step::Done(SessionStatus::Undetermined(_))));
+    }
+
+    #[test]
+    fn test_status_from_landing() {
+        let endpoints = endpoints();
+        let landing = |url: &str, status| {
+            SessionStatus::from_landing(&Url::parse(url).unwrap(), status, &endpoints)
+        };
+
+        assert!(landing(&endpoints.personal_center_url, StatusCode::OK).is_active());
+        assert!(matches!(
+            landing("https://idas.uestc.edu.cn/authserver/login", StatusCode::OK),
+            SessionStatus::LoggedOut
+        ));
+        // A maintenance page says nothing about the session
+        assert!(matches!(
+            landing("https://idas.uestc.edu.cn/authserver/login", StatusCode::BAD_GATEWAY),
+            SessionStatus::Undetermined(_)
+        ));
+        assert!(matches!(
+            landing("https://idas.uestc.edu.cn/maintenance.html", StatusCode::OK),
+            SessionStatus::Undetermined(_)
+        ));
+    }
+
+    #[test]
+    fn test_login_outcome() {
+        let auth = "https://idas.uestc.edu.cn/authserver";
+        let landed = HttpResponse {
+            url: Url::parse("https://idas.uestc.edu.cn/personalInfo/personCenter/index.html")
+                .unwrap(),
+            status: StatusCode::OK,
+            body: String::new(),
+        };
+        assert!(login_outcome(&landed, "2022010901001", auth).is_ok());
+
+        let reauth = HttpResponse {
+            url: Url::parse("https://idas.uestc.edu.cn/authserver/reAuthCheck/reAuthLoginView.do")
+                .unwrap(),
+            ..landed.clone()
+        };
+        assert!(matches!(
+            login_outcome(&reauth, "2022010901001", auth),
+            Err(UestcClientError::VerificationRequired { .. })
+        ));
+
+        let rejected = HttpResponse {
+            url: Url::parse("https://idas.uestc.edu.cn/authserver/login").unwrap(),
+            ..landed
+        };
+        assert!(matches!(
+            login_outcome(&rejected, "2022010901001", auth),
+            Err(UestcClientError::LoginFailed { .. })
+        ));
+    }
+}
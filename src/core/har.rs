@@ -0,0 +1,404 @@
+//! HTTP Archive (HAR 1.2) entries for recorded traffic.
+//!
+//! Secrets are masked while an entry is built, so nothing unredacted is ever
+//! kept: URLs and bodies go through [`log::redact`], cookie values and
+//! credentials in headers are replaced with `***`.
+
+use crate::core::log;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use cookie::time::OffsetDateTime;
+use cookie::time::format_description::well_known::Rfc3339;
+use reqwest::header::{self, HeaderMap};
+use reqwest::{StatusCode, Version};
+use serde::Serialize;
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+/// Headers whose whole value is a credential
+const SECRET_HEADERS: &[&str] = &["authorization", "proxy-authorization"];
+
+/// One request and its response, as seen on the wire
+#[derive(Debug)]
+pub struct Exchange<'a> {
+    pub started: SystemTime,
+    pub time: Duration,
+    pub method: &'a str,
+    pub url: &'a Url,
+    pub version: Version,
+    pub request_headers: &'a HeaderMap,
+    /// Cookies the session sent with the request
+    pub request_cookies: Vec<String>,
+    pub request_body: Option<&'a [u8]>,
+    pub status: StatusCode,
+    pub response_headers: &'a HeaderMap,
+    pub response_body: &'a [u8],
+}
+
+#[derive(Debug, Serialize)]
+pub struct Har<'a> {
+    pub log: HarLog<'a>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HarLog<'a> {
+    pub version: &'static str,
+    pub creator: Creator,
+    pub entries: &'a [Entry],
+}
+
+impl<'a> Har<'a> {
+    pub fn new(entries: &'a [Entry]) -> Self {
+        Self {
+            log: HarLog {
+                version: "1.2",
+                creator: Creator {
+                    name: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                entries,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Creator {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub started_date_time: String,
+    /// Milliseconds
+    pub time: f64,
+    pub request: Request,
+    pub response: Response,
+    pub cache: Cache,
+    pub timings: Timings,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub http_version: String,
+    pub cookies: Vec<Cookie>,
+    pub headers: Vec<NameValue>,
+    pub query_string: Vec<NameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<PostData>,
+    pub headers_size: i64,
+    pub body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Response {
+    pub status: u16,
+    pub status_text: String,
+    pub http_version: String,
+    pub cookies: Vec<Cookie>,
+    pub headers: Vec<NameValue>,
+    pub content: Content,
+    #[serde(rename = "redirectURL")]
+    pub redirect_url: String,
+    pub headers_size: i64,
+    pub body_size: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NameValue {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secure: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostData {
+    pub mime_type: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Content {
+    pub size: i64,
+    pub mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Cache {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Timings {
+    pub send: f64,
+    pub wait: f64,
+    pub receive: f64,
+}
+
+impl Entry {
+    /// Build a redacted entry, keeping at most `max_body_size` bytes of the
+    /// response body
+    pub fn new(exchange: &Exchange<'_>, max_body_size: usize) -> Self {
+        let time = exchange.time.as_secs_f64() * 1000.0;
+        let http_version = format!("{:?}", exchange.version);
+        let url = log::redact(exchange.url.as_str()).into_owned();
+        let query_string = Url::parse(&url)
+            .map(|url| {
+                url.query_pairs()
+                    .map(|(name, value)| NameValue {
+                        name: name.into_owned(),
+                        value: value.into_owned(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let request = Request {
+            method: exchange.method.to_string(),
+            url,
+            http_version: http_version.clone(),
+            cookies: exchange
+                .request_cookies
+                .iter()
+                .map(|name| Cookie {
+                    name: name.clone(),
+                    value: "***".to_string(),
+                    ..Cookie::default()
+                })
+                .collect(),
+            headers: headers(exchange.request_headers),
+            query_string,
+            post_data: exchange.request_body.map(|body| PostData {
+                mime_type: mime_type(exchange.request_headers),
+                text: log::redact(&String::from_utf8_lossy(body)).into_owned(),
+            }),
+            headers_size: -1,
+            body_size: exchange.request_body.map_or(0, |body| body.len() as i64),
+        };
+
+        let redirect_url = exchange
+            .response_headers
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(|location| log::redact(location).into_owned())
+            .unwrap_or_default();
+        let response = Response {
+            status: exchange.status.as_u16(),
+            status_text: exchange
+                .status
+                .canonical_reason()
+                .unwrap_or_default()
+                .to_string(),
+            http_version,
+            cookies: exchange
+                .response_headers
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .filter_map(set_cookie)
+                .collect(),
+            headers: headers(exchange.response_headers),
+            content: content(
+                exchange.response_body,
+                mime_type(exchange.response_headers),
+                max_body_size,
+            ),
+            redirect_url,
+            headers_size: -1,
+            body_size: exchange.response_body.len() as i64,
+        };
+
+        Self {
+            started_date_time: OffsetDateTime::from(exchange.started)
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            time,
+            request,
+            response,
+            cache: Cache {},
+            timings: Timings {
+                send: 0.0,
+                wait: time,
+                receive: 0.0,
+            },
+        }
+    }
+}
+
+fn headers(headers: &HeaderMap) -> Vec<NameValue> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                "***".to_string()
+            } else if name == header::COOKIE || name == header::SET_COOKIE {
+                log::redact_cookies(&value).into_owned()
+            } else {
+                log::redact(&value).into_owned()
+            };
+            NameValue {
+                name: name.to_string(),
+                value,
+            }
+        })
+        .collect()
+}
+
+fn mime_type(headers: &HeaderMap) -> String {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// A cookie set by the response, attributes kept and value masked
+fn set_cookie(value: &str) -> Option<Cookie> {
+    let cookie = cookie::Cookie::parse(value).ok()?;
+    Some(Cookie {
+        name: cookie.name().to_string(),
+        value: "***".to_string(),
+        path: cookie.path().map(str::to_string),
+        domain: cookie.domain().map(str::to_string),
+        expires: cookie
+            .expires_datetime()
+            .and_then(|expires| expires.format(&Rfc3339).ok()),
+        http_only: cookie.http_only(),
+        secure: cookie.secure(),
+    })
+}
+
+/// The response body, as redacted text or base64 if it is not UTF-8
+fn content(body: &[u8], mime_type: String, max_body_size: usize) -> Content {
+    let kept = &body[..body.len().min(max_body_size)];
+    let comment = (kept.len() < body.len())
+        .then(|| format!("Truncated to {} of {} bytes", kept.len(), body.len()));
+    let (text, encoding) = match std::str::from_utf8(kept) {
+        Ok(text) => (log::redact(text).into_owned(), None),
+        // Cut in the middle of a character
+        Err(e) if e.error_len().is_none() => {
+            let text = std::str::from_utf8(&kept[..e.valid_up_to()]).unwrap_or_default();
+            (log::redact(text).into_owned(), None)
+        }
+        Err(_) => (BASE64.encode(kept), Some("base64".to_string())),
+    };
+    Content {
+        size: body.len() as i64,
+        mime_type,
+        text: Some(text),
+        encoding,
+        comment,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_entry_is_redacted() {
+        let url = Url::parse("https://idas.uestc.edu.cn/authserver/login?service=https%3A%2F%2Feportal.uestc.edu.cn%2F").unwrap();
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        request_headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic abc"));
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(
+            header::LOCATION,
+            HeaderValue::from_static("https://eportal.uestc.edu.cn/?ticket=ST-1-abc-idas"),
+        );
+        response_headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("CASTGC=TGT-1-xyz; Path=/authserver; HttpOnly; Secure"),
+        );
+
+        let entry = Entry::new(
+            &Exchange {
+                started: SystemTime::UNIX_EPOCH,
+                time: Duration::from_millis(120),
+                method: "POST",
+                url: &url,
+                version: Version::HTTP_11,
+                request_headers: &request_headers,
+                request_cookies: vec!["route".to_string()],
+                request_body: Some(b"username=2022010901001&password=abc%3D&captcha=a1b2"),
+                status: StatusCode::FOUND,
+                response_headers: &response_headers,
+                response_body: "<html>登录成功</html>".as_bytes(),
+            },
+            8,
+        );
+
+        assert_eq!(entry.started_date_time, "1970-01-01T00:00:00Z");
+        assert_eq!(entry.request.query_string[0].name, "service");
+        assert_eq!(
+            entry.request.post_data.as_ref().unwrap().text,
+            "username=2022010901001&password=***&captcha=***"
+        );
+        assert!(entry.request.headers.contains(&NameValue {
+            name: "authorization".to_string(),
+            value: "***".to_string(),
+        }));
+        assert_eq!(entry.request.cookies[0].value, "***");
+        assert_eq!(
+            entry.response.redirect_url,
+            "https://eportal.uestc.edu.cn/?ticket=***"
+        );
+        let cookie = &entry.response.cookies[0];
+        assert_eq!(cookie.name, "CASTGC");
+        assert_eq!(cookie.value, "***");
+        assert_eq!(cookie.path.as_deref(), Some("/authserver"));
+        assert!(!serde_json::to_string(&entry).unwrap().contains("TGT-1"));
+
+        // 8 bytes end inside `登`
+        assert_eq!(entry.response.content.text.as_deref(), Some("<html>"));
+        assert!(entry.response.content.comment.is_some());
+        assert_eq!(
+            entry.response.content.size,
+            "<html>登录成功</html>".len() as i64
+        );
+    }
+
+    #[test]
+    fn test_binary_body_is_base64() {
+        let content = content(
+            &[0x89, b'P', b'N', b'G', 0xff],
+            "image/png".to_string(),
+            1024,
+        );
+        assert_eq!(content.encoding.as_deref(), Some("base64"));
+        assert_eq!(content.text.as_deref(), Some("iVBOR/8="));
+    }
+}
@@ -0,0 +1,131 @@
+//! Logging with secrets redacted.
+//!
+//! Modules import this module as `log` and log through its macros, which
+//! format the message, pass it through [`redact`] and hand it to the `log`
+//! crate, so tickets, codes and cookie values never reach a log line even
+//! when they are part of a URL or an error message.
+//!
+//! With the `tracing` feature, messages are also emitted as `tracing` events
+//! when a subscriber wants them, so they show up inside the flow's spans.
+//! The `log` output is kept either way.
+
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+/// Patterns for secrets and their replacements
+static PATTERNS: LazyLock<Vec<(Regex, &'static str)>> = LazyLock::new(|| {
+    [
+        // Query and form parameters, e.g. `?ticket=ST-...` or `code=...`
+        (
+            r"(?i)\b(ticket|code|wx_code|dynamicCode|captcha|password|CASTGC|JSESSIONID|MOD_AUTH_CAS|p_auth_token)=[^&;,\s'\x22#]+",
+            "$1=***",
+        ),
+        // The WeChat poll response, `window.wx_code='...'`
+        (r"(?i)\b(wx_code\s*=\s*['\x22])[^'\x22]*", "${1}***"),
+        // CAS tickets wherever they appear
+        (r"\b(ST|TGT|PGT|PGTIOU)-[\w.-]+", "$1-***"),
+    ]
+    .into_iter()
+    .map(|(pattern, replacement)| (Regex::new(pattern).unwrap(), replacement))
+    .collect()
+});
+
+/// `Cookie` and `Set-Cookie` headers, and the `name=value` pairs in them
+static COOKIE_HEADER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\b(?:set-)?cookie:[^\r\n]*").unwrap());
+static COOKIE_PAIR: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"([\w.-]+)=[^;\r\n]*").unwrap());
+
+/// Mask CAS tickets, login codes and cookie values in `message`
+pub fn redact(message: &str) -> Cow<'_, str> {
+    let mut redacted = Cow::Borrowed(message);
+    for (pattern, replacement) in PATTERNS.iter() {
+        if let Cow::Owned(replaced) = pattern.replace_all(&redacted, *replacement) {
+            redacted = Cow::Owned(replaced);
+        }
+    }
+    // Keep cookie names, mask their values and attributes
+    if let Cow::Owned(replaced) = COOKIE_HEADER.replace_all(&redacted, |caps: &regex::Captures| {
+        redact_cookies(&caps[0]).into_owned()
+    }) {
+        redacted = Cow::Owned(replaced);
+    }
+    redacted
+}
+
+/// Mask every value in a `Cookie` or `Set-Cookie` header value, keeping the names
+pub fn redact_cookies(header: &str) -> Cow<'_, str> {
+    COOKIE_PAIR.replace_all(header, "$1=***")
+}
+
+macro_rules! log_redacted {
+    ($level:ident, $tracing_level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        let to_tracing = ::tracing::event_enabled!(::tracing::Level::$tracing_level);
+        #[cfg(not(feature = "tracing"))]
+        let to_tracing = false;
+
+        if to_tracing || ::log::log_enabled!(::log::Level::$level) {
+            let message = ::std::format!($($arg)+);
+            let message = $crate::core::log::redact(&message);
+            ::log::log!(::log::Level::$level, "{}", message);
+            #[cfg(feature = "tracing")]
+            if to_tracing {
+                ::tracing::event!(::tracing::Level::$tracing_level, "{}", message);
+            }
+        }
+    }};
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)+) => { $crate::core::log::log_redacted!(Debug, DEBUG, $($arg)+) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::core::log::log_redacted!(Info, INFO, $($arg)+) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::core::log::log_redacted!(Warn, WARN, $($arg)+) };
+}
+
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::core::log::log_redacted!(Error, ERROR, $($arg)+) };
+}
+
+pub(crate) use log_redacted;
+pub(crate) use {log_debug as debug, log_error as error, log_info as info, log_warn as warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("Following redirect to: https://eportal.uestc.edu.cn/?ticket=ST-123-abc-idas"),
+            "Following redirect to: https://eportal.uestc.edu.cn/?ticket=***"
+        );
+        assert_eq!(
+            redact("GET /authserver/combinedLogin.do?type=weixin&code=081xYz&state=abc"),
+            "GET /authserver/combinedLogin.do?type=weixin&code=***&state=abc"
+        );
+        assert_eq!(
+            redact("window.wx_errcode=405;window.wx_code='081xYz';"),
+            "window.wx_errcode=405;window.wx_code='***';"
+        );
+        assert_eq!(
+            redact("Set-Cookie: CASTGC=TGT-1-abc; Path=/authserver"),
+            "Set-Cookie: CASTGC=***; Path=***"
+        );
+        assert_eq!(
+            redact("Cookie: route=r1; JSESSIONID=s1"),
+            "Cookie: route=***; JSESSIONID=***"
+        );
+        assert_eq!(redact("invalid ticket TGT-1-abc"), "invalid ticket TGT-***");
+        assert!(matches!(
+            redact("Starting login for user: 2022010901001"),
+            Cow::Borrowed(_)
+        ));
+    }
+}
@@ -0,0 +1,248 @@
+//! Logout as an I/O-free flow, with its options and outcome.
+
+use super::endpoints::Endpoints;
+use super::flow::{Flow, HttpRequest, Input, Step, unexpected};
+use crate::core::log;
+use crate::{Result, UestcClientError};
+use reqwest::StatusCode;
+use reqwest_cookie_store::CookieStoreMutex;
+use std::collections::HashSet;
+
+/// What `logout_with` invalidates besides the CAS session
+#[derive(Debug, Clone, Default)]
+pub struct LogoutOptions {
+    pub(crate) service_logouts: Vec<String>,
+}
+
+impl LogoutOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also visit `logout_url` if its system (same origin) was entered with
+    /// `login_to_service` during this session. May be called multiple times.
+    ///
+    /// Logging out of CAS ends single sign-on but not the sessions downstream
+    /// systems already set up, e.g. in the online hall.
+    pub fn service_logout(mut self, logout_url: impl Into<String>) -> Self {
+        self.service_logouts.push(logout_url.into());
+        self
+    }
+}
+
+/// A downstream logout endpoint visited during logout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceLogout {
+    pub url: String,
+    /// `None` if the request failed
+    pub status: Option<StatusCode>,
+}
+
+impl ServiceLogout {
+    pub fn succeeded(&self) -> bool {
+        self.status
+            .is_some_and(|status| status.is_success() || status.is_redirection())
+    }
+}
+
+/// What a logout invalidated
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogoutReport {
+    /// Downstream logout endpoints visited, in order
+    pub services: Vec<ServiceLogout>,
+    /// Cookies removed from the client's in-memory store, always 0 for a
+    /// client made with `with_client`
+    pub cookies_cleared: usize,
+    /// Whether the persisted session was cleared; `false` if cookies are not
+    /// persisted or clearing failed
+    pub saved_session_cleared: bool,
+}
+
+/// Origin of `url`, the key under which entered systems are remembered
+pub(crate) fn origin(url: &str) -> Option<String> {
+    url::Url::parse(url)
+        .ok()
+        .map(|url| url.origin().ascii_serialization())
+}
+
+/// The configured logout URLs of systems that were entered
+pub(crate) fn due_service_logouts(
+    options: &LogoutOptions,
+    entered: &HashSet<String>,
+) -> Vec<String> {
+    options
+        .service_logouts
+        .iter()
+        .filter(|url| origin(url).is_some_and(|origin| entered.contains(&origin)))
+        .cloned()
+        .collect()
+}
+
+/// Empty the in-memory cookie store, returning how many cookies it held
+pub(crate) fn clear_cookies(store: &CookieStoreMutex) -> usize {
+    let mut store = store.lock().unwrap();
+    let count = store.iter_any().count();
+    store.clear();
+    count
+}
+
+/// Visit the logout endpoints of entered systems, then log out of CAS. The
+/// client forgets the session whatever IDAS answers.
+#[derive(Debug)]
+pub struct Logout {
+    services: std::vec::IntoIter<String>,
+    logout_url: String,
+    report: LogoutReport,
+    state: State,
+}
+
+#[derive(Debug)]
+enum State {
+    /// Waiting for this service logout endpoint
+    Service(String),
+    /// Waiting for the CAS logout
+    Cas,
+    /// Waiting for the client to forget the session, with what CAS answered
+    Clearing(Result<StatusCode>),
+    Done,
+}
+
+impl Logout {
+    /// `entered` holds the origins of the systems entered during the session
+    pub fn new(endpoints: &Endpoints, options: &LogoutOptions, entered: &HashSet<String>) -> Self {
+        Self {
+            services: due_service_logouts(options, entered).into_iter(),
+            logout_url: endpoints.logout_url(),
+            report: LogoutReport::default(),
+            state: State::Done,
+        }
+    }
+
+    fn next_request(&mut self) -> Step<LogoutReport> {
+        let Some(url) = self.services.next() else {
+            self.state = State::Cas;
+            return Step::Send(HttpRequest::get(&self.logout_url).step("cas_logout"));
+        };
+        log::debug!("Logging out of service: {}", url);
+        self.state = State::Service(url.clone());
+        Step::Send(HttpRequest::get(url).step("service_logout"))
+    }
+}
+
+impl Flow for Logout {
+    type Output = LogoutReport;
+
+    fn resume(&mut self, input: Input) -> Result<Step<LogoutReport>> {
+        match (std::mem::replace(&mut self.state, State::Done), input) {
+            (_, Input::Start) => {
+                log::info!("Attempting to logout");
+                Ok(self.next_request())
+            }
+            (State::Service(url), Input::Response(resp)) => {
+                let status = match resp {
+                    Ok(resp) => Some(resp.status),
+                    Err(e) => {
+                        log::warn!("Service logout failed for {}: {}", url, e);
+                        None
+                    }
+                };
+                self.report.services.push(ServiceLogout { url, status });
+                Ok(self.next_request())
+            }
+            (State::Cas, Input::Response(resp)) => {
+                self.state = State::Clearing(resp.map(|resp| resp.status));
+                Ok(Step::ClearSession)
+            }
+            (
+                State::Clearing(status),
+                Input::SessionCleared {
+                    cookies,
+                    saved_session,
+                },
+            ) => {
+                self.report.cookies_cleared = cookies;
+                self.report.saved_session_cleared = saved_session;
+                let status = status?;
+                if status.is_success() {
+                    log::info!("Logout successful");
+                    return Ok(Step::Done(std::mem::take(&mut self.report)));
+                }
+
+                let error_msg = format!("Logout failed with status: {}", status);
+                log::error!("{}", error_msg);
+                Err(UestcClientError::LogoutFailed { message: error_msg })
+            }
+            (_, input) => unexpected(input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::flow::tests::{endpoints, response};
+
+    #[test]
+    fn test_only_entered_services_are_logged_out() {
+        let options = LogoutOptions::new()
+            .service_logout("https://online.uestc.edu.cn/common/logout")
+            .service_logout("https://eams.uestc.edu.cn/eams/logout.action");
+        let entered = HashSet::from([origin("https://online.uestc.edu.cn/page/").unwrap()]);
+
+        assert_eq!(
+            due_service_logouts(&options, &entered),
+            ["https://online.uestc.edu.cn/common/logout"]
+        );
+    }
+
+    #[test]
+    fn test_clear_cookies() {
+        let url = url::Url::parse("https://idas.uestc.edu.cn/authserver/login").unwrap();
+        let mut store = cookie_store::CookieStore::default();
+        store
+            .insert_raw(&"CASTGC=TGT-1; Path=/authserver".parse().unwrap(), &url)
+            .unwrap();
+        store
+            .insert_raw(&"route=r1; Path=/".parse().unwrap(), &url)
+            .unwrap();
+        let store = CookieStoreMutex::new(store);
+
+        assert_eq!(clear_cookies(&store), 2);
+        assert_eq!(store.lock().unwrap().iter_any().count(), 0);
+    }
+
+    #[test]
+    fn test_logout_flow() {
+        let options = LogoutOptions::new().service_logout("https://online.uestc.edu.cn/common/logout");
+        let entered = HashSet::from([origin("https://online.uestc.edu.cn/page/").unwrap()]);
+        let mut logout = Logout::new(&endpoints(), &options, &entered);
+
+        let Step::Send(request) = logout.resume(Input::Start).unwrap() else {
+            panic!("expected a service logout");
+        };
+        assert_eq!(request.step, "service_logout");
+        let step = logout
+            .resume(Input::Response(Err(UestcClientError::Timeout {
+                message: "connect".to_string(),
+            })))
+            .unwrap();
+        let Step::Send(request) = step else {
+            panic!("expected the CAS logout");
+        };
+        assert_eq!(request.url, "https://idas.uestc.edu.cn/authserver/logout");
+
+        // The session is forgotten even though IDAS fails the logout
+        let step = logout
+            .resume(response(&request.url, StatusCode::BAD_GATEWAY, ""))
+            .unwrap();
+        assert!(matches!(step, Step::ClearSession));
+        let cleared = Input::SessionCleared {
+            cookies: 2,
+            saved_session: true,
+        };
+        assert!(matches!(
+            logout.resume(cleared),
+            Err(UestcClientError::LogoutFailed { .. })
+        ));
+    }
+}
@@ -1,6 +1,92 @@
+use crate::core::log;
 use crate::{Result, UestcClientError};
+use regex::Regex;
 use scraper::{Html, Selector};
 use std::collections::HashMap;
+use std::sync::LazyLock;
+use url::Url;
+
+/// Why IDAS rejected a login, parsed from the error tip on the login page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoginFailureReason {
+    /// Wrong username or password
+    InvalidCredentials,
+    /// Too many failed attempts; `unlock_time` is the time shown by IDAS, if any
+    AccountLocked { unlock_time: Option<String> },
+    /// A captcha is required or the submitted one was wrong
+    CaptchaRequired,
+    /// The SMS dynamic code was wrong or has expired
+    InvalidVerificationCode,
+    /// The account has not been activated yet
+    AccountInactive,
+    /// The password has expired and must be changed in a browser
+    PasswordExpired,
+    /// Risk control asks for secondary verification of this device
+    ReauthRequired,
+    /// Any other message, kept verbatim
+    Unknown(String),
+}
+
+impl LoginFailureReason {
+    /// Classify an IDAS error tip such as `您提供的用户名或者密码有误`
+    pub fn from_error_message(message: &str) -> Self {
+        let message = message.trim();
+
+        if message.contains("冻结") || message.contains("锁定") {
+            return Self::AccountLocked {
+                unlock_time: extract_unlock_time(message),
+            };
+        }
+        if message.contains("动态码") {
+            return Self::InvalidVerificationCode;
+        }
+        if message.contains("验证码") {
+            return Self::CaptchaRequired;
+        }
+        if message.contains("未激活") {
+            return Self::AccountInactive;
+        }
+        if message.contains("密码已过期") || message.contains("密码过期") {
+            return Self::PasswordExpired;
+        }
+        if message.contains("二次认证")
+            || message.contains("二次验证")
+            || message.contains("安全验证")
+            || message.contains("风险")
+        {
+            return Self::ReauthRequired;
+        }
+        if message.contains("用户名或者密码有误")
+            || message.contains("用户名或密码")
+            || message.contains("密码有误")
+            || message.contains("密码错误")
+        {
+            return Self::InvalidCredentials;
+        }
+
+        Self::Unknown(message.to_string())
+    }
+}
+
+/// Lock messages give either the time the account unlocks or how long it stays locked
+static UNLOCK_DATETIME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\d{4}[-/年]\d{1,2}[-/月]\d{1,2}日?\s*\d{1,2}[:：]\d{2}(?:[:：]\d{2})?").unwrap()
+});
+static UNLOCK_DURATION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\d+\s*(?:分钟|小时|天)").unwrap());
+
+/// Extract an unlock time like `2024-01-01 12:00:00` or `30分钟` from a lock message
+fn extract_unlock_time(message: &str) -> Option<String> {
+    UNLOCK_DATETIME
+        .find(message)
+        .or_else(|| UNLOCK_DURATION.find(message))
+        .map(|m| m.as_str().to_string())
+}
+
+/// Whether the login form submission ended on the IDAS secondary verification page
+pub fn is_reauth_page(url: &Url) -> bool {
+    url.path().contains("/reAuthCheck/")
+}
 
 #[derive(Debug, Clone)]
 pub struct LoginPageInfo {
@@ -28,11 +114,11 @@ pub fn parse_login_page(html: &str) -> Result<LoginPageInfo> {
 
     let mut encrypt_script_path = None;
     for element in document.select(&script_selector) {
-        if let Some(src) = element.value().attr("src") {
-            if src.contains("encrypt") {
-                encrypt_script_path = Some(src.to_string());
-                break;
-            }
+        if let Some(src) = element.value().attr("src")
+            && src.contains("encrypt")
+        {
+            encrypt_script_path = Some(src.to_string());
+            break;
         }
     }
 
@@ -110,6 +196,7 @@ pub fn extract_error_message(html: &str) -> Option<String> {
 mod tests {
     use super::*;
 
+    #[cfg(feature = "async")]
     #[tokio::test]
     async fn test_parse_login_page() {
         // 请求真实的登录页面
@@ -147,4 +234,58 @@ mod tests {
         // 验证表单数据
         assert!(!info.form_data.is_empty(), "form_data should not be empty");
     }
+
+    #[test]
+    fn test_login_failure_reason() {
+        assert_eq!(
+            LoginFailureReason::from_error_message("您提供的用户名或者密码有误"),
+            LoginFailureReason::InvalidCredentials
+        );
+        assert_eq!(
+            LoginFailureReason::from_error_message("密码错误次数过多，账号已被冻结，请于2024-05-01 12:30:00后再试"),
+            LoginFailureReason::AccountLocked {
+                unlock_time: Some("2024-05-01 12:30:00".to_string())
+            }
+        );
+        assert_eq!(
+            LoginFailureReason::from_error_message("账号已被锁定，请30分钟后重试"),
+            LoginFailureReason::AccountLocked {
+                unlock_time: Some("30分钟".to_string())
+            }
+        );
+        assert_eq!(
+            LoginFailureReason::from_error_message("动态码错误或已失效"),
+            LoginFailureReason::InvalidVerificationCode
+        );
+        assert_eq!(
+            LoginFailureReason::from_error_message("系统繁忙，请稍后再试"),
+            LoginFailureReason::Unknown("系统繁忙，请稍后再试".to_string())
+        );
+        assert_eq!(
+            LoginFailureReason::from_error_message("验证码错误"),
+            LoginFailureReason::CaptchaRequired
+        );
+        assert_eq!(
+            LoginFailureReason::from_error_message("该账号未激活，请先激活"),
+            LoginFailureReason::AccountInactive
+        );
+        assert_eq!(
+            LoginFailureReason::from_error_message("您的密码已过期，请修改密码"),
+            LoginFailureReason::PasswordExpired
+        );
+        assert_eq!(
+            LoginFailureReason::from_error_message("当前登录存在风险，请进行二次认证"),
+            LoginFailureReason::ReauthRequired
+        );
+    }
+
+    #[test]
+    fn test_extract_error_message() {
+        let html = r#"<html><body><span id="showErrorTip"> 您提供的用户名或者密码有误 </span></body></html>"#;
+        assert_eq!(
+            extract_error_message(html).as_deref(),
+            Some("您提供的用户名或者密码有误")
+        );
+        assert_eq!(extract_error_message("<html></html>"), None);
+    }
 }
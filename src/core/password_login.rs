@@ -0,0 +1,267 @@
+//! Username/password login as an I/O-free flow.
+
+use super::endpoints::Endpoints;
+use super::flow::{
+    Flow, HttpRequest, Input, SessionCheck, SessionStatus, Step, login_outcome, unexpected,
+};
+use super::parser::LoginPageInfo;
+use super::secret::Secret;
+use crate::core::log;
+use crate::{Result, core};
+
+#[derive(Debug)]
+enum State {
+    CheckSession(SessionCheck),
+    LoginPage,
+    Guarded(LoginPageInfo),
+    CaptchaCheck(LoginPageInfo),
+    Captcha(LoginPageInfo),
+    Submitted,
+    Saved,
+}
+
+/// Password login: skipped if the session is still active, otherwise fetch
+/// the login page, solve a captcha if IDAS asks for one and submit the form
+#[derive(Debug)]
+pub struct PasswordLogin {
+    endpoints: Endpoints,
+    username: String,
+    password: Secret,
+    state: State,
+}
+
+impl PasswordLogin {
+    pub fn new(endpoints: &Endpoints, username: &str, password: Secret) -> Self {
+        Self {
+            endpoints: endpoints.clone(),
+            username: username.to_string(),
+            password,
+            state: State::CheckSession(SessionCheck::new(endpoints)),
+        }
+    }
+
+    fn submit(&mut self, info: LoginPageInfo, captcha: Option<String>) -> Result<Step<()>> {
+        log::debug!("Encrypting password");
+        let encrypted_password =
+            core::crypto::encrypt_password(self.password.expose(), &info.pwd_encrypt_salt)?;
+
+        let mut form_data = info.form_data;
+        form_data.insert("username".to_string(), self.username.clone());
+        form_data.insert("password".to_string(), encrypted_password);
+        if let Some(captcha) = captcha {
+            form_data.insert("captcha".to_string(), captcha);
+        }
+
+        log::debug!("Submitting login form");
+        self.state = State::Submitted;
+        Ok(Step::Send(
+            HttpRequest::post_form(self.endpoints.login_url(), form_data)
+                .step("submit_login_form"),
+        ))
+    }
+}
+
+impl Flow for PasswordLogin {
+    type Output = ();
+
+    fn resume(&mut self, input: Input) -> Result<Step<()>> {
+        if let State::CheckSession(check) = &mut self.state {
+            // An undetermined check is an error rather than a reason to log
+            // in again, which could get the account captcha-locked
+            return match check.resume(input)? {
+                Step::Done(SessionStatus::Active) => {
+                    log::info!("Session already active, skipping login");
+                    Ok(Step::Done(()))
+                }
+                Step::Done(SessionStatus::LoggedOut) => {
+                    log::debug!("Fetching login page");
+                    self.state = State::LoginPage;
+                    Ok(Step::Send(
+                        HttpRequest::get(self.endpoints.login_url())
+                            .step("fetch_login_page")
+                            .error_for_status(),
+                    ))
+                }
+                Step::Done(SessionStatus::Undetermined(e)) => Err(e),
+                Step::Send(request) => Ok(Step::Send(request)),
+                other => unreachable!("session check asked for {:?}", other),
+            };
+        }
+
+        let input = match input {
+            Input::Response(Err(e)) => return Err(e),
+            input => input,
+        };
+        match (std::mem::replace(&mut self.state, State::Saved), input) {
+            (State::LoginPage, Input::Response(Ok(resp))) => {
+                log::debug!("Parsing login page");
+                let info = core::parser::parse_login_page(&resp.body)?;
+                self.state = State::Guarded(info);
+                Ok(Step::BeginAttempt(self.username.clone()))
+            }
+            (State::Guarded(info), Input::Continue) => {
+                self.state = State::CaptchaCheck(info);
+                let check_url =
+                    core::captcha::build_check_url(&self.endpoints.auth_server_url, &self.username);
+                // No error_for_status: an error page means no captcha
+                Ok(Step::Send(HttpRequest::get(check_url).step("check_captcha")))
+            }
+            (State::CaptchaCheck(info), Input::Response(Ok(resp))) => {
+                if core::captcha::parse_need_captcha(&resp.body) {
+                    self.state = State::Captcha(info);
+                    return Ok(Step::SolveCaptcha(self.username.clone()));
+                }
+                self.submit(info, None)
+            }
+            (State::Captcha(info), Input::Captcha(answer)) => self.submit(info, Some(answer)),
+            (State::Submitted, Input::Response(Ok(resp))) => {
+                login_outcome(&resp, &self.username, &self.endpoints.auth_server_url)?;
+                self.state = State::Saved;
+                Ok(Step::SaveSession)
+            }
+            (State::Saved, Input::Continue) => Ok(Step::Done(())),
+            (_, input) => unexpected(input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UestcClientError;
+    use crate::core::flow::tests::{endpoints, response};
+    use reqwest::{Method, StatusCode};
+
+    const LOGIN_PAGE: &str = r#"<html><body><div id="pwdLoginDiv">
+        <input id="pwdEncryptSalt" value="rjBFAaHsNkKAhpoi"/>
+        <input id="execution" value="e1s1"/>
+        <input id="_eventId" value="submit"/>
+        </div></body></html>"#;
+
+    fn send(step: Step<()>) -> HttpRequest {
+        match step {
+            Step::Send(request) => request,
+            other => panic!("expected a request, got {:?}", other),
+        }
+    }
+
+    fn logged_out_login(captcha: bool) -> PasswordLogin {
+        let endpoints = endpoints();
+        let mut login = PasswordLogin::new(&endpoints, "2022010901001", "secret".into());
+        send(login.resume(Input::Start).unwrap());
+        let login_url = endpoints.login_url();
+        let page = send(
+            login
+                .resume(response(&login_url, StatusCode::OK, "<html></html>"))
+                .unwrap(),
+        );
+        assert_eq!(page.url, login_url);
+        assert_eq!(page.step, "fetch_login_page");
+        let step = login
+            .resume(response(&login_url, StatusCode::OK, LOGIN_PAGE))
+            .unwrap();
+        assert!(matches!(step, Step::BeginAttempt(ref account) if account == "2022010901001"));
+        let check = send(login.resume(Input::Continue).unwrap());
+        assert!(check.url.contains("checkNeedCaptcha.htl"));
+        let need = if captcha { "true" } else { "false" };
+        let step = login
+            .resume(response(
+                &check.url,
+                StatusCode::OK,
+                &format!(r#"{{"isNeed":{}}}"#, need),
+            ))
+            .unwrap();
+        if captcha {
+            assert!(matches!(step, Step::SolveCaptcha(ref account) if account == "2022010901001"));
+        } else {
+            let form = send(step);
+            assert_eq!(form.method, Method::POST);
+            assert_eq!(form.step, "submit_login_form");
+        }
+        login
+    }
+
+    #[test]
+    fn test_active_session_skips_login() {
+        let endpoints = endpoints();
+        let mut login = PasswordLogin::new(&endpoints, "2022010901001", "secret".into());
+        send(login.resume(Input::Start).unwrap());
+        let step = login
+            .resume(response(&endpoints.personal_center_url, StatusCode::OK, ""))
+            .unwrap();
+        assert!(matches!(step, Step::Done(())));
+    }
+
+    #[test]
+    fn test_login_with_captcha() {
+        let mut login = logged_out_login(true);
+        let form = send(login.resume(Input::Captcha("a1b2".to_string())).unwrap());
+        let fields = form.form.unwrap();
+        assert_eq!(fields["username"].expose(), "2022010901001");
+        assert_eq!(fields["captcha"].expose(), "a1b2");
+        assert_eq!(fields["execution"].expose(), "e1s1");
+        assert_ne!(fields["password"].expose(), "secret");
+
+        let step = login
+            .resume(response(
+                "https://idas.uestc.edu.cn/personalInfo/personCenter/index.html",
+                StatusCode::OK,
+                "",
+            ))
+            .unwrap();
+        assert!(matches!(step, Step::SaveSession));
+        assert!(matches!(
+            login.resume(Input::Continue).unwrap(),
+            Step::Done(())
+        ));
+    }
+
+    #[test]
+    fn test_unreadable_captcha_check_submits_without_captcha() {
+        let endpoints = endpoints();
+        let mut login = PasswordLogin::new(&endpoints, "2022010901001", "secret".into());
+        send(login.resume(Input::Start).unwrap());
+        let login_url = endpoints.login_url();
+        send(
+            login
+                .resume(response(&login_url, StatusCode::OK, "<html></html>"))
+                .unwrap(),
+        );
+        login
+            .resume(response(&login_url, StatusCode::OK, LOGIN_PAGE))
+            .unwrap();
+        let check = send(login.resume(Input::Continue).unwrap());
+        let form = send(
+            login
+                .resume(response(&check.url, StatusCode::NOT_FOUND, "<html>404</html>"))
+                .unwrap(),
+        );
+        assert_eq!(form.step, "submit_login_form");
+        assert!(!form.form.unwrap().contains_key("captcha"));
+    }
+
+    #[test]
+    fn test_rejected_password() {
+        let mut login = logged_out_login(false);
+        let err = login
+            .resume(response(
+                "https://idas.uestc.edu.cn/authserver/login",
+                StatusCode::OK,
+                r#"<span id="showErrorTip">您提供的用户名或者密码有误</span>"#,
+            ))
+            .unwrap_err();
+        assert!(matches!(err, UestcClientError::LoginFailed { .. }));
+    }
+
+    #[test]
+    fn test_undetermined_session_is_an_error() {
+        let mut login = PasswordLogin::new(&endpoints(), "2022010901001", "secret".into());
+        send(login.resume(Input::Start).unwrap());
+        let err = login
+            .resume(Input::Response(Err(UestcClientError::Timeout {
+                message: "connect".to_string(),
+            })))
+            .unwrap_err();
+        assert!(matches!(err, UestcClientError::Timeout { .. }));
+    }
+}
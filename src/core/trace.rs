@@ -0,0 +1,204 @@
+//! Spans for login flows and their HTTP steps.
+//!
+//! With the `tracing` feature each flow runs in an `auth` span and each
+//! request it sends in a child `http_step` span. Both set `otel.name` to the
+//! flow or step name for OpenTelemetry exporters. Without the feature a
+//! [`Span`] does nothing and costs nothing.
+
+use super::flow::HttpRequest;
+use crate::Result;
+#[cfg(feature = "tracing")]
+use crate::UestcClientError;
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    #[cfg(feature = "tracing")]
+    started: Instant,
+    #[cfg(feature = "tracing")]
+    url: Option<String>,
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+impl Span {
+    /// A span for a whole flow, e.g. `login`, on behalf of `username` if known
+    pub(crate) fn flow(name: &'static str, username: Option<&str>) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                "auth",
+                otel.name = name,
+                flow = name,
+                username,
+                outcome = tracing::field::Empty,
+                error = tracing::field::Empty,
+                elapsed_ms = tracing::field::Empty,
+            ),
+            #[cfg(feature = "tracing")]
+            started: Instant::now(),
+            #[cfg(feature = "tracing")]
+            url: None,
+        }
+    }
+
+    /// A span for sending `request`, retries included
+    pub(crate) fn step(request: &HttpRequest) -> Self {
+        Self::http(request.step, request.method.as_str(), &request.url)
+    }
+
+    /// A span for a request sent outside a flow, e.g. by logout
+    pub(crate) fn http(step: &'static str, method: &str, url: &str) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                "http_step",
+                otel.name = step,
+                step,
+                method,
+                host = host(url),
+                status = tracing::field::Empty,
+                redirect_host = tracing::field::Empty,
+                retries = tracing::field::Empty,
+                outcome = tracing::field::Empty,
+                error = tracing::field::Empty,
+                elapsed_ms = tracing::field::Empty,
+            ),
+            #[cfg(feature = "tracing")]
+            started: Instant::now(),
+            #[cfg(feature = "tracing")]
+            url: Some(url.to_string()),
+        }
+    }
+
+    /// Run `future` inside the span and record its outcome
+    #[cfg(feature = "async")]
+    pub(crate) async fn run<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        #[cfg(feature = "tracing")]
+        let result = tracing::Instrument::instrument(future, self.span.clone()).await;
+        #[cfg(not(feature = "tracing"))]
+        let result = future.await;
+        self.finish(&result);
+        result
+    }
+
+    /// Run `f` inside the span and record its outcome
+    #[cfg(feature = "blocking")]
+    pub(crate) fn run_blocking<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        #[cfg(feature = "tracing")]
+        let result = self.span.in_scope(f);
+        #[cfg(not(feature = "tracing"))]
+        let result = f();
+        self.finish(&result);
+        result
+    }
+
+    pub(crate) fn record_retries(&self, retries: usize) {
+        #[cfg(feature = "tracing")]
+        self.span.record("retries", retries);
+    }
+
+    /// Record the status of a response, and the host it was redirected to
+    /// if any
+    pub(crate) fn record_response(&self, status: reqwest::StatusCode, final_url: &url::Url) {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("status", status.as_u16());
+            if self.url.as_deref() != Some(final_url.as_str())
+                && let Some(final_host) = final_url.host_str()
+            {
+                self.span.record("redirect_host", final_host);
+            }
+        }
+    }
+
+    /// Record the outcome and the time taken since the span was created
+    fn finish<T>(&self, result: &Result<T>) {
+        #[cfg(feature = "tracing")]
+        {
+            self.span
+                .record("elapsed_ms", self.started.elapsed().as_millis() as u64);
+            self.span.record("outcome", outcome(result));
+            if let Err(e) = result {
+                self.span
+                    .record("error", super::log::redact(&e.to_string()).as_ref());
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn host(url: &str) -> Option<String> {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+}
+
+/// A short, low-cardinality name for how an operation ended
+#[cfg(feature = "tracing")]
+fn outcome<T>(result: &Result<T>) -> &'static str {
+    let Err(e) = result else {
+        return "ok";
+    };
+    match e {
+        UestcClientError::NetworkError { .. } => "network_error",
+        UestcClientError::HtmlParseError { .. } | UestcClientError::XmlParseError { .. } => {
+            "parse_error"
+        }
+        UestcClientError::CryptoError { .. } => "crypto_error",
+        UestcClientError::LoginFailed { .. } => "login_failed",
+        UestcClientError::CaptchaRequired { .. } | UestcClientError::CaptchaError { .. } => {
+            "captcha_error"
+        }
+        UestcClientError::VerificationRequired { .. } => "verification_required",
+        UestcClientError::VerificationFailed { .. } => "verification_failed",
+        UestcClientError::LogoutFailed { .. } => "logout_failed",
+        UestcClientError::CookieError { .. } => "cookie_error",
+        UestcClientError::IoError { .. } => "io_error",
+        UestcClientError::CredentialError { .. } => "credential_error",
+        UestcClientError::SessionExpired => "session_expired",
+        UestcClientError::WeChatError { .. } => "wechat_error",
+        UestcClientError::Cancelled { .. } => "cancelled",
+        UestcClientError::Timeout { .. } => "timeout",
+        UestcClientError::LoginThrottled { .. } => "throttled",
+        UestcClientError::ClientInitError { .. } => "client_error",
+        UestcClientError::InvalidArgument { .. } => "invalid_argument",
+        UestcClientError::TooManyRedirects { .. } => "too_many_redirects",
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use crate::LoginFailureReason;
+
+    #[test]
+    fn test_outcome() {
+        assert_eq!(outcome(&Ok(())), "ok");
+        assert_eq!(
+            outcome::<()>(&Err(UestcClientError::LoginFailed {
+                message: "您提供的用户名或者密码有误".to_string(),
+                username: None,
+                reason: LoginFailureReason::InvalidCredentials,
+            })),
+            "login_failed"
+        );
+        assert_eq!(
+            outcome::<()>(&Err(UestcClientError::Timeout {
+                message: "poll".to_string(),
+            })),
+            "timeout"
+        );
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_span_runs_its_work() {
+        let span = Span::step(&HttpRequest::get(
+            "https://idas.uestc.edu.cn/authserver/login",
+        ));
+        assert_eq!(span.run_blocking(|| Ok(1 + 1)).unwrap(), 2);
+    }
+}
@@ -0,0 +1,188 @@
+//! Identity of the logged-in account, from the IDAS personal center.
+
+use crate::{Result, UestcClientError};
+use serde_json::Value;
+
+/// Kind of account, as IDAS describes the identity
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccountType {
+    Undergraduate,
+    /// Master's and doctoral students
+    Graduate,
+    Staff,
+    /// Any other identity, e.g. alumni or visitors, as IDAS names it
+    Other(String),
+}
+
+impl AccountType {
+    /// Classify an identity name such as `本科生` or `教职工`
+    pub fn from_identity(identity: &str) -> Self {
+        let lower = identity.to_lowercase();
+        if identity.contains("本科") || lower.contains("undergraduate") {
+            AccountType::Undergraduate
+        } else if ["研究生", "硕士", "博士"]
+            .iter()
+            .any(|s| identity.contains(s))
+            || lower.contains("graduate")
+        {
+            AccountType::Graduate
+        } else if ["教职工", "教师", "职工", "员工"]
+            .iter()
+            .any(|s| identity.contains(s))
+            || lower.contains("staff")
+            || lower.contains("teacher")
+        {
+            AccountType::Staff
+        } else {
+            AccountType::Other(identity.to_string())
+        }
+    }
+}
+
+/// Who the current session belongs to.
+///
+/// Phone number and email are always masked, even if IDAS returns them in
+/// full, so the struct can be logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// Student or staff ID
+    pub id: String,
+    pub name: String,
+    /// `None` if IDAS does not report an identity
+    pub account_type: Option<AccountType>,
+    pub department: Option<String>,
+    /// e.g. `138****0000`
+    pub masked_phone: Option<String>,
+    /// e.g. `z***@std.uestc.edu.cn`
+    pub masked_email: Option<String>,
+}
+
+const ID_KEYS: &[&str] = &["uid", "userId", "loginName", "userAccount", "xgh"];
+const NAME_KEYS: &[&str] = &["cn", "userName", "name", "xm"];
+const IDENTITY_KEYS: &[&str] = &[
+    "identityTypeName",
+    "userTypeName",
+    "identityName",
+    "userType",
+];
+const DEPARTMENT_KEYS: &[&str] = &["deptName", "orgName", "departmentName", "dwmc"];
+const PHONE_KEYS: &[&str] = &["mobile", "phone", "telephoneNumber", "sjh"];
+const EMAIL_KEYS: &[&str] = &["email", "mail", "dzyx"];
+
+fn user_info_error(message: impl Into<String>) -> UestcClientError {
+    UestcClientError::HtmlParseError {
+        message: message.into(),
+        source: None,
+    }
+}
+
+/// First non-empty string (or number) among `keys`
+fn field(data: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter().find_map(|key| match data.get(key)? {
+        Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    })
+}
+
+/// Keep the first 3 and last 4 digits of a phone number
+pub fn mask_phone(phone: &str) -> String {
+    let chars: Vec<char> = phone.chars().collect();
+    if phone.contains('*') || chars.len() < 8 {
+        return phone.to_string();
+    }
+    let head: String = chars[..3].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}****{}", head, tail)
+}
+
+/// Keep the first character of the local part and the domain of an email
+pub fn mask_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) if !local.contains('*') => {
+            let first: String = local.chars().take(1).collect();
+            format!("{}***@{}", first, domain)
+        }
+        _ => email.to_string(),
+    }
+}
+
+/// Parse the personal center user info response, e.g.
+/// `{"code":"0","datas":{"uid":"2022...","cn":"张三",...}}`
+pub fn parse_user_info(text: &str) -> Result<SessionInfo> {
+    let json: Value =
+        serde_json::from_str(text.trim()).map_err(|e| UestcClientError::HtmlParseError {
+            message: format!("Failed to parse user info response: {}", e),
+            source: Some(Box::new(e)),
+        })?;
+
+    if let Some(code) = field(&json, &["code"])
+        && code != "0"
+    {
+        let message = field(&json, &["message", "msg"]).unwrap_or_default();
+        return Err(user_info_error(format!(
+            "User info request failed (code {}): {}",
+            code, message
+        )));
+    }
+
+    let data = ["datas", "data", "user"]
+        .iter()
+        .find_map(|key| json.get(key).filter(|v| v.is_object()))
+        .unwrap_or(&json);
+
+    let id = field(data, ID_KEYS).ok_or_else(|| user_info_error("User info has no account ID"))?;
+    let name = field(data, NAME_KEYS).unwrap_or_default();
+
+    Ok(SessionInfo {
+        id,
+        name,
+        account_type: field(data, IDENTITY_KEYS)
+            .map(|identity| AccountType::from_identity(&identity)),
+        department: field(data, DEPARTMENT_KEYS),
+        masked_phone: field(data, PHONE_KEYS).map(|phone| mask_phone(&phone)),
+        masked_email: field(data, EMAIL_KEYS).map(|email| mask_email(&email)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_user_info() {
+        let info = parse_user_info(
+            r#"{"code":"0","datas":{"uid":"2022010901001","cn":"张三","identityTypeName":"本科生",
+            "deptName":"计算机科学与工程学院","mobile":"13800001234","email":"zhangsan@std.uestc.edu.cn"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(info.id, "2022010901001");
+        assert_eq!(info.name, "张三");
+        assert_eq!(info.account_type, Some(AccountType::Undergraduate));
+        assert_eq!(info.department.as_deref(), Some("计算机科学与工程学院"));
+        assert_eq!(info.masked_phone.as_deref(), Some("138****1234"));
+        assert_eq!(info.masked_email.as_deref(), Some("z***@std.uestc.edu.cn"));
+    }
+
+    #[test]
+    fn test_parse_user_info_errors() {
+        assert!(parse_user_info(r#"{"code":"1","message":"未登录"}"#).is_err());
+        assert!(parse_user_info(r#"{"code":"0","datas":{"cn":"张三"}}"#).is_err());
+        assert!(parse_user_info("<html></html>").is_err());
+    }
+
+    #[test]
+    fn test_account_type() {
+        assert_eq!(
+            AccountType::from_identity("硕士研究生"),
+            AccountType::Graduate
+        );
+        assert_eq!(AccountType::from_identity("教职工"), AccountType::Staff);
+        assert_eq!(
+            AccountType::from_identity("校友"),
+            AccountType::Other("校友".to_string())
+        );
+        assert_eq!(mask_phone("138****1234"), "138****1234");
+    }
+}
@@ -0,0 +1,48 @@
+//! Types for logins that need a code received by SMS.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// A dynamic-code login waiting for the code sent by SMS.
+///
+/// Returned by `request_dynamic_code` and consumed by `dynamic_code_login`.
+#[derive(Debug, Clone)]
+pub struct DynamicCodeLogin {
+    pub(crate) account: String,
+    pub(crate) form_data: HashMap<String, String>,
+    pub(crate) resend_after: Option<Duration>,
+}
+
+impl DynamicCodeLogin {
+    /// The phone number or student ID the code was requested for
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    /// How long IDAS wants the caller to wait before requesting another code
+    pub fn resend_after(&self) -> Option<Duration> {
+        self.resend_after
+    }
+}
+
+/// A login paused because IDAS asks for secondary verification of this device.
+///
+/// Carried by [`UestcClientError::VerificationRequired`](crate::UestcClientError::VerificationRequired).
+/// Call `send_verification_code` to have IDAS text a code to the bound phone,
+/// then `complete_verification` with the code to resume the login.
+#[derive(Debug, Clone)]
+pub struct PendingVerification {
+    pub(crate) username: String,
+    pub(crate) reauth_url: String,
+}
+
+impl PendingVerification {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// The IDAS verification page the login was redirected to
+    pub fn reauth_url(&self) -> &str {
+        &self.reauth_url
+    }
+}
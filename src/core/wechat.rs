@@ -1,4 +1,7 @@
+use crate::core::log;
 use crate::{Result, UestcClientError};
+use qrcode::QrCode;
+use qrcode::render::{svg, unicode};
 use quick_xml::events::Event;
 use quick_xml::Reader;
 use regex::Regex;
@@ -7,7 +10,7 @@ use url::Url;
 pub const WECHAT_OPEN_URL: &str = "https://open.weixin.qq.com";
 pub const WECHAT_LP_URL: &str = "https://lp.open.weixin.qq.com";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct WechatAuthParams {
     pub appid: String,
     pub redirect_uri: String,
@@ -95,10 +98,8 @@ pub fn parse_qr_uuid_from_xml(xml_text: &str) -> Result<String> {
 
     loop {
         match reader.read_event_into(&mut buf) {
-            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) => {
-                if e.name().as_ref() == b"uuid" {
-                    in_uuid_tag = true;
-                }
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) if e.name().as_ref() == b"uuid" => {
+                in_uuid_tag = true;
             }
             Ok(Event::Text(text)) if in_uuid_tag => {
                 let text_str = std::str::from_utf8(text.as_ref()).map_err(|e| {
@@ -120,10 +121,8 @@ pub fn parse_qr_uuid_from_xml(xml_text: &str) -> Result<String> {
                 uuid = Some(text_str.trim().to_string());
                 in_uuid_tag = false;
             }
-            Ok(Event::End(ref e)) => {
-                if e.name().as_ref() == b"uuid" {
-                    in_uuid_tag = false;
-                }
+            Ok(Event::End(ref e)) if e.name().as_ref() == b"uuid" => {
+                in_uuid_tag = false;
             }
             Ok(Event::Eof) => break,
             Err(e) => {
@@ -147,9 +146,72 @@ pub fn parse_qr_uuid_from_xml(xml_text: &str) -> Result<String> {
     })
 }
 
+/// Quiet zone around the QR code, in modules
+const QR_QUIET_ZONE: usize = 4;
+
+/// Build the URL encoded in the login QR code
+pub fn build_confirm_url(uuid: &str) -> String {
+    format!("{}/connect/confirm?uuid={}", WECHAT_OPEN_URL, uuid)
+}
+
+fn encode_qr(data: &str) -> Result<QrCode> {
+    QrCode::new(data.as_bytes()).map_err(|e| UestcClientError::WeChatError {
+        message: format!("Failed to encode QR code: {}", e),
+    })
+}
+
+/// Render `data` as an SVG document
+pub fn render_qr_svg(data: &str) -> Result<String> {
+    let code = encode_qr(data)?;
+    Ok(code
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build())
+}
+
+/// Render `data` with Unicode half blocks, two modules per character row
+pub fn render_qr_unicode(data: &str) -> Result<String> {
+    let code = encode_qr(data)?;
+    Ok(code.render::<unicode::Dense1x2>().build())
+}
+
+/// Render `data` as a grayscale PNG with `module_size` pixels per module
+pub fn render_qr_png(data: &str, module_size: u32) -> Result<Vec<u8>> {
+    let code = encode_qr(data)?;
+    let module_size = module_size.max(1) as usize;
+    let width = code.width();
+    let colors = code.to_colors();
+    let size = (width + 2 * QR_QUIET_ZONE) * module_size;
+
+    let mut pixels = vec![0xFFu8; size * size];
+    for (i, color) in colors.iter().enumerate() {
+        if *color != qrcode::Color::Dark {
+            continue;
+        }
+        let x0 = (i % width + QR_QUIET_ZONE) * module_size;
+        let y0 = (i / width + QR_QUIET_ZONE) * module_size;
+        for y in y0..y0 + module_size {
+            pixels[y * size + x0..y * size + x0 + module_size].fill(0);
+        }
+    }
+
+    let mut png_bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_bytes, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(|e| UestcClientError::WeChatError {
+            message: format!("Failed to encode QR code PNG: {}", e),
+        })?;
+
+    Ok(png_bytes)
+}
+
 /// Display QR code in terminal for WeChat login
 pub fn display_qr_in_terminal(uuid: &str) -> Result<()> {
-    let qr_url = format!("https://open.weixin.qq.com/connect/confirm?uuid={}", uuid);
+    let qr_url = build_confirm_url(uuid);
 
     log::info!("请使用微信扫描二维码登录");
 
@@ -165,12 +227,51 @@ pub fn display_qr_in_terminal(uuid: &str) -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, PartialEq)]
+/// A WeChat login QR code waiting to be scanned.
+///
+/// Returned by `start_wechat_login`; render it however suits the frontend and
+/// pass it to `wait_wechat_login` to follow the scan.
+#[derive(Debug, Clone)]
+pub struct WechatQrLogin {
+    pub(crate) params: WechatAuthParams,
+    pub(crate) uuid: String,
+}
+
+impl WechatQrLogin {
+    pub fn uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// The URL encoded in the QR code
+    pub fn confirm_url(&self) -> String {
+        build_confirm_url(&self.uuid)
+    }
+
+    /// Render the QR code as a PNG image with `module_size` pixels per module
+    pub fn to_png(&self, module_size: u32) -> Result<Vec<u8>> {
+        render_qr_png(&self.confirm_url(), module_size)
+    }
+
+    /// Render the QR code as an SVG document
+    pub fn to_svg(&self) -> Result<String> {
+        render_qr_svg(&self.confirm_url())
+    }
+
+    /// Render the QR code with Unicode block characters, e.g. for chat messages
+    pub fn to_unicode(&self) -> Result<String> {
+        render_qr_unicode(&self.confirm_url())
+    }
+}
+
+/// Progress of a WeChat QR login, as reported by the long-polling endpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ScanStatus {
     Waiting,       // 408: Waiting for scan
     Scanned,       // 404: Scanned, waiting for confirmation
     Confirmed,     // 405: Login confirmed
     Expired,       // 402: QR code expired
+    Cancelled,     // 403: User cancelled on the phone
+    ServerError,   // 500: WeChat server error, polling may continue
     Unknown(i32),  // Other status codes
 }
 
@@ -227,6 +328,14 @@ pub fn parse_scan_status(text: &str) -> Result<ScanResult> {
                 log::warn!("WeChat QR code expired");
                 ScanStatus::Expired
             }
+            403 => {
+                log::info!("WeChat login cancelled on the phone");
+                ScanStatus::Cancelled
+            }
+            500 => {
+                log::warn!("WeChat server error while polling scan status");
+                ScanStatus::ServerError
+            }
             _ => {
                 log::warn!("Unknown WeChat status code: {}", code);
                 ScanStatus::Unknown(code)
@@ -261,3 +370,35 @@ pub fn parse_scan_status(text: &str) -> Result<ScanResult> {
 
     Ok(ScanResult { status, wx_code })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scan_status() {
+        let result = parse_scan_status("window.wx_errcode=408;window.wx_code='';").unwrap();
+        assert_eq!(result.status, ScanStatus::Waiting);
+        assert_eq!(result.wx_code, None);
+
+        let result = parse_scan_status("window.wx_errcode=405;window.wx_code='abc123';").unwrap();
+        assert_eq!(result.status, ScanStatus::Confirmed);
+        assert_eq!(result.wx_code.as_deref(), Some("abc123"));
+
+        let result = parse_scan_status("window.wx_errcode=403;window.wx_code='';").unwrap();
+        assert_eq!(result.status, ScanStatus::Cancelled);
+    }
+
+    #[test]
+    fn test_render_qr() {
+        let url = build_confirm_url("0a1b2c3d");
+        assert_eq!(url, "https://open.weixin.qq.com/connect/confirm?uuid=0a1b2c3d");
+
+        let png = render_qr_png(&url, 4).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        assert!(render_qr_svg(&url).unwrap().contains("<svg"));
+        let text = render_qr_unicode(&url).unwrap();
+        assert!(text.chars().any(|c| matches!(c, '█' | '▀' | '▄')));
+    }
+}
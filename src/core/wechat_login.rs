@@ -0,0 +1,572 @@
+//! WeChat QR login as I/O-free flows, with its options and events.
+
+use super::endpoints::Endpoints;
+use super::flow::{Flow, HttpRequest, Input, Step, unexpected};
+use super::wechat::{self, ScanStatus, WechatAuthParams, WechatQrLogin};
+use crate::core::log;
+use crate::{Result, UestcClientError, core};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Default timeout of a single long-poll request to WeChat
+pub(crate) const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval between long-poll requests
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A cloneable flag for cancelling a login from another task or thread.
+///
+/// The blocking client checks it between long-poll requests, so it may take
+/// up to one poll (about 30 seconds) to notice. The async client abandons
+/// the request in flight as soon as it is cancelled; dropping the login
+/// future works as well.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<TokenState>);
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    #[cfg(feature = "async")]
+    notify: tokio::sync::Notify,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        #[cfg(feature = "async")]
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolve once the token is cancelled
+    #[cfg(feature = "async")]
+    pub async fn cancelled(&self) {
+        let notified = self.0.notify.notified();
+        let mut notified = std::pin::pin!(notified);
+        // Register before checking, so a cancel in between still wakes us
+        notified.as_mut().enable();
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+}
+
+/// Controls how long and how persistently a WeChat QR login waits
+#[derive(Debug, Clone, Default)]
+pub struct WechatLoginOptions {
+    pub(crate) deadline: Option<Duration>,
+    pub(crate) max_refreshes: usize,
+    pub(crate) cancel: Option<CancellationToken>,
+    pub(crate) poll_timeout: Option<Duration>,
+}
+
+impl WechatLoginOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up with [`UestcClientError::Timeout`] after `deadline`
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Fetch a new QR code up to `max_refreshes` times when the current one expires
+    pub fn auto_refresh(mut self, max_refreshes: usize) -> Self {
+        self.max_refreshes = max_refreshes;
+        self
+    }
+
+    /// Timeout of each long-poll request, 30 seconds by default
+    pub fn poll_timeout(mut self, timeout: Duration) -> Self {
+        self.poll_timeout = Some(timeout);
+        self
+    }
+
+    /// Stop with [`UestcClientError::Cancelled`] once `token` is cancelled
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Check for cancellation and the deadline before the next poll, returning
+    /// the timeout to use for it
+    pub(crate) fn next_poll_timeout(&self, started: Instant) -> Result<Duration> {
+        if self.is_cancelled() {
+            log::info!("微信登录已取消");
+            return Err(UestcClientError::Cancelled {
+                message: "WeChat login cancelled".to_string(),
+            });
+        }
+
+        let poll_timeout = self.poll_timeout.unwrap_or(POLL_TIMEOUT);
+        match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_sub(started.elapsed());
+                if remaining.is_zero() {
+                    return Err(self.timeout_error());
+                }
+                Ok(remaining.min(poll_timeout))
+            }
+            None => Ok(poll_timeout),
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancellationToken::is_cancelled)
+    }
+
+    pub(crate) fn deadline_passed(&self, started: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| started.elapsed() >= deadline)
+    }
+
+    pub(crate) fn timeout_error(&self) -> UestcClientError {
+        UestcClientError::Timeout {
+            message: format!(
+                "WeChat login not confirmed within {:?}",
+                self.deadline.unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Progress reported by `wait_wechat_login_with`
+#[derive(Debug, Clone)]
+pub enum WechatLoginEvent {
+    /// The scan status changed
+    Status(ScanStatus),
+    /// The previous QR code expired and was replaced; display this one instead
+    QrRefreshed(WechatQrLogin),
+}
+
+/// Fetch a QR code: follow CAS to the WeChat OAuth page, then ask WeChat for
+/// the code's UUID
+#[derive(Debug)]
+pub struct WechatStart {
+    endpoints: Endpoints,
+    params: Option<WechatAuthParams>,
+}
+
+impl WechatStart {
+    pub fn new(endpoints: &Endpoints) -> Self {
+        Self {
+            endpoints: endpoints.clone(),
+            params: None,
+        }
+    }
+}
+
+impl Flow for WechatStart {
+    type Output = WechatQrLogin;
+
+    fn resume(&mut self, input: Input) -> Result<Step<WechatQrLogin>> {
+        let resp = match input {
+            Input::Start => {
+                log::debug!("正在连接 CAS 初始化参数");
+                let url = format!(
+                    "{}/combinedLogin.do?type=weixin",
+                    self.endpoints.auth_server_url
+                );
+                return Ok(Step::Send(
+                    HttpRequest::get(url)
+                        .step("open_wechat_oauth")
+                        .error_for_status(),
+                ));
+            }
+            Input::Response(resp) => resp?,
+            other => unexpected(other),
+        };
+
+        match self.params.take() {
+            None => {
+                // Extract WeChat OAuth parameters from the final URL
+                let wechat_auth_url = resp.url.to_string();
+                if !wechat_auth_url.contains("open.weixin.qq.com") {
+                    return Err(UestcClientError::WeChatError {
+                        message: format!(
+                            "Failed to redirect to WeChat login page, current URL: {}",
+                            wechat_auth_url
+                        ),
+                    });
+                }
+
+                let params = WechatAuthParams::from_url(&wechat_auth_url)?;
+                log::debug!("Target AppID: {}", params.appid);
+                log::debug!("正在获取二维码 UUID");
+                let request = HttpRequest::get(params.build_qr_xml_url())
+                    .step("fetch_qr_code")
+                    .error_for_status();
+                self.params = Some(params);
+                Ok(Step::Send(request))
+            }
+            Some(params) => {
+                let uuid = wechat::parse_qr_uuid_from_xml(&resp.body)?;
+                Ok(Step::Done(WechatQrLogin { params, uuid }))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Polling,
+    Sleeping,
+    Refreshing,
+    Confirming,
+    Saved,
+}
+
+/// Poll the scan status of a QR code until it is confirmed, refreshing it on
+/// expiry as `options` allow, then complete the login through CAS
+#[derive(Debug)]
+pub struct WechatWait {
+    endpoints: Endpoints,
+    qr: WechatQrLogin,
+    options: WechatLoginOptions,
+    started: Instant,
+    refreshes: usize,
+    last_code: Option<String>,
+    last_status: Option<ScanStatus>,
+    state: State,
+    /// Steps decided but not yet handed out
+    queued: VecDeque<Step<()>>,
+}
+
+impl WechatWait {
+    pub fn new(endpoints: &Endpoints, qr: &WechatQrLogin, options: &WechatLoginOptions) -> Self {
+        Self {
+            endpoints: endpoints.clone(),
+            qr: qr.clone(),
+            options: options.clone(),
+            started: Instant::now(),
+            refreshes: 0,
+            last_code: None,
+            last_status: None,
+            state: State::Polling,
+            queued: VecDeque::new(),
+        }
+    }
+
+    fn poll(&mut self) -> Result<Step<()>> {
+        let timeout = self.options.next_poll_timeout(self.started)?;
+        let url = wechat::build_poll_url(&self.qr.uuid, self.last_code.as_deref());
+        self.state = State::Polling;
+        Ok(Step::Send(
+            HttpRequest::get(url)
+                .step("poll_scan_status")
+                .timeout(timeout)
+                .error_for_status(),
+        ))
+    }
+
+    fn sleep_then_poll(&mut self) {
+        self.state = State::Sleeping;
+        self.queued.push_back(Step::Sleep(POLL_INTERVAL));
+    }
+
+    fn on_scan_status(&mut self, text: &str) -> Result<()> {
+        let result = wechat::parse_scan_status(text)?;
+
+        if self.last_status.as_ref() != Some(&result.status) {
+            self.queued
+                .push_back(Step::Event(WechatLoginEvent::Status(result.status.clone())));
+            self.last_status = Some(result.status.clone());
+        }
+
+        match result.status {
+            ScanStatus::Confirmed => {
+                log::debug!("登录成功 (405)");
+                let Some(wx_code) = result.wx_code else {
+                    return Err(UestcClientError::WeChatError {
+                        message: "Received 405 status but wx_code not found".to_string(),
+                    });
+                };
+                log::debug!("获取到 wx_code");
+                log::debug!("正在验证登录");
+                self.state = State::Confirming;
+                // The code is redeemed by the first attempt, never retry it
+                let callback_url = self.qr.params.build_callback_url(&wx_code);
+                self.queued.push_back(Step::Send(
+                    HttpRequest::get(callback_url)
+                        .step("wechat_callback")
+                        .not_idempotent(),
+                ));
+            }
+            ScanStatus::Scanned => {
+                self.last_code = Some("404".to_string());
+                self.sleep_then_poll();
+            }
+            ScanStatus::Expired if self.refreshes < self.options.max_refreshes => {
+                self.refreshes += 1;
+                log::info!(
+                    "二维码已过期，正在刷新 ({}/{})",
+                    self.refreshes,
+                    self.options.max_refreshes
+                );
+                self.state = State::Refreshing;
+                self.queued.push_back(Step::Send(
+                    HttpRequest::get(self.qr.params.build_qr_xml_url())
+                        .step("refresh_qr_code")
+                        .error_for_status(),
+                ));
+            }
+            ScanStatus::Expired => {
+                return Err(UestcClientError::WeChatError {
+                    message: "QR code expired, please run again".to_string(),
+                });
+            }
+            ScanStatus::Cancelled => {
+                return Err(UestcClientError::Cancelled {
+                    message: "WeChat login cancelled on the phone".to_string(),
+                });
+            }
+            ScanStatus::ServerError => {
+                log::warn!("微信服务器错误，继续等待");
+                self.sleep_then_poll();
+            }
+            ScanStatus::Waiting => self.sleep_then_poll(),
+            ScanStatus::Unknown(code) => {
+                log::warn!("未知状态码: {}", code);
+                self.sleep_then_poll();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Flow for WechatWait {
+    type Output = ();
+
+    fn resume(&mut self, input: Input) -> Result<Step<()>> {
+        if let Some(step) = self.queued.pop_front() {
+            return Ok(step);
+        }
+
+        match (&self.state, input) {
+            (State::Polling, Input::Start) | (State::Sleeping, Input::Continue) => self.poll(),
+            (State::Polling, Input::Response(Err(UestcClientError::NetworkError { .. })))
+                if self.options.deadline_passed(self.started) =>
+            {
+                Err(self.options.timeout_error())
+            }
+            (_, Input::Response(Err(e))) => Err(e),
+            (State::Polling, Input::Response(Ok(resp))) => {
+                self.on_scan_status(&resp.body)?;
+                self.resume(Input::Continue)
+            }
+            (State::Refreshing, Input::Response(Ok(resp))) => {
+                self.qr.uuid = wechat::parse_qr_uuid_from_xml(&resp.body)?;
+                self.queued
+                    .push_back(Step::Event(WechatLoginEvent::QrRefreshed(self.qr.clone())));
+                self.last_code = None;
+                self.last_status = None;
+                self.sleep_then_poll();
+                self.resume(Input::Continue)
+            }
+            (State::Confirming, Input::Response(Ok(resp))) => {
+                // Check if login succeeded by examining the final URL
+                if core::cas::is_login_page(&resp.url, &self.endpoints.auth_server_url) {
+                    return Err(UestcClientError::WeChatError {
+                        message: "WeChat login failed, still on login page".to_string(),
+                    });
+                }
+                log::info!("微信登录成功");
+                self.state = State::Saved;
+                Ok(Step::SaveSession)
+            }
+            (State::Saved, Input::Continue) => Ok(Step::Done(())),
+            (_, input) => unexpected(input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::flow::tests::{endpoints, response};
+    use reqwest::StatusCode;
+
+    const OAUTH_URL: &str = "https://open.weixin.qq.com/connect/qrconnect?appid=wx123&redirect_uri=https%3A%2F%2Fidas.uestc.edu.cn%2Fauthserver%2FcombinedLogin.do%3Ftype%3Dweixin&response_type=code&scope=snsapi_login&state=abc";
+
+    fn qr() -> WechatQrLogin {
+        WechatQrLogin {
+            params: WechatAuthParams::from_url(OAUTH_URL).unwrap(),
+            uuid: "uuid-1".to_string(),
+        }
+    }
+
+    fn send(step: Step<()>) -> HttpRequest {
+        match step {
+            Step::Send(request) => request,
+            other => panic!("expected a request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_start() {
+        let mut start = WechatStart::new(&endpoints());
+        let Step::Send(request) = start.resume(Input::Start).unwrap() else {
+            panic!("expected a request");
+        };
+        assert!(request.url.ends_with("/combinedLogin.do?type=weixin"));
+
+        let Step::Send(request) = start
+            .resume(response(OAUTH_URL, StatusCode::OK, ""))
+            .unwrap()
+        else {
+            panic!("expected a request");
+        };
+        assert!(request.url.contains("appid=wx123"));
+
+        assert!(
+            WechatStart::new(&endpoints())
+                .resume(response(
+                    "https://idas.uestc.edu.cn/authserver/login",
+                    StatusCode::OK,
+                    ""
+                ))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_scan_and_confirm() {
+        let mut wait = WechatWait::new(&endpoints(), &qr(), &WechatLoginOptions::new());
+        let poll = send(wait.resume(Input::Start).unwrap());
+        assert!(poll.url.contains("uuid=uuid-1"));
+        assert!(poll.timeout.is_some());
+
+        let step = wait
+            .resume(response(
+                &poll.url,
+                StatusCode::OK,
+                "window.wx_errcode=404;window.wx_code='';",
+            ))
+            .unwrap();
+        assert!(matches!(
+            step,
+            Step::Event(WechatLoginEvent::Status(ScanStatus::Scanned))
+        ));
+        assert!(matches!(
+            wait.resume(Input::Continue).unwrap(),
+            Step::Sleep(_)
+        ));
+        let poll = send(wait.resume(Input::Continue).unwrap());
+        assert!(poll.url.contains("last=404"));
+
+        let step = wait
+            .resume(response(
+                &poll.url,
+                StatusCode::OK,
+                "window.wx_errcode=405;window.wx_code='CODE';",
+            ))
+            .unwrap();
+        assert!(matches!(
+            step,
+            Step::Event(WechatLoginEvent::Status(ScanStatus::Confirmed))
+        ));
+        let callback = send(wait.resume(Input::Continue).unwrap());
+        assert!(callback.url.contains("code=CODE"));
+        assert!(!callback.idempotent);
+
+        let step = wait
+            .resume(response(
+                "https://idas.uestc.edu.cn/personalInfo/personCenter/index.html",
+                StatusCode::OK,
+                "",
+            ))
+            .unwrap();
+        assert!(matches!(step, Step::SaveSession));
+        assert!(matches!(
+            wait.resume(Input::Continue).unwrap(),
+            Step::Done(())
+        ));
+    }
+
+    #[test]
+    fn test_expired_qr_is_refreshed() {
+        let options = WechatLoginOptions::new().auto_refresh(1);
+        let mut wait = WechatWait::new(&endpoints(), &qr(), &options);
+        let poll = send(wait.resume(Input::Start).unwrap());
+
+        let expired = "window.wx_errcode=402;window.wx_code='';";
+        wait.resume(response(&poll.url, StatusCode::OK, expired))
+            .unwrap();
+        let refresh = send(wait.resume(Input::Continue).unwrap());
+        let step = wait
+            .resume(response(
+                &refresh.url,
+                StatusCode::OK,
+                r#"<xml><uuid>uuid-2</uuid></xml>"#,
+            ))
+            .unwrap();
+        assert!(
+            matches!(step, Step::Event(WechatLoginEvent::QrRefreshed(ref qr)) if qr.uuid() == "uuid-2")
+        );
+        assert!(matches!(
+            wait.resume(Input::Continue).unwrap(),
+            Step::Sleep(_)
+        ));
+        let poll = send(wait.resume(Input::Continue).unwrap());
+        assert!(poll.url.contains("uuid=uuid-2"));
+
+        // Out of refreshes
+        wait.resume(response(&poll.url, StatusCode::OK, expired))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_next_poll_timeout() {
+        let started = Instant::now();
+        let options = WechatLoginOptions::new();
+        assert_eq!(options.next_poll_timeout(started).unwrap(), POLL_TIMEOUT);
+
+        let options = WechatLoginOptions::new().deadline(Duration::from_secs(5));
+        assert!(options.next_poll_timeout(started).unwrap() <= Duration::from_secs(5));
+
+        let options = WechatLoginOptions::new().poll_timeout(Duration::from_secs(10));
+        assert_eq!(options.next_poll_timeout(started).unwrap(), Duration::from_secs(10));
+
+        let options = WechatLoginOptions::new().deadline(Duration::ZERO);
+        assert!(matches!(
+            options.next_poll_timeout(started),
+            Err(UestcClientError::Timeout { .. })
+        ));
+
+        let token = CancellationToken::new();
+        let options = WechatLoginOptions::new().cancellation_token(token.clone());
+        token.cancel();
+        assert!(matches!(
+            options.next_poll_timeout(started),
+            Err(UestcClientError::Cancelled { .. })
+        ));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_cancel_wakes_waiter() {
+        let token = CancellationToken::new();
+        let waiter = tokio::spawn({
+            let token = token.clone();
+            async move { token.cancelled().await }
+        });
+        tokio::task::yield_now().await;
+        token.cancel();
+        tokio::time::timeout(Duration::from_millis(50), waiter)
+            .await
+            .expect("waiter not woken")
+            .unwrap();
+
+        // Already cancelled tokens resolve at once
+        token.cancelled().await;
+    }
+}
@@ -7,6 +7,38 @@ pub use client::UestcClient;
 #[cfg(feature = "blocking")]
 pub use client::UestcBlockingClient;
 
+pub use client::captcha::{CaptchaSolveError, CaptchaSolver};
+pub use client::builder::{CookieKey, CookiePersistence, DEFAULT_COOKIE_FILE, UestcClientBuilder};
+pub use client::cookies::FileSessionStore;
+pub use client::credentials::{
+    CredentialProvider, Credentials, EnvCredentials, FileCredentials, PromptCredentials,
+};
+pub use client::har::HarRecorder;
+pub use client::keep_alive::{KeepAliveConfig, KeepAliveEvent};
+pub use client::lockout::LoginGuard;
+pub use client::pool::{AccountPool, AccountStatus};
+pub use client::retry::RetryPolicy;
+pub use client::session::{MemorySessionStore, SessionSnapshot, SessionStore};
+pub use client::{ServiceLogin, TicketValidation, default_headers};
+pub use core::flow::SessionStatus;
+pub use core::logout::{LogoutOptions, LogoutReport, ServiceLogout};
+pub use core::parser::LoginFailureReason;
+pub use core::secret::Secret;
+pub use core::user_info::{AccountType, SessionInfo};
+pub use core::verification::{DynamicCodeLogin, PendingVerification};
+pub use core::wechat::{ScanStatus, WechatQrLogin};
+pub use core::wechat_login::{CancellationToken, WechatLoginEvent, WechatLoginOptions};
+pub use reqwest_cookie_store::CookieStoreMutex;
+
+#[cfg(feature = "async")]
+pub use client::captcha::{AsyncCaptchaSolver, CaptchaFuture};
+
+#[cfg(feature = "async")]
+pub use client::keep_alive::KeepAliveHandle;
+
+#[cfg(feature = "blocking")]
+pub use client::keep_alive::BlockingKeepAliveHandle;
+
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -44,8 +76,25 @@ pub enum UestcClientError {
     LoginFailed {
         message: String,
         username: Option<String>,
+        reason: LoginFailureReason,
     },
 
+    #[error("Captcha required but no captcha solver is configured")]
+    CaptchaRequired { username: Option<String> },
+
+    #[error("Captcha handling failed: {message}")]
+    CaptchaError {
+        message: String,
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+
+    #[error("Secondary verification required for user {}", pending.username())]
+    VerificationRequired { pending: PendingVerification },
+
+    #[error("Verification failed: {message}")]
+    VerificationFailed { message: String },
+
     #[error("Logout failed: {message}")]
     LogoutFailed { message: String },
 
@@ -58,14 +107,67 @@ pub enum UestcClientError {
         source: Option<Box<dyn std::error::Error + Send + Sync>>,
     },
 
+    #[error("File operation failed: {operation} - {message}")]
+    IoError {
+        operation: String,
+        file_path: Option<String>,
+        message: String,
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+
+    #[error("Failed to obtain credentials: {message}")]
+    CredentialError {
+        message: String,
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+
     #[error("Session expired or invalid")]
     SessionExpired,
 
     #[error("WeChat QR code operation failed: {message}")]
     WeChatError { message: String },
 
+    #[error("Operation cancelled: {message}")]
+    Cancelled { message: String },
+
+    #[error("Operation timed out: {message}")]
+    Timeout { message: String },
+
+    #[error("Login attempt refused: {message}")]
+    LoginThrottled {
+        message: String,
+        username: String,
+        retry_after: std::time::Duration,
+    },
+
     #[error("Client initialization failed: {message}")]
     ClientInitError { message: String },
+
+    #[error("Invalid argument: {message}")]
+    InvalidArgument { message: String },
+
+    #[error("Too many redirects (more than {limit}) starting from {url}")]
+    TooManyRedirects { url: String, limit: usize },
+}
+
+impl UestcClientError {
+    /// Whether the failed operation may succeed if tried again unchanged:
+    /// connection failures, timed out requests, 5xx responses and 429.
+    ///
+    /// Rejected credentials, parse errors, cancellation and deadlines are not.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            UestcClientError::NetworkError { source, .. } => match source.status() {
+                Some(status) => {
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                }
+                None => source.is_connect() || source.is_timeout() || source.is_request(),
+            },
+            _ => false,
+        }
+    }
 }
 
 // Helper implementations for backward compatibility
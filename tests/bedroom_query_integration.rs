@@ -1,11 +1,11 @@
-use uestc_client::UestcClient;
+use uestc_client::{TicketValidation, UestcClient};
 use serde_json::Value;
 
 /// Integration test for querying dormitory electricity fees
 ///
 /// This test simulates the complete business flow:
 /// 1. Login with automatic cookie management
-/// 2. Initialize session with online service hall (forced CAS authentication)
+/// 2. Log in to the online service hall through the CAS service flow
 /// 3. Query dormitory electricity information
 ///
 /// To run this test:
@@ -31,20 +31,20 @@ async fn test_bedroom_electricity_query() {
         .expect("Login failed");
     println!("[✓] Login successful");
 
-    // Step 2: Initialize session with forced CAS authentication
-    // This URL forces the system to go through IDAS authentication and set p_auth_token
-    println!("[*] Initializing online service hall session (forced CAS authentication)...");
-    let init_url = "https://online.uestc.edu.cn/common/actionCasLogin?redirect_url=https://online.uestc.edu.cn/page/";
-    let init_resp = client
-        .get(init_url)
-        .send()
+    // Step 2: Enter the online service hall through the CAS service flow
+    // The service ticket is followed through so that the hall sets p_auth_token
+    println!("[*] Logging in to online service hall...");
+    let service_url = "https://online.uestc.edu.cn/common/actionCasLogin?redirect_url=https://online.uestc.edu.cn/page/";
+    let service_login = client
+        .login_to_service(service_url)
         .await
-        .expect("Failed to initialize session");
+        .expect("Failed to log in to online service hall");
 
-    assert!(
-        init_resp.status().is_success() || init_resp.status().is_redirection(),
-        "Session initialization failed with status: {}",
-        init_resp.status()
+    assert_eq!(
+        service_login.validation,
+        TicketValidation::Accepted,
+        "Service ticket was not accepted (landing status: {})",
+        service_login.response.status()
     );
     println!("[✓] Session initialized");
 
@@ -88,10 +88,10 @@ async fn test_bedroom_electricity_query() {
     println!("{}", "-".repeat(30));
 
     // Warning for low electricity
-    if let Ok(elec_value) = electricity.parse::<f64>() {
-        if elec_value < 10.0 {
-            println!("⚠️  Warning: Low electricity ({} kWh), please recharge soon!", elec_value);
-        }
+    if let Ok(elec_value) = electricity.parse::<f64>()
+        && elec_value < 10.0
+    {
+        println!("⚠️  Warning: Low electricity ({} kWh), please recharge soon!", elec_value);
     }
 
     println!("\n[✓] Integration test completed successfully");